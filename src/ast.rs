@@ -1,58 +1,186 @@
-use crate::token::{Token, TokenKind};
+use crate::token::{Span, Token, TokenKind};
 use std::error::Error;
 use std::fmt;
+use std::rc::Rc;
 
 pub use crate::token::InputPosition;
 
-#[derive(Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AST(pub Vec<ASTNode>);
 
 impl AST {
-    pub fn from_tokens(tokens: &Vec<Token>) -> Result<AST, SyntaxError> {
+    pub fn from_tokens(tokens: &[Token]) -> Result<AST, SyntaxError> {
+        AST::parse(tokens, false)
+    }
+
+    /// Parse `tokens`, keeping each run of comment characters as a `Comment`
+    /// node, for tools which rewrite the source.
+    pub fn from_tokens_with_comments(tokens: &[Token]) -> Result<AST, SyntaxError> {
+        AST::parse(tokens, true)
+    }
+
+    fn parse(tokens: &[Token], comments: bool) -> Result<AST, SyntaxError> {
         let mut ops = vec![];
-        let mut ts = tokens.clone();
+        let mut ts = tokens.to_vec();
         ts.reverse();
 
         while let Some(t) = ts.pop() {
             if let Some(op) = try_parse_scalar(&t) {
                 ops.push(op);
+            } else if t.kind == TokenKind::Comment {
+                if comments {
+                    push_comment(&mut ops, &t);
+                }
             } else if t.kind == TokenKind::StartLoop {
-                ops.push(parse_loop(&mut ts, &t.pos)?);
+                ops.push(parse_loop(&mut ts, &t, comments)?);
+            } else if t.kind == TokenKind::StartProc {
+                ops.push(parse_procedure(&mut ts, &t, comments)?);
             } else if t.kind == TokenKind::EndLoop {
                 return Err(SyntaxError {
                     pos: t.pos,
                     kind: ErrorKind::UnopenedLoop,
                 });
+            } else if t.kind == TokenKind::EndProc {
+                return Err(SyntaxError {
+                    pos: t.pos,
+                    kind: ErrorKind::UnopenedProcedure,
+                });
             }
         }
 
         Ok(AST(ops))
     }
+
+    /// Whether the program defines or calls any procedures.
+    pub fn uses_procedures(&self) -> bool {
+        fn any(ops: &[ASTNode]) -> bool {
+            ops.iter().any(|op| match op.kind {
+                ASTNodeKind::Procedure | ASTNodeKind::Call => true,
+                ASTNodeKind::Loop => any(op.ops.as_ref().unwrap()),
+                _ => false,
+            })
+        }
+        any(&self.0)
+    }
 }
 
-fn parse_loop(ts: &mut Vec<Token>, start_pos: &InputPosition) -> Result<ASTNode, SyntaxError> {
+fn parse_loop(ts: &mut Vec<Token>, start: &Token, comments: bool) -> Result<ASTNode, SyntaxError> {
     let mut ops = vec![];
 
     while let Some(t) = ts.pop() {
         if let Some(op) = try_parse_scalar(&t) {
             ops.push(op);
+        } else if t.kind == TokenKind::Comment {
+            if comments {
+                push_comment(&mut ops, &t);
+            }
         } else if t.kind == TokenKind::StartLoop {
-            ops.push(parse_loop(ts, &t.pos)?);
+            ops.push(parse_loop(ts, &t, comments)?);
         } else if t.kind == TokenKind::EndLoop {
+            check_same_source(start, &t)?;
             return Ok(ASTNode {
                 kind: ASTNodeKind::Loop,
-                pos: start_pos.clone(),
+                pos: start.pos.clone(),
+                span: Span {
+                    start: start.span.start,
+                    end: t.span.end,
+                },
                 ops: Some(ops),
             });
+        } else if t.kind == TokenKind::StartProc {
+            return Err(SyntaxError {
+                pos: t.pos,
+                kind: ErrorKind::NestedProcedure,
+            });
+        } else if t.kind == TokenKind::EndProc {
+            break;
         }
     }
 
     Err(SyntaxError {
-        pos: start_pos.clone(),
+        pos: start.pos.clone(),
         kind: ErrorKind::UnclosedLoop,
     })
 }
 
+/// Procedures may contain loops but are only defined at the top level.
+fn parse_procedure(ts: &mut Vec<Token>, start: &Token, comments: bool) -> Result<ASTNode, SyntaxError> {
+    let mut ops = vec![];
+
+    while let Some(t) = ts.pop() {
+        if let Some(op) = try_parse_scalar(&t) {
+            ops.push(op);
+        } else if t.kind == TokenKind::Comment {
+            if comments {
+                push_comment(&mut ops, &t);
+            }
+        } else if t.kind == TokenKind::StartLoop {
+            ops.push(parse_loop(ts, &t, comments)?);
+        } else if t.kind == TokenKind::EndProc {
+            check_same_source(start, &t)?;
+            return Ok(ASTNode {
+                kind: ASTNodeKind::Procedure,
+                pos: start.pos.clone(),
+                span: Span {
+                    start: start.span.start,
+                    end: t.span.end,
+                },
+                ops: Some(ops),
+            });
+        } else if t.kind == TokenKind::StartProc {
+            return Err(SyntaxError {
+                pos: t.pos,
+                kind: ErrorKind::NestedProcedure,
+            });
+        } else if t.kind == TokenKind::EndLoop {
+            return Err(SyntaxError {
+                pos: t.pos,
+                kind: ErrorKind::UnopenedLoop,
+            });
+        }
+    }
+
+    Err(SyntaxError {
+        pos: start.pos.clone(),
+        kind: ErrorKind::UnclosedProcedure,
+    })
+}
+
+/// Add the comment character `t` to the comment just before it, if any.
+fn push_comment(ops: &mut Vec<ASTNode>, t: &Token) {
+    if let Some(last) = ops.last_mut() {
+        if last.kind == ASTNodeKind::Comment && last.pos.file == t.pos.file && last.pos.expansion.is_none() {
+            last.span.end = t.span.end;
+            return;
+        }
+    }
+    ops.push(ASTNode {
+        kind: ASTNodeKind::Comment,
+        pos: t.pos.clone(),
+        span: t.span,
+        ops: None,
+    });
+}
+
+/// Spans are offsets into a single file, so loops and procedures must end in
+/// the file and macro expansion they start in.
+fn check_same_source(start: &Token, end: &Token) -> Result<(), SyntaxError> {
+    let same_expansion = match (&start.pos.expansion, &end.pos.expansion) {
+        (None, None) => true,
+        (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+        _ => false,
+    };
+    if start.pos.file == end.pos.file && same_expansion {
+        Ok(())
+    } else {
+        Err(SyntaxError {
+            pos: end.pos.clone(),
+            kind: ErrorKind::ClosedElsewhere,
+        })
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct SyntaxError {
     pub pos: InputPosition,
@@ -63,64 +191,102 @@ pub struct SyntaxError {
 pub enum ErrorKind {
     UnclosedLoop,
     UnopenedLoop,
+    UnclosedProcedure,
+    UnopenedProcedure,
+    NestedProcedure,
+    ClosedElsewhere,
+}
+
+impl ErrorKind {
+    fn message(&self) -> &str {
+        match self {
+            ErrorKind::UnopenedLoop => "Unopened loop",
+            ErrorKind::UnclosedLoop => "Unclosed loop",
+            ErrorKind::UnopenedProcedure => "Unopened procedure",
+            ErrorKind::UnclosedProcedure => "Unclosed procedure",
+            ErrorKind::NestedProcedure => "Procedure defined inside a loop or procedure",
+            ErrorKind::ClosedElsewhere => "Loop or procedure closed in a different file or macro",
+        }
+    }
 }
 
 impl fmt::Display for SyntaxError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{} ({}:{})", self, self.pos.line, self.pos.pos)
+        write!(f, "{} ({})", self.kind.message(), self.pos)
     }
 }
 
 impl Error for SyntaxError {
     fn description(&self) -> &str {
-        match self.kind {
-            ErrorKind::UnopenedLoop => "Unopened loop",
-            ErrorKind::UnclosedLoop => "Unclosed loop",
-        }
+        self.kind.message()
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ASTNode {
     pub kind: ASTNodeKind,
     pub pos: InputPosition,
+    /// Source bytes of the command; for loops, from `[` through `]`, and for
+    /// procedures, from `(` through `)`.
+    pub span: Span,
     pub ops: Option<Vec<ASTNode>>,
 }
 
+// Test sources are single lines of ASCII, so columns map directly to bytes.
 #[cfg(test)]
 impl ASTNode {
     fn new_scalar(kind: ASTNodeKind, line: usize, pos: usize) -> ASTNode {
         ASTNode {
-            kind: kind,
+            kind,
             pos: InputPosition {
-                line: line,
-                pos: pos,
+                file: None,
+                line,
+                pos,
+                expansion: None,
+            },
+            span: Span {
+                start: pos - 1,
+                end: pos,
             },
             ops: None,
         }
     }
 
-    fn new_loop(line: usize, pos: usize, ops: Vec<ASTNode>) -> ASTNode {
+    fn new_loop(line: usize, pos: usize, end: usize, ops: Vec<ASTNode>) -> ASTNode {
         ASTNode {
             kind: ASTNodeKind::Loop,
             pos: InputPosition {
-                line: line,
-                pos: pos,
+                file: None,
+                line,
+                pos,
+                expansion: None,
+            },
+            span: Span {
+                start: pos - 1,
+                end,
             },
             ops: Some(ops),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ASTNodeKind {
     Loop,
+    /// Define the procedure numbered by the active cell as the body in `ops`.
+    Procedure,
+    /// Call the procedure numbered by the active cell.
+    Call,
     IncTape,
     DecTape,
     IncVal,
     DecVal,
     Read,
     Write,
+    Debug,
+    /// Text which is not a command, spanning from its first character through
+    /// its last. Only parsed by `AST::from_tokens_with_comments`.
+    Comment,
 }
 
 fn try_parse_scalar(t: &Token) -> Option<ASTNode> {
@@ -132,17 +298,20 @@ fn try_parse_scalar(t: &Token) -> Option<ASTNode> {
             TokenKind::DecVal => ASTNodeKind::DecVal,
             TokenKind::Read => ASTNodeKind::Read,
             TokenKind::Write => ASTNodeKind::Write,
+            TokenKind::Debug => ASTNodeKind::Debug,
+            TokenKind::Call => ASTNodeKind::Call,
             _ => return None,
         },
         pos: t.pos.clone(),
+        span: t.span,
         ops: None,
     })
 }
 
 #[cfg(test)]
 mod test {
-    use super::{ASTNode, ASTNodeKind, ErrorKind, InputPosition, SyntaxError, AST};
-    use crate::token::tokenize;
+    use super::{ASTNode, ASTNodeKind, ErrorKind, InputPosition, Span, SyntaxError, AST};
+    use crate::token::{tokenize, tokenize_file, tokenize_with, Extensions};
 
     #[test]
     fn empty() {
@@ -169,11 +338,27 @@ mod test {
         assert_eq!(val, expect);
     }
 
+    #[test]
+    fn debug() {
+        let raw = "+#";
+        let ext = Extensions {
+            debug_hash: true,
+            ..Extensions::default()
+        };
+        let val = AST::from_tokens(&tokenize_with(raw, &ext));
+        let expect = Ok(AST(vec![
+            ASTNode::new_scalar(ASTNodeKind::IncVal, 1, 1),
+            ASTNode::new_scalar(ASTNodeKind::Debug, 1, 2),
+        ]));
+
+        assert_eq!(val, expect);
+    }
+
     #[test]
     fn empty_loop() {
         let raw = "[]";
         let val = AST::from_tokens(&tokenize(raw));
-        let expect = Ok(AST(vec![ASTNode::new_loop(1, 1, vec![])]));
+        let expect = Ok(AST(vec![ASTNode::new_loop(1, 1, 2, vec![])]));
 
         assert_eq!(val, expect);
     }
@@ -184,7 +369,7 @@ mod test {
         let val = AST::from_tokens(&tokenize(raw));
         let expect = Ok(AST(vec![
             ASTNode::new_scalar(ASTNodeKind::IncVal, 1, 1),
-            ASTNode::new_loop(1, 2, vec![ASTNode::new_scalar(ASTNodeKind::DecVal, 1, 3)]),
+            ASTNode::new_loop(1, 2, 4, vec![ASTNode::new_scalar(ASTNodeKind::DecVal, 1, 3)]),
         ]));
 
         assert_eq!(val, expect);
@@ -199,9 +384,10 @@ mod test {
             ASTNode::new_loop(
                 1,
                 2,
+                8,
                 vec![
                     ASTNode::new_scalar(ASTNodeKind::IncVal, 1, 3),
-                    ASTNode::new_loop(1, 4, vec![ASTNode::new_scalar(ASTNodeKind::DecVal, 1, 5)]),
+                    ASTNode::new_loop(1, 4, 6, vec![ASTNode::new_scalar(ASTNodeKind::DecVal, 1, 5)]),
                     ASTNode::new_scalar(ASTNodeKind::DecVal, 1, 7),
                 ],
             ),
@@ -210,12 +396,31 @@ mod test {
         assert_eq!(val, expect);
     }
 
+    #[test]
+    fn multi_line_span() {
+        let raw = "[\r\n+]";
+        let val = AST::from_tokens(&tokenize(raw));
+        let expect = Ok(AST(vec![ASTNode {
+            kind: ASTNodeKind::Loop,
+            pos: InputPosition { file: None, line: 1, pos: 1, expansion: None },
+            span: Span { start: 0, end: 5 },
+            ops: Some(vec![ASTNode {
+                kind: ASTNodeKind::IncVal,
+                pos: InputPosition { file: None, line: 2, pos: 1, expansion: None },
+                span: Span { start: 3, end: 4 },
+                ops: None,
+            }]),
+        }]));
+
+        assert_eq!(val, expect);
+    }
+
     #[test]
     fn unopened_loop() {
         let raw = "]";
         let val = AST::from_tokens(&tokenize(raw));
         let expect = Err(SyntaxError {
-            pos: InputPosition { line: 1, pos: 1 },
+            pos: InputPosition { file: None, line: 1, pos: 1, expansion: None },
             kind: ErrorKind::UnopenedLoop,
         });
 
@@ -227,10 +432,106 @@ mod test {
         let raw = "[";
         let val = AST::from_tokens(&tokenize(raw));
         let expect = Err(SyntaxError {
-            pos: InputPosition { line: 1, pos: 1 },
+            pos: InputPosition { file: None, line: 1, pos: 1, expansion: None },
             kind: ErrorKind::UnclosedLoop,
         });
 
         assert_eq!(val, expect);
     }
+
+    fn parse_procedures(raw: &str) -> Result<AST, SyntaxError> {
+        let ext = Extensions {
+            procedures: true,
+            ..Extensions::default()
+        };
+        AST::from_tokens(&tokenize_with(raw, &ext))
+    }
+
+    #[test]
+    fn procedure() {
+        let val = parse_procedures("([-]):");
+        let expect = Ok(AST(vec![
+            ASTNode {
+                kind: ASTNodeKind::Procedure,
+                pos: InputPosition { file: None, line: 1, pos: 1, expansion: None },
+                span: Span { start: 0, end: 5 },
+                ops: Some(vec![ASTNode::new_loop(
+                    1,
+                    2,
+                    4,
+                    vec![ASTNode::new_scalar(ASTNodeKind::DecVal, 1, 3)],
+                )]),
+            },
+            ASTNode::new_scalar(ASTNodeKind::Call, 1, 6),
+        ]));
+
+        assert_eq!(val, expect);
+        assert!(val.unwrap().uses_procedures());
+        assert!(!AST::from_tokens(&tokenize("(:)")).unwrap().uses_procedures());
+    }
+
+    #[test]
+    fn procedure_errors() {
+        let err = |line, pos, kind| {
+            Err(SyntaxError {
+                pos: InputPosition {
+                    file: None,
+                    line,
+                    pos,
+                    expansion: None,
+                },
+                kind,
+            })
+        };
+
+        assert_eq!(parse_procedures("(+"), err(1, 1, ErrorKind::UnclosedProcedure));
+        assert_eq!(parse_procedures("+)"), err(1, 2, ErrorKind::UnopenedProcedure));
+        assert_eq!(parse_procedures("(())"), err(1, 2, ErrorKind::NestedProcedure));
+        assert_eq!(parse_procedures("[()]"), err(1, 2, ErrorKind::NestedProcedure));
+        assert_eq!(parse_procedures("([)"), err(1, 2, ErrorKind::UnclosedLoop));
+        assert_eq!(parse_procedures("(])"), err(1, 2, ErrorKind::UnopenedLoop));
+    }
+
+    #[test]
+    fn closed_in_other_file() {
+        let mut ts = tokenize("+[");
+        ts.extend(tokenize_file("-]", &Extensions::default(), Some("a.bf".into())));
+        let expect = SyntaxError {
+            pos: InputPosition {
+                file: Some("a.bf".into()),
+                line: 1,
+                pos: 2,
+                expansion: None,
+            },
+            kind: ErrorKind::ClosedElsewhere,
+        };
+
+        assert_eq!(
+            AST::from_tokens(&ts).unwrap_err().to_string(),
+            "Loop or procedure closed in a different file or macro (a.bf:1:2)"
+        );
+        assert_eq!(AST::from_tokens(&ts), Err(expect));
+    }
+
+    #[test]
+    fn comments() {
+        let raw = "ab\ncd+[ e]";
+        let ast = AST::from_tokens_with_comments(&tokenize(raw)).unwrap();
+        let comment = |line, pos, start, end| ASTNode {
+            kind: ASTNodeKind::Comment,
+            pos: InputPosition {
+                file: None,
+                line,
+                pos,
+                expansion: None,
+            },
+            span: Span { start, end },
+            ops: None,
+        };
+
+        assert_eq!(ast.0[0], comment(1, 1, 0, 5));
+        assert_eq!(ast.0[1].kind, ASTNodeKind::IncVal);
+        assert_eq!(ast.0[2].ops, Some(vec![comment(2, 5, 7, 9)]));
+        assert_eq!(AST::from_tokens(&tokenize(raw)).unwrap().0.len(), 2);
+    }
 }
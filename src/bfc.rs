@@ -0,0 +1,404 @@
+//! On-disk bytecode format (`.bfc`).
+//!
+//! All integers are little-endian.
+//!
+//! ```text
+//! magic        b"SBFC"
+//! version      u16
+//! flags        u8    FLAG_POSITIONS | FLAG_INPUT
+//! cell_bits    u8    always 8
+//! tape_len     u32
+//! op_count     u32
+//! ops          op_count x (tag u8, operands)
+//! files        count u32, count x (len u32, len x u8)   if FLAG_POSITIONS
+//! positions    op_count x (file u32, line u32, pos u32) if FLAG_POSITIONS
+//! input        len u32, len x u8                        if FLAG_INPUT
+//! ```
+//!
+//! `files` names the included files positions can be in, and a position's
+//! `file` is 0 for the script itself or one more than an index into it.
+use crate::bytecode::{Op, Program};
+use crate::token::InputPosition;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 4] = b"SBFC";
+pub const VERSION: u16 = 2;
+
+const FLAG_POSITIONS: u8 = 1;
+const FLAG_INPUT: u8 = 2;
+
+const CELL_BITS: u8 = 8;
+
+const TAG_ADD: u8 = 0;
+const TAG_MOVE: u8 = 1;
+const TAG_CLEAR: u8 = 2;
+const TAG_MUL_ADD: u8 = 3;
+const TAG_READ: u8 = 4;
+const TAG_WRITE: u8 = 5;
+const TAG_DEBUG: u8 = 6;
+const TAG_JUMP_IF_ZERO: u8 = 7;
+const TAG_JUMP_IF_NON_ZERO: u8 = 8;
+
+/// A compiled program along with the configuration it was built for.
+#[derive(Debug, PartialEq)]
+pub struct Image {
+    pub tape_len: u32,
+    pub program: Program,
+    /// Input embedded with `--bang-input`.
+    pub input: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnsupportedCellWidth(u8),
+    EmptyTape,
+    InvalidOp(u8),
+    InvalidJump(usize),
+    InvalidFileName(usize),
+    InvalidFile(usize),
+    IOError(io::Error),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::BadMagic => write!(f, "Not a sateko bytecode file"),
+            FormatError::UnsupportedVersion(v) => write!(f, "Unsupported bytecode version {}", v),
+            FormatError::UnsupportedCellWidth(b) => write!(f, "Unsupported cell width of {} bits", b),
+            FormatError::EmptyTape => write!(f, "Tape length of 0 cells"),
+            FormatError::InvalidOp(tag) => write!(f, "Invalid op tag {}", tag),
+            FormatError::InvalidJump(i) => write!(f, "Invalid jump at op {}", i),
+            FormatError::InvalidFileName(i) => write!(f, "File name {} is not UTF-8", i),
+            FormatError::InvalidFile(i) => write!(f, "Invalid file at op {}", i),
+            FormatError::IOError(e) => write!(f, "I/O failure: {}", e),
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+impl From<io::Error> for FormatError {
+    fn from(e: io::Error) -> FormatError {
+        FormatError::IOError(e)
+    }
+}
+
+/// Whether `data` starts with the bytecode magic number.
+pub fn is_bytecode(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+impl Image {
+    /// Serialize the image. The position table is only written when
+    /// `positions` is set and the program has one.
+    pub fn write_to(&self, w: &mut dyn Write, positions: bool) -> io::Result<()> {
+        let prog = &self.program;
+        let positions = positions && prog.positions.len() == prog.ops.len();
+        let mut flags = 0;
+        if positions {
+            flags |= FLAG_POSITIONS;
+        }
+        if self.input.is_some() {
+            flags |= FLAG_INPUT;
+        }
+
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&[flags, CELL_BITS])?;
+        w.write_all(&self.tape_len.to_le_bytes())?;
+        w.write_all(&(prog.ops.len() as u32).to_le_bytes())?;
+
+        for op in &prog.ops {
+            match *op {
+                Op::Add(n) => w.write_all(&[TAG_ADD, n])?,
+                Op::Move(n) => {
+                    w.write_all(&[TAG_MOVE])?;
+                    w.write_all(&(n as i32).to_le_bytes())?;
+                }
+                Op::Clear => w.write_all(&[TAG_CLEAR])?,
+                Op::MulAdd(offset, factor) => {
+                    w.write_all(&[TAG_MUL_ADD])?;
+                    w.write_all(&(offset as i32).to_le_bytes())?;
+                    w.write_all(&[factor])?;
+                }
+                Op::Read => w.write_all(&[TAG_READ])?,
+                Op::Write => w.write_all(&[TAG_WRITE])?,
+                Op::Debug => w.write_all(&[TAG_DEBUG])?,
+                Op::JumpIfZero(target) => {
+                    w.write_all(&[TAG_JUMP_IF_ZERO])?;
+                    w.write_all(&(target as u32).to_le_bytes())?;
+                }
+                Op::JumpIfNonZero(target) => {
+                    w.write_all(&[TAG_JUMP_IF_NON_ZERO])?;
+                    w.write_all(&(target as u32).to_le_bytes())?;
+                }
+            }
+        }
+
+        if positions {
+            let mut files: Vec<&Rc<str>> = vec![];
+            for file in prog.positions.iter().filter_map(|pos| pos.file.as_ref()) {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+            w.write_all(&(files.len() as u32).to_le_bytes())?;
+            for file in &files {
+                w.write_all(&(file.len() as u32).to_le_bytes())?;
+                w.write_all(file.as_bytes())?;
+            }
+
+            for pos in &prog.positions {
+                let file = match &pos.file {
+                    Some(file) => files.iter().position(|f| *f == file).unwrap() + 1,
+                    None => 0,
+                };
+                w.write_all(&(file as u32).to_le_bytes())?;
+                w.write_all(&(pos.line as u32).to_le_bytes())?;
+                w.write_all(&(pos.pos as u32).to_le_bytes())?;
+            }
+        }
+
+        if let Some(input) = &self.input {
+            w.write_all(&(input.len() as u32).to_le_bytes())?;
+            w.write_all(input)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deserialize and validate an image.
+    pub fn read_from(r: &mut dyn Read) -> Result<Image, FormatError> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+        let version = u16::from_le_bytes(read_array(r)?);
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        let [flags, cell_bits] = read_array(r)?;
+        if cell_bits != CELL_BITS {
+            return Err(FormatError::UnsupportedCellWidth(cell_bits));
+        }
+        let tape_len = read_u32(r)?;
+        if tape_len == 0 {
+            return Err(FormatError::EmptyTape);
+        }
+        let count = read_u32(r)? as usize;
+
+        // counts aren't trusted to size anything until the data behind them
+        // has been read
+        let mut ops = vec![];
+        for _ in 0..count {
+            let [tag] = read_array(r)?;
+            ops.push(match tag {
+                TAG_ADD => Op::Add(read_array::<1>(r)?[0]),
+                TAG_MOVE => Op::Move(read_i32(r)? as isize),
+                TAG_CLEAR => Op::Clear,
+                TAG_MUL_ADD => Op::MulAdd(read_i32(r)? as isize, read_array::<1>(r)?[0]),
+                TAG_READ => Op::Read,
+                TAG_WRITE => Op::Write,
+                TAG_DEBUG => Op::Debug,
+                TAG_JUMP_IF_ZERO => Op::JumpIfZero(read_u32(r)? as usize),
+                TAG_JUMP_IF_NON_ZERO => Op::JumpIfNonZero(read_u32(r)? as usize),
+                _ => return Err(FormatError::InvalidOp(tag)),
+            });
+        }
+        validate_jumps(&ops)?;
+
+        let mut positions = vec![];
+        if flags & FLAG_POSITIONS != 0 {
+            let mut files: Vec<Rc<str>> = vec![];
+            for i in 0..read_u32(r)? {
+                let len = read_u32(r)? as usize;
+                let name = String::from_utf8(read_bytes(r, len)?);
+                files.push(Rc::from(name.map_err(|_| FormatError::InvalidFileName(i as usize))?));
+            }
+
+            for i in 0..count {
+                let file = match read_u32(r)? as usize {
+                    0 => None,
+                    n => Some(files.get(n - 1).ok_or(FormatError::InvalidFile(i))?.clone()),
+                };
+                let line = read_u32(r)? as usize;
+                let pos = read_u32(r)? as usize;
+                positions.push(InputPosition {
+                    file,
+                    line,
+                    pos,
+                    expansion: None,
+                });
+            }
+        }
+
+        let mut input = None;
+        if flags & FLAG_INPUT != 0 {
+            let len = read_u32(r)? as usize;
+            input = Some(read_bytes(r, len)?);
+        }
+
+        Ok(Image {
+            tape_len,
+            program: Program { ops, positions },
+            input,
+        })
+    }
+}
+
+/// Jumps must come in nested `[`/`]` pairs which target just past each other,
+/// as `Program::compile` produces them.
+fn validate_jumps(ops: &[Op]) -> Result<(), FormatError> {
+    let mut open = vec![];
+
+    for (i, op) in ops.iter().enumerate() {
+        match *op {
+            Op::JumpIfZero(_) => open.push(i),
+            Op::JumpIfNonZero(target) => {
+                let start = open.pop().ok_or(FormatError::InvalidJump(i))?;
+                if target != start + 1 || ops[start] != Op::JumpIfZero(i + 1) {
+                    return Err(FormatError::InvalidJump(i));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match open.pop() {
+        Some(i) => Err(FormatError::InvalidJump(i)),
+        None => Ok(()),
+    }
+}
+
+fn read_array<const N: usize>(r: &mut dyn Read) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read `len` bytes, only allocating as much as is actually there.
+fn read_bytes(r: &mut dyn Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    Read::take(r, len as u64).read_to_end(&mut data)?;
+    if data.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(data)
+}
+
+fn read_u32(r: &mut dyn Read) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(r)?))
+}
+
+fn read_i32(r: &mut dyn Read) -> io::Result<i32> {
+    Ok(i32::from_le_bytes(read_array(r)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FormatError, Image};
+    use crate::ast::AST;
+    use crate::bytecode::{Op, Program};
+    use crate::ir;
+    use crate::token::tokenize;
+
+    fn image(raw: &str) -> Image {
+        let ast = AST::from_tokens(&tokenize(raw)).unwrap();
+        Image {
+            tape_len: 300,
+            program: Program::compile(&ir::from_ast(&ast)),
+            input: Some(b"in".to_vec()),
+        }
+    }
+
+    fn round_trip(img: &Image, positions: bool) -> Result<Image, FormatError> {
+        let mut data = vec![];
+        img.write_to(&mut data, positions).unwrap();
+        Image::read_from(&mut &data[..])
+    }
+
+    #[test]
+    fn round_trip_positions() {
+        let img = image("+[->>+<<]<[.,#]");
+        assert_eq!(round_trip(&img, true).unwrap(), img);
+    }
+
+    #[test]
+    fn round_trip_files() {
+        let mut img = image("+>-<.");
+        img.program.positions[1].file = Some("lib.bf".into());
+        img.program.positions[2].file = Some("other.bf".into());
+        img.program.positions[3].file = Some("lib.bf".into());
+        let mut data = vec![];
+        img.write_to(&mut data, true).unwrap();
+        assert_eq!(Image::read_from(&mut &data[..]).unwrap(), img);
+
+        // the last op's position names a third file, which isn't there
+        let at = data.len() - 6 - 12;
+        data[at..at + 4].copy_from_slice(&3u32.to_le_bytes());
+        assert!(matches!(Image::read_from(&mut &data[..]), Err(FormatError::InvalidFile(4))));
+    }
+
+    #[test]
+    fn round_trip_stripped() {
+        let img = image("+[->>+<<]<[.,#]");
+        let loaded = round_trip(&img, false).unwrap();
+        assert_eq!(loaded.program.ops, img.program.ops);
+        assert!(loaded.program.positions.is_empty());
+    }
+
+    #[test]
+    fn bad_magic() {
+        let res = Image::read_from(&mut &b"+[-]"[..]);
+        assert!(matches!(res, Err(FormatError::BadMagic)));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut data = vec![];
+        image("+[-]").write_to(&mut data, true).unwrap();
+        data.truncate(data.len() - 1);
+        assert!(matches!(Image::read_from(&mut &data[..]), Err(FormatError::IOError(_))));
+    }
+
+    #[test]
+    fn oversized_counts() {
+        let mut data = vec![];
+        image("+").write_to(&mut data, false).unwrap();
+        // an op count and input length far past the end of the file
+        data[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Image::read_from(&mut &data[..16]), Err(FormatError::IOError(_))));
+
+        let mut data = vec![];
+        image("+").write_to(&mut data, false).unwrap();
+        let len = data.len();
+        data[len - 6..len - 2].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Image::read_from(&mut &data[..]), Err(FormatError::IOError(_))));
+    }
+
+    #[test]
+    fn empty_tape() {
+        let mut img = image("+");
+        img.tape_len = 0;
+        assert!(matches!(round_trip(&img, true), Err(FormatError::EmptyTape)));
+    }
+
+    #[test]
+    fn invalid_jump() {
+        let img = Image {
+            tape_len: 1,
+            program: Program {
+                ops: vec![Op::JumpIfZero(5), Op::JumpIfNonZero(1)],
+                positions: vec![],
+            },
+            input: None,
+        };
+        assert!(matches!(round_trip(&img, true), Err(FormatError::InvalidJump(1))));
+    }
+}
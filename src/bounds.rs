@@ -0,0 +1,317 @@
+//! Static analysis of where on the tape the pointer can go.
+//!
+//! The pointer is tracked as a range of offsets from the first cell through
+//! straight-line code and loops. A loop body is analysed until the range at
+//! its start stops growing, with any bound that keeps moving widened to
+//! unbounded, so a balanced loop costs one pass and an unbalanced one a few.
+//! The program stops at a move off the tape, so the range after each move is
+//! narrowed to the tape, and a move which leaves it every time it runs is
+//! reported. Cell values aren't tracked beyond knowing when the current one is
+//! zero, so every other loop is assumed to run, and a procedure call could
+//! move anywhere.
+use crate::ast::{ASTNode, ASTNodeKind, AST};
+use crate::token::InputPosition;
+use std::fmt;
+
+/// Offsets the pointer can be at, with `None` for no bound.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Range {
+    lo: Option<isize>,
+    hi: Option<isize>,
+}
+
+impl Range {
+    fn at(offset: isize) -> Range {
+        Range {
+            lo: Some(offset),
+            hi: Some(offset),
+        }
+    }
+
+    fn shift(self, n: isize) -> Range {
+        Range {
+            lo: self.lo.map(|lo| lo + n),
+            hi: self.hi.map(|hi| hi + n),
+        }
+    }
+
+    fn join(self, other: Range) -> Range {
+        Range {
+            lo: self.lo.zip(other.lo).map(|(a, b)| a.min(b)),
+            hi: self.hi.zip(other.hi).map(|(a, b)| a.max(b)),
+        }
+    }
+
+    /// `self` grown to cover `next`, dropping any bound which had to move.
+    fn widen(self, next: Range) -> Range {
+        let grown = self.join(next);
+        Range {
+            lo: grown.lo.filter(|_| grown.lo == self.lo),
+            hi: grown.hi.filter(|_| grown.hi == self.hi),
+        }
+    }
+}
+
+fn join(a: Option<Range>, b: Option<Range>) -> Option<Range> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.join(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    Start,
+    End,
+}
+
+/// A move which leaves the tape every time it runs.
+#[derive(Debug, PartialEq)]
+pub struct Escape {
+    pub pos: InputPosition,
+    pub edge: Edge,
+}
+
+impl fmt::Display for Escape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let edge = match self.edge {
+            Edge::Start => "tape beginning",
+            Edge::End => "end of tape",
+        };
+        write!(f, "Moves past {} every time it runs ({})", edge, self.pos)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Bounds {
+    /// Lowest cell the pointer can move to, or `None` if there is no bound.
+    pub min: Option<isize>,
+    /// Highest cell the pointer can move to, or `None` if there is no bound.
+    pub max: Option<isize>,
+    pub escapes: Vec<Escape>,
+    tape_len: usize,
+}
+
+impl Bounds {
+    /// Whether the pointer is proven to stay on the tape.
+    pub fn safe(&self) -> bool {
+        self.min.is_some_and(|min| min >= 0) && self.max.is_some_and(|max| (max as usize) < self.tape_len)
+    }
+
+    /// Number of cells from the first one that the program can use, if it is
+    /// bounded.
+    pub fn extent(&self) -> Option<usize> {
+        self.max.map(|max| max as usize + 1)
+    }
+}
+
+impl fmt::Display for Bounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lo = self.min.map(|n| n.to_string()).unwrap_or_default();
+        let hi = self.max.map(|n| format!("={}", n)).unwrap_or_default();
+        if self.safe() {
+            write!(f, "Pointer stays on the tape, in cells {}..{}", lo, hi)
+        } else {
+            write!(f, "Pointer may leave the tape, reaching cells {}..{}", lo, hi)
+        }
+    }
+}
+
+/// Analyse where the pointer of `ast` can go on a tape of `tape_len` cells.
+pub fn analyse(ast: &AST, tape_len: usize) -> Bounds {
+    let mut a = Analyser {
+        last: tape_len as isize - 1,
+        seen: Range::at(0),
+        escapes: vec![],
+    };
+    a.body(&ast.0, Some(Range::at(0)), true, true);
+    Bounds {
+        min: a.seen.lo,
+        max: a.seen.hi,
+        escapes: a.escapes,
+        tape_len,
+    }
+}
+
+struct Analyser {
+    /// The last cell of the tape.
+    last: isize,
+    /// Every offset the pointer has been moved to.
+    seen: Range,
+    escapes: Vec<Escape>,
+}
+
+impl Analyser {
+    /// The range after running `nodes` from `range`, or `None` if they never
+    /// finish. The current cell starts zero when `zero` is set, and every cell
+    /// when `blank` is set.
+    fn body(&mut self, nodes: &[ASTNode], mut range: Option<Range>, mut zero: bool, mut blank: bool) -> Option<Range> {
+        for node in nodes {
+            let r = range?;
+            range = match node.kind {
+                ASTNodeKind::IncTape | ASTNodeKind::DecTape => {
+                    zero = blank;
+                    let n = if node.kind == ASTNodeKind::IncTape { 1 } else { -1 };
+                    self.step(node, r, n)
+                }
+                // never runs
+                ASTNodeKind::Loop if zero => range,
+                ASTNodeKind::Loop => {
+                    zero = true;
+                    blank = false;
+                    Some(self.exec_loop(node.ops.as_ref().unwrap(), r))
+                }
+                ASTNodeKind::Call => {
+                    zero = false;
+                    blank = false;
+                    self.seen = Range { lo: None, hi: None };
+                    Some(Range {
+                        lo: Some(0),
+                        hi: Some(self.last),
+                    })
+                }
+                ASTNodeKind::IncVal | ASTNodeKind::DecVal | ASTNodeKind::Read => {
+                    zero = false;
+                    blank = false;
+                    range
+                }
+                _ => range,
+            };
+        }
+        range
+    }
+
+    fn step(&mut self, node: &ASTNode, range: Range, n: isize) -> Option<Range> {
+        let range = range.shift(n);
+        self.seen = self.seen.join(range);
+
+        let lo = range.lo.map_or(0, |lo| lo.max(0));
+        let hi = range.hi.map_or(self.last, |hi| hi.min(self.last));
+        if lo <= hi {
+            return Some(Range {
+                lo: Some(lo),
+                hi: Some(hi),
+            });
+        }
+        let edge = if n < 0 { Edge::Start } else { Edge::End };
+        self.escapes.push(Escape {
+            pos: node.pos.clone(),
+            edge,
+        });
+        None
+    }
+
+    /// The range after a loop entered at `entry`.
+    fn exec_loop(&mut self, body: &[ASTNode], entry: Range) -> Range {
+        // passes over the body from a range wider than the final one may
+        // report moves which don't always escape
+        let (seen, escapes) = (self.seen, self.escapes.len());
+        let mut head = entry;
+        loop {
+            let end = self.body(body, Some(head), false, false);
+            let grown = join(Some(head), end).map_or(head, |r| head.widen(r));
+            if grown == head {
+                // widening can overshoot, so pull the start back in to
+                // where the body can actually end
+                let narrowed = join(Some(entry), end).unwrap();
+                if narrowed != head {
+                    self.seen = seen;
+                    self.escapes.truncate(escapes);
+                    self.body(body, Some(narrowed), false, false);
+                }
+                return narrowed;
+            }
+            self.seen = seen;
+            self.escapes.truncate(escapes);
+            head = grown;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bounds, Edge};
+    use crate::ast::AST;
+    use crate::token::{tokenize, tokenize_file, Extensions};
+
+    fn analyse(text: &str, tape_len: usize) -> Bounds {
+        super::analyse(&AST::from_tokens(&tokenize(text)).unwrap(), tape_len)
+    }
+
+    fn escapes(text: &str, tape_len: usize) -> Vec<(Edge, usize)> {
+        analyse(text, tape_len).escapes.into_iter().map(|e| (e.edge, e.pos.pos)).collect()
+    }
+
+    #[test]
+    fn straight_line() {
+        let b = analyse(">>+<.>>>", 10);
+        assert_eq!((b.min, b.max, b.extent()), (Some(0), Some(4), Some(5)));
+        assert!(b.safe());
+        assert!(!analyse(">>+<.>>>", 4).safe());
+        assert_eq!(analyse("", 1).to_string(), "Pointer stays on the tape, in cells 0..=0");
+    }
+
+    #[test]
+    fn loops() {
+        let b = analyse("++[>+++[>++<-]<-]>>.", 3);
+        assert_eq!((b.min, b.max), (Some(0), Some(2)));
+        assert!(b.safe());
+
+        // moving right by an unknown amount, up to the end of the tape
+        let b = analyse("+[>+]<", 100);
+        assert_eq!((b.min, b.max), (Some(-1), Some(100)));
+        assert!(b.escapes.is_empty());
+        assert_eq!(b.to_string(), "Pointer may leave the tape, reaching cells -1..=100");
+
+        // the scan back left could run off the start, as cell values aren't
+        // known
+        let b = analyse("+>+>+[<]>", 100);
+        assert_eq!((b.min, b.max), (Some(-1), Some(3)));
+
+        // each pass ends one cell further left
+        let b = analyse(">>>>+[<<+>-]", 10);
+        assert_eq!((b.min, b.max), (Some(-1), Some(4)));
+    }
+
+    #[test]
+    fn escapes_found() {
+        assert_eq!(escapes("+<", 10), vec![(Edge::Start, 2)]);
+        assert_eq!(escapes(">>>", 3), vec![(Edge::End, 3)]);
+        // the program stops at the first, so later moves aren't reported
+        assert_eq!(escapes("<<>", 10), vec![(Edge::Start, 1)]);
+        // only if the loop runs, but then always
+        assert_eq!(escapes("+[<]", 10), vec![(Edge::Start, 3)]);
+        assert_eq!(escapes(",[>,]<<", 10), vec![]);
+        // nor in loops which never run
+        assert_eq!(escapes("[<]<", 10), vec![(Edge::Start, 4)]);
+        assert_eq!(
+            analyse("\n <", 10).escapes[0].to_string(),
+            "Moves past tape beginning every time it runs (2:2)"
+        );
+    }
+
+    #[test]
+    fn procedures() {
+        let ext = Extensions {
+            procedures: true,
+            ..Extensions::default()
+        };
+        let tokens = tokenize_file(b"(<)+:", &ext, None);
+        let b = super::analyse(&AST::from_tokens(&tokens).unwrap(), 10);
+        assert_eq!((b.min, b.max), (None, None));
+        assert!(b.escapes.is_empty());
+    }
+
+    #[test]
+    fn samples() {
+        let puta = analyse(include_str!("../bf/puta.bf"), 30_000);
+        assert!(puta.safe());
+        assert_eq!(puta.extent(), Some(2));
+        for text in &[include_str!("../bf/hello.bf"), include_str!("../bf/rot13.bf")] {
+            let b = analyse(text, 30_000);
+            assert!(!b.safe());
+            assert!(b.escapes.is_empty());
+        }
+    }
+}
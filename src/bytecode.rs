@@ -0,0 +1,254 @@
+use crate::exec::{ErrorKind, RuntimeError, Tape};
+use crate::ir::{Node, NodeKind};
+use crate::token::InputPosition;
+use std::io::{Read, Write};
+
+/// A single bytecode instruction. Jump targets are indices into
+/// `Program::ops` and are resolved when the program is compiled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Add(u8),
+    Move(isize),
+    Clear,
+    /// Add the active cell times the factor to the cell at the offset.
+    MulAdd(isize, u8),
+    Read,
+    Write,
+    Debug,
+    /// Jump to the target when the active cell is zero (`[`).
+    JumpIfZero(usize),
+    /// Jump to the target when the active cell is non-zero (`]`).
+    JumpIfNonZero(usize),
+}
+
+/// A flat program for the bytecode interpreter, with the source position of
+/// each op kept alongside for error reporting. `positions` is empty when the
+/// program was loaded without a position table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    pub positions: Vec<InputPosition>,
+}
+
+impl Program {
+    pub fn compile(ir: &[Node]) -> Program {
+        let mut prog = Program {
+            ops: vec![],
+            positions: vec![],
+        };
+        prog.emit_all(ir);
+        prog
+    }
+
+    fn emit_all(&mut self, ir: &[Node]) {
+        for node in ir {
+            let op = match &node.kind {
+                NodeKind::Add(n) => Op::Add(*n),
+                NodeKind::Move(n) => Op::Move(*n),
+                NodeKind::Clear => Op::Clear,
+                NodeKind::Read => Op::Read,
+                NodeKind::Write => Op::Write,
+                NodeKind::Debug => Op::Debug,
+                NodeKind::Transfer(targets) => {
+                    for &(offset, factor) in targets {
+                        self.emit(Op::MulAdd(offset, factor), &node.pos);
+                    }
+                    Op::Clear
+                }
+                NodeKind::Loop(body) => {
+                    // both jumps land just past their partner
+                    let start = self.emit(Op::JumpIfZero(0), &node.pos);
+                    self.emit_all(body);
+                    let end = self.emit(Op::JumpIfNonZero(start + 1), &node.pos);
+                    self.ops[start] = Op::JumpIfZero(end + 1);
+                    continue;
+                }
+            };
+            self.emit(op, &node.pos);
+        }
+    }
+
+    fn emit(&mut self, op: Op, pos: &InputPosition) -> usize {
+        self.ops.push(op);
+        self.positions.push(pos.clone());
+        self.ops.len() - 1
+    }
+
+    /// Run the program against `tape`, with the same I/O behaviour as
+    /// `exec::run`.
+    pub fn run(
+        &self,
+        tape: &mut Tape,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<(), RuntimeError> {
+        let len = tape.cells.len();
+        let mut pc = 0;
+
+        let res = loop {
+            let op = match self.ops.get(pc) {
+                Some(op) => *op,
+                None => break Ok(()),
+            };
+            let cell = &mut tape.cells[tape.pos];
+
+            match op {
+                Op::Add(n) => *cell = cell.wrapping_add(n),
+                Op::Move(n) => {
+                    let next = tape.pos as isize + n;
+                    if next < 0 {
+                        break Err(ErrorKind::OffTapeStart);
+                    } else if next as usize >= len {
+                        break Err(ErrorKind::OffTapeEnd(len));
+                    }
+                    tape.pos = next as usize;
+                }
+                Op::Clear => *cell = 0,
+                Op::MulAdd(offset, factor) => {
+                    // the loop this came from never moves off a zero cell
+                    let val = *cell;
+                    if val != 0 {
+                        let target = tape.pos as isize + offset;
+                        if target < 0 {
+                            break Err(ErrorKind::OffTapeStart);
+                        } else if target as usize >= len {
+                            break Err(ErrorKind::OffTapeEnd(len));
+                        }
+                        let target = &mut tape.cells[target as usize];
+                        *target = target.wrapping_add(val.wrapping_mul(factor));
+                    }
+                }
+                Op::Read => {
+                    let mut buf = [0u8];
+                    *cell = match input.read(&mut buf) {
+                        Ok(0) => 255,
+                        Ok(_) => buf[0],
+                        Err(_) => break Err(ErrorKind::IOError),
+                    };
+                }
+                Op::Write => {
+                    if output.write_all(&[*cell]).is_err() {
+                        break Err(ErrorKind::IOError);
+                    }
+                }
+                Op::Debug => {
+                    let _ = output.flush();
+                    let pos = self.position(pc);
+                    eprintln!("[{}{},{}] {}", pos.file_prefix(), pos.line, pos.pos, tape.dump());
+                }
+                Op::JumpIfZero(target) => {
+                    if *cell == 0 {
+                        pc = target;
+                        continue;
+                    }
+                }
+                Op::JumpIfNonZero(target) => {
+                    if *cell != 0 {
+                        pc = target;
+                        continue;
+                    }
+                }
+            }
+            pc += 1;
+        };
+
+        res.map_err(|kind| RuntimeError {
+            kind,
+            pos: self.position(pc),
+        })
+    }
+
+    fn position(&self, pc: usize) -> InputPosition {
+        self.positions
+            .get(pc)
+            .cloned()
+            .unwrap_or(InputPosition { file: None, line: 0, pos: 0, expansion: None })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Op, Program};
+    use crate::ast::AST;
+    use crate::exec::{ErrorKind, RuntimeError, Tape};
+    use crate::ir;
+    use crate::token::{tokenize, InputPosition};
+
+    fn compile(raw: &str) -> Program {
+        let ast = AST::from_tokens(&tokenize(raw)).unwrap();
+        Program::compile(&ir::from_ast(&ast))
+    }
+
+    fn run(raw: &str, tape_len: usize, input: &[u8]) -> (Result<(), RuntimeError>, Vec<u8>) {
+        let mut tape = Tape::with_size(tape_len);
+        let mut output = vec![];
+        let res = compile(raw).run(&mut tape, &mut &input[..], &mut output);
+        (res, output)
+    }
+
+    #[test]
+    fn jump_targets() {
+        let prog = compile("+[>[-]<-]");
+        assert_eq!(
+            prog.ops,
+            vec![
+                Op::Add(1),
+                Op::JumpIfZero(7),
+                Op::Move(1),
+                Op::Clear,
+                Op::Move(-1),
+                Op::Add(255),
+                Op::JumpIfNonZero(2),
+            ]
+        );
+        assert_eq!(prog.positions[1], InputPosition { file: None, line: 1, pos: 2, expansion: None });
+    }
+
+    #[test]
+    fn transfer() {
+        let prog = compile("[->+>---<<]");
+        assert_eq!(prog.ops, vec![Op::MulAdd(1, 1), Op::MulAdd(2, 253), Op::Clear]);
+        assert_eq!(run("+++[->+>---<<]>.>.", 3, b""), (Ok(()), vec![3, 247]));
+
+        let expect = Err(RuntimeError {
+            kind: ErrorKind::OffTapeEnd(2),
+            pos: InputPosition { file: None, line: 1, pos: 2, expansion: None },
+        });
+        assert_eq!(run("+[->>+<<]", 2, b""), (expect, vec![]));
+        assert_eq!(run("[->>+<<].", 2, b""), (Ok(()), vec![0]));
+    }
+
+    #[test]
+    fn hello() {
+        let raw = include_str!("../bf/hello.bf");
+        assert_eq!(run(raw, 30_000, b""), (Ok(()), b"Hello Sateko!\n".to_vec()));
+    }
+
+    #[test]
+    fn rot13() {
+        let raw = include_str!("../bf/rot13.bf");
+        assert_eq!(run(raw, 30_000, b"Hello"), (Ok(()), b"Uryyb".to_vec()));
+    }
+
+    #[test]
+    fn off_tape() {
+        let expect = Err(RuntimeError {
+            kind: ErrorKind::OffTapeStart,
+            pos: InputPosition { file: None, line: 1, pos: 3, expansion: None },
+        });
+        assert_eq!(run("+[<]", 2, b""), (expect, vec![]));
+
+        let expect = Err(RuntimeError {
+            kind: ErrorKind::OffTapeEnd(2),
+            pos: InputPosition { file: None, line: 1, pos: 2, expansion: None },
+        });
+        assert_eq!(run(".>>", 2, b""), (expect, vec![0]));
+
+        // as in the tree interpreter, coming straight back doesn't help
+        let expect = Err(RuntimeError {
+            kind: ErrorKind::OffTapeStart,
+            pos: InputPosition { file: None, line: 1, pos: 1, expansion: None },
+        });
+        assert_eq!(run("<>+.", 2, b""), (expect, vec![]));
+    }
+}
@@ -0,0 +1,234 @@
+//! Portable C code generation.
+//!
+//! The tape is a static array of `unsigned char`, so cells wrap at 256 on
+//! any conforming compiler, and `,` stores 255 at end of file like the
+//! interpreter does.
+use crate::exec::DEBUG_WINDOW;
+use crate::ir::{self, Node, NodeKind};
+use std::fmt::{self, Write};
+
+/// Render `ir` as a standalone C program. `input` is compiled in and read
+/// in place of stdin when given.
+pub fn generate(ir: &[Node], tape_len: u32, input: Option<&[u8]>) -> String {
+    let mut out = String::new();
+    write_program(&mut out, ir, tape_len, input).unwrap();
+    out
+}
+
+fn write_program(out: &mut String, ir: &[Node], tape_len: u32, input: Option<&[u8]>) -> fmt::Result {
+    writeln!(out, "#include <stdio.h>")?;
+    writeln!(out)?;
+    writeln!(out, "#define TAPE_LEN {}", tape_len)?;
+    writeln!(out)?;
+    writeln!(out, "static unsigned char tape[TAPE_LEN];")?;
+
+    if let Some(input) = input {
+        write_input(out, input)?;
+    }
+    if has_debug(ir) {
+        write_debug(out, tape_len)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "int main(void) {{")?;
+    writeln!(out, "    unsigned char *p = tape;")?;
+    if input.is_none() && has_read(ir) {
+        writeln!(out, "    int c;")?;
+    }
+    write_nodes(out, ir, 1, input.is_some())?;
+    writeln!(out, "    return 0;")?;
+    writeln!(out, "}}")
+}
+
+fn write_input(out: &mut String, input: &[u8]) -> fmt::Result {
+    writeln!(out)?;
+    writeln!(out, "#define INPUT_LEN {}", input.len())?;
+    writeln!(out)?;
+    // C does not allow an empty initializer, so pad empty input with a zero
+    let data = if input.is_empty() { &[0][..] } else { input };
+    write!(out, "static const unsigned char input[] = {{")?;
+    for (i, b) in data.iter().enumerate() {
+        if i % 16 == 0 {
+            write!(out, "\n   ")?;
+        }
+        write!(out, " {},", b)?;
+    }
+    writeln!(out, "\n}};")?;
+    writeln!(out, "static unsigned long input_pos;")?;
+    writeln!(out)?;
+    writeln!(out, "static unsigned char read_input(void) {{")?;
+    writeln!(out, "    if (input_pos < INPUT_LEN) {{")?;
+    writeln!(out, "        return input[input_pos++];")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    return 255;")?;
+    writeln!(out, "}}")
+}
+
+/// Define `debug`, which flushes stdout and prints the cells around `p` to
+/// stderr the way `Tape::dump` does.
+fn write_debug(out: &mut String, tape_len: u32) -> fmt::Result {
+    let window = (DEBUG_WINDOW as u32).min(tape_len);
+    writeln!(out)?;
+    writeln!(out, "static void debug(unsigned char *p, const char *file, int line, int col) {{")?;
+    writeln!(out, "    unsigned long pos = p - tape, start = 0, i;")?;
+    writeln!(out, "    if (pos > {}) {{", DEBUG_WINDOW / 2)?;
+    writeln!(out, "        start = pos - {};", DEBUG_WINDOW / 2)?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    if (start > {}) {{", tape_len - window)?;
+    writeln!(out, "        start = {};", tape_len - window)?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fflush(stdout);")?;
+    writeln!(out, "    fprintf(stderr, \"[%s%d,%d] ptr %lu:\", file, line, col, pos);")?;
+    writeln!(out, "    for (i = start; i < start + {}; i++) {{", window)?;
+    writeln!(out, "        fprintf(stderr, i == pos ? \" [%u]\" : \" %u\", tape[i]);")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fputc('\\n', stderr);")?;
+    writeln!(out, "}}")
+}
+
+fn write_nodes(out: &mut String, ir: &[Node], depth: usize, embedded: bool) -> fmt::Result {
+    let indent = "    ".repeat(depth);
+
+    for node in ir {
+        match &node.kind {
+            NodeKind::Add(n) if *n > 128 => writeln!(out, "{}*p -= {};", indent, n.wrapping_neg())?,
+            NodeKind::Add(n) => writeln!(out, "{}*p += {};", indent, n)?,
+            NodeKind::Move(n) if *n < 0 => writeln!(out, "{}p -= {};", indent, -n)?,
+            NodeKind::Move(n) => writeln!(out, "{}p += {};", indent, n)?,
+            NodeKind::Clear => writeln!(out, "{}*p = 0;", indent)?,
+            NodeKind::Transfer(targets) => {
+                // the loop this came from never moves off a zero cell, and
+                // `p[off]` could be outside the tape
+                writeln!(out, "{}if (*p) {{", indent)?;
+                for &(offset, factor) in targets {
+                    let (op, factor) = if factor > 128 {
+                        ("-=", factor.wrapping_neg())
+                    } else {
+                        ("+=", factor)
+                    };
+                    if factor == 1 {
+                        writeln!(out, "{}    p[{}] {} *p;", indent, offset, op)?;
+                    } else {
+                        writeln!(out, "{}    p[{}] {} *p * {};", indent, offset, op, factor)?;
+                    }
+                }
+                writeln!(out, "{}    *p = 0;", indent)?;
+                writeln!(out, "{}}}", indent)?;
+            }
+            NodeKind::Read if embedded => writeln!(out, "{}*p = read_input();", indent)?,
+            NodeKind::Read => {
+                writeln!(out, "{}c = getchar();", indent)?;
+                writeln!(out, "{}*p = c == EOF ? 255 : c;", indent)?;
+            }
+            NodeKind::Write => writeln!(out, "{}putchar(*p);", indent)?,
+            NodeKind::Debug => {
+                let file = string(&node.pos.file_prefix());
+                writeln!(out, "{}debug(p, {}, {}, {});", indent, file, node.pos.line, node.pos.pos)?
+            }
+            NodeKind::Loop(body) => {
+                writeln!(out, "{}while (*p) {{", indent)?;
+                write_nodes(out, body, depth + 1, embedded)?;
+                writeln!(out, "{}}}", indent)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A C string literal holding the bytes of `s`, with octal escapes as they
+/// can't run on into the next character.
+fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            b' '..=b'~' => out.push(b as char),
+            _ => out.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn has_read(ir: &[Node]) -> bool {
+    ir::contains(ir, &|kind| *kind == NodeKind::Read)
+}
+
+fn has_debug(ir: &[Node]) -> bool {
+    ir::contains(ir, &|kind| *kind == NodeKind::Debug)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ir;
+
+    fn generate(raw: &str, input: Option<&[u8]>) -> String {
+        super::generate(&ir::parse(raw), 300, input)
+    }
+
+    fn body(src: &str) -> &str {
+        let start = src.find("    unsigned char *p = tape;\n").unwrap();
+        let end = src.rfind("    return 0;").unwrap();
+        &src[start..end]
+    }
+
+    #[test]
+    fn statements() {
+        let src = generate("+++>--<<[->+>---<<][-]+[>.<-]", None);
+        assert!(src.contains("static unsigned char tape[TAPE_LEN];"));
+        assert!(src.contains("#define TAPE_LEN 300\n"));
+        assert_eq!(
+            body(&src),
+            "    unsigned char *p = tape;
+    *p += 3;
+    p += 1;
+    *p -= 2;
+    p -= 2;
+    if (*p) {
+        p[1] += *p;
+        p[2] -= *p * 3;
+        *p = 0;
+    }
+    *p = 0;
+    *p += 1;
+    while (*p) {
+        p += 1;
+        putchar(*p);
+        p -= 1;
+        *p -= 1;
+    }
+"
+        );
+        assert!(!src.contains("debug("));
+    }
+
+    #[test]
+    fn read() {
+        let src = generate(",.", None);
+        assert!(src.contains("    int c;\n    c = getchar();\n    *p = c == EOF ? 255 : c;\n"));
+
+        let src = generate(",.", Some(b"hi"));
+        assert!(src.contains("static const unsigned char input[] = {\n    104, 105,\n};"));
+        assert!(src.contains("    *p = read_input();\n"));
+        assert!(!src.contains("getchar"));
+
+        let src = generate(",.", Some(b""));
+        assert!(src.contains("#define INPUT_LEN 0\n"));
+        assert!(src.contains("input[] = {\n    0,\n};"));
+    }
+
+    #[test]
+    fn debug() {
+        let src = generate("+\n #", None);
+        assert!(src.contains("static void debug(unsigned char *p, const char *file, int line, int col)"));
+        assert!(src.contains("    debug(p, \"\", 2, 2);\n"));
+
+        let mut ir = ir::parse("#");
+        ir[0].pos.file = Some("lib/\"\u{e9}\".bf".into());
+        let src = super::generate(&ir, 300, None);
+        assert!(src.contains("    debug(p, \"lib/\\\"\\303\\251\\\".bf:\", 1, 1);\n"));
+    }
+}
@@ -0,0 +1,400 @@
+//! Surface syntaxes of brainfuck.
+//!
+//! A dialect spells the commands differently but lexes to the same tokens as
+//! plain brainfuck, so parsing and everything after it work on any of them.
+//! Characters outside of commands are `Comment` tokens of one character each,
+//! which keeps `#include` and `#define` lines readable in every dialect.
+//!
+//! Besides the built-in dialects, a token map file gives the words for each
+//! command, one command per line followed by its words:
+//!
+//! ```text
+//! ; comments start with a semicolon
+//! + incr
+//! - decr
+//! [ Ook! Ook?
+//! ```
+//!
+//! A command spelled as several words matches them separated by any amount of
+//! whitespace. Words of more than one character only match whole words, so
+//! `incr` is never found inside `incremental`.
+use crate::token::{self, Extensions, Span, Token, TokenKind};
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+/// Names of the built-in dialects.
+pub const DIALECTS: &[&str] = &["bf", "ook", "blub", "alphuck"];
+
+/// Turns source text into tokens.
+pub trait Lexer {
+    /// Tokenize the text of `file`, which every token position refers to.
+    fn tokenize(&self, text: &[u8], file: Option<Rc<str>>) -> Vec<Token>;
+
+    /// How the command `kind` is written, if the dialect has it.
+    fn spell(&self, kind: &TokenKind) -> Option<String>;
+}
+
+/// Split source text into the program and, when `bang_input` is enabled and
+/// the text contains a `!` outside of any command, the program input that
+/// follows it.
+pub fn split_input<'a>(lexer: &dyn Lexer, s: &'a [u8], ext: &Extensions) -> (&'a [u8], Option<&'a [u8]>) {
+    if !ext.bang_input {
+        return (s, None);
+    }
+    let bang = lexer
+        .tokenize(s, None)
+        .into_iter()
+        .find(|t| t.kind == TokenKind::Comment && &s[t.span.start..t.span.end] == b"!");
+    match bang {
+        Some(t) => (&s[..t.span.start], Some(&s[t.span.end..])),
+        None => (s, None),
+    }
+}
+
+/// The built-in dialect `name`. Only plain brainfuck has extensions.
+pub fn builtin(name: &str, ext: &Extensions) -> Option<Box<dyn Lexer>> {
+    match name {
+        "bf" => Some(Box::new(Brainfuck(ext.clone()))),
+        "ook" => Some(Box::new(Words::ook())),
+        "blub" => Some(Box::new(Words::blub())),
+        "alphuck" => Some(Box::new(Chars::alphuck())),
+        _ => None,
+    }
+}
+
+/// The command written as `c` in plain brainfuck, including extensions.
+fn command(c: char) -> Option<TokenKind> {
+    match c {
+        '#' => Some(TokenKind::Debug),
+        '(' => Some(TokenKind::StartProc),
+        ')' => Some(TokenKind::EndProc),
+        ':' => Some(TokenKind::Call),
+        c => match TokenKind::from(c) {
+            TokenKind::Comment => None,
+            kind => Some(kind),
+        },
+    }
+}
+
+/// Plain brainfuck with the given extensions.
+pub struct Brainfuck(pub Extensions);
+
+impl Lexer for Brainfuck {
+    fn tokenize(&self, text: &[u8], file: Option<Rc<str>>) -> Vec<Token> {
+        token::tokenize_file(text, &self.0, file)
+    }
+
+    fn spell(&self, kind: &TokenKind) -> Option<String> {
+        let ext = &self.0;
+        let c = match kind {
+            TokenKind::Debug if ext.debug_hash => '#',
+            TokenKind::StartProc if ext.procedures => '(',
+            TokenKind::EndProc if ext.procedures => ')',
+            TokenKind::Call if ext.procedures => ':',
+            TokenKind::Debug | TokenKind::StartProc | TokenKind::EndProc | TokenKind::Call => return None,
+            kind => "[]><+-,.".chars().find(|&c| TokenKind::from(c) == *kind)?,
+        };
+        Some(c.to_string())
+    }
+}
+
+/// Commands spelled as single characters.
+pub struct Chars {
+    map: Vec<(char, TokenKind)>,
+}
+
+impl Chars {
+    /// alphuck, which spells `> < + [ . - ] ,` as `a c e i j o p s`.
+    pub fn alphuck() -> Chars {
+        Chars {
+            map: "aceijops"
+                .chars()
+                .zip("><+[.-],".chars())
+                .map(|(a, c)| (a, command(c).unwrap()))
+                .collect(),
+        }
+    }
+}
+
+impl Lexer for Chars {
+    fn tokenize(&self, text: &[u8], file: Option<Rc<str>>) -> Vec<Token> {
+        token::tokenize_chars(text, file, &|c| match self.map.iter().find(|(m, _)| *m == c) {
+            Some((_, kind)) => kind.clone(),
+            None => TokenKind::Comment,
+        })
+    }
+
+    fn spell(&self, kind: &TokenKind) -> Option<String> {
+        self.map.iter().find(|(_, k)| k == kind).map(|(c, _)| c.to_string())
+    }
+}
+
+/// Commands spelled as sequences of words.
+pub struct Words {
+    commands: Vec<(Vec<String>, TokenKind)>,
+}
+
+impl Words {
+    /// Ook!, where each command is a pair of `Ook.`, `Ook?` and `Ook!`.
+    pub fn ook() -> Words {
+        Words::pairs("Ook")
+    }
+
+    /// Blub, which is Ook! with `Blub` in place of `Ook`.
+    pub fn blub() -> Words {
+        Words::pairs("Blub")
+    }
+
+    fn pairs(word: &str) -> Words {
+        let pairs = [
+            ('>', ".?"),
+            ('<', "?."),
+            ('+', ".."),
+            ('-', "!!"),
+            ('.', "!."),
+            (',', ".!"),
+            ('[', "!?"),
+            (']', "?!"),
+        ];
+        Words {
+            commands: pairs
+                .iter()
+                .map(|(c, p)| {
+                    let words = p.chars().map(|p| format!("{}{}", word, p)).collect();
+                    (words, command(*c).unwrap())
+                })
+                .collect(),
+        }
+    }
+
+    /// Read a token map file.
+    pub fn parse(text: &str) -> Result<Words, MapError> {
+        let mut commands = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let err = |kind| MapError { line: n + 1, kind };
+            let mut fields = line.split_whitespace();
+            let c = match fields.next() {
+                Some(c) if c.starts_with(';') => continue,
+                Some(c) => c,
+                None => continue,
+            };
+            let kind = match c.chars().next().and_then(command) {
+                Some(kind) if c.len() == 1 => kind,
+                _ => return Err(err(ErrorKind::UnknownCommand(c.to_string()))),
+            };
+            let words: Vec<String> = fields.map(str::to_string).collect();
+            if words.is_empty() {
+                return Err(err(ErrorKind::MissingWords));
+            }
+            commands.push((words, kind));
+        }
+        Ok(Words { commands })
+    }
+
+    /// The end of `words` if they are spelled from the character `i` on.
+    fn match_at(&self, chars: &[(Option<char>, Span)], i: usize, words: &[String]) -> Option<usize> {
+        let joined = |j: usize| chars[j - 1].1.end == chars[j].1.start;
+        let alnum = |j: usize| chars[j].0.is_some_and(char::is_alphanumeric);
+
+        let mut j = i;
+        for (n, word) in words.iter().enumerate() {
+            if n > 0 {
+                while j < chars.len() && chars[j].0.is_some_and(char::is_whitespace) {
+                    j += 1;
+                }
+                if j < chars.len() && joined(j) && alnum(j) && alnum(j - 1) {
+                    return None;
+                }
+            }
+            let start = j;
+            for c in word.chars() {
+                if j == chars.len() || chars[j].0 != Some(c) || (j > start && !joined(j)) {
+                    return None;
+                }
+                j += 1;
+            }
+        }
+
+        let first = &words[0];
+        if first.chars().count() > 1 && i > 0 && joined(i) && alnum(i) && alnum(i - 1) {
+            return None;
+        }
+        let last = &words[words.len() - 1];
+        if last.chars().count() > 1 && j < chars.len() && joined(j) && alnum(j) && alnum(j - 1) {
+            return None;
+        }
+        Some(j)
+    }
+}
+
+impl Lexer for Words {
+    fn tokenize(&self, text: &[u8], file: Option<Rc<str>>) -> Vec<Token> {
+        // every character but newlines, with its position
+        let tokens = token::tokenize_chars(text, file, &|_| TokenKind::Comment);
+        let chars: Vec<(Option<char>, Span)> = tokens
+            .iter()
+            .map(|t| {
+                let c = std::str::from_utf8(&text[t.span.start..t.span.end]).ok();
+                (c.and_then(|c| c.chars().next()), t.span)
+            })
+            .collect();
+
+        let mut ret = vec![];
+        let mut i = 0;
+        while i < tokens.len() {
+            let found = self
+                .commands
+                .iter()
+                .filter_map(|(words, kind)| Some((self.match_at(&chars, i, words)?, kind)))
+                .max_by_key(|(end, _)| *end);
+            match found {
+                Some((end, kind)) => {
+                    ret.push(Token {
+                        kind: kind.clone(),
+                        pos: tokens[i].pos.clone(),
+                        span: Span {
+                            start: tokens[i].span.start,
+                            end: tokens[end - 1].span.end,
+                        },
+                    });
+                    i = end;
+                }
+                None => {
+                    ret.push(tokens[i].clone());
+                    i += 1;
+                }
+            }
+        }
+        ret
+    }
+
+    fn spell(&self, kind: &TokenKind) -> Option<String> {
+        self.commands.iter().find(|(_, k)| k == kind).map(|(words, _)| words.join(" "))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MapError {
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ErrorKind {
+    UnknownCommand(String),
+    MissingWords,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::UnknownCommand(c) => write!(f, "Unknown command \"{}\"", c)?,
+            ErrorKind::MissingWords => write!(f, "No words given for command")?,
+        }
+        write!(f, " (line {})", self.line)
+    }
+}
+
+impl Error for MapError {}
+
+#[cfg(test)]
+mod test {
+    use super::{builtin, split_input, ErrorKind, Lexer, MapError, Words};
+    use crate::ast::AST;
+    use crate::token::{tokenize, Extensions, InputPosition, TokenKind};
+
+    fn commands(lexer: &dyn Lexer, text: &str) -> Vec<TokenKind> {
+        let tokens = lexer.tokenize(text.as_bytes(), None);
+        AST::from_tokens(&tokens).unwrap();
+        tokens.into_iter().map(|t| t.kind).filter(|k| *k != TokenKind::Comment).collect()
+    }
+
+    fn bf(text: &str) -> Vec<TokenKind> {
+        tokenize(text).into_iter().map(|t| t.kind).filter(|k| *k != TokenKind::Comment).collect()
+    }
+
+    #[test]
+    fn ook() {
+        let ook = builtin("ook", &Extensions::default()).unwrap();
+        let text = "Ook. Ook. Ook! Ook? Ook. Ook?\nOok. Ook. Ook? Ook. Ook! Ook! Ook? Ook! Ook. Ook? Ook! Ook.";
+        assert_eq!(commands(&*ook, text), bf("+[>+<-]>."));
+        // pairs can be glued together or split over lines
+        assert_eq!(commands(&*ook, "Ook.Ook.Ook!\nOok!"), bf("+-"));
+
+        let tokens = ook.tokenize(b"hi Ook. Ook?\n  Ook! Ook.", None);
+        assert_eq!(tokens[3].kind, TokenKind::IncTape);
+        assert_eq!((tokens[3].span.start, tokens[3].span.end), (3, 12));
+        let pos = &tokens.last().unwrap().pos;
+        assert_eq!((pos.line, pos.pos), (2, 3));
+    }
+
+    #[test]
+    fn blub_and_alphuck() {
+        let blub = builtin("blub", &Extensions::default()).unwrap();
+        assert_eq!(commands(&*blub, "Blub. Blub. Blub! Blub? Blub? Blub!"), bf("+[]"));
+
+        let alphuck = builtin("alphuck", &Extensions::default()).unwrap();
+        assert_eq!(commands(&*alphuck, "eee iaeco p aj s"), bf("+++[>+<-]>.,"));
+        assert_eq!(commands(&*alphuck, "+-<>"), vec![]);
+    }
+
+    #[test]
+    fn extensions() {
+        let ext = Extensions {
+            debug_hash: true,
+            ..Extensions::default()
+        };
+        let bf_ext = builtin("bf", &ext).unwrap();
+        assert_eq!(commands(&*bf_ext, "+#"), vec![TokenKind::IncVal, TokenKind::Debug]);
+        assert!(builtin("cow", &ext).is_none());
+    }
+
+    #[test]
+    fn bang_input() {
+        let ext = Extensions {
+            bang_input: true,
+            ..Extensions::default()
+        };
+        let bf = builtin("bf", &ext).unwrap();
+        assert_eq!(split_input(&*bf, b",.!ab!c", &ext), (&b",."[..], Some(&b"ab!c"[..])));
+        assert_eq!(split_input(&*bf, b",.", &ext), (&b",."[..], None));
+        assert_eq!(split_input(&*bf, b",.!ab", &Extensions::default()), (&b",.!ab"[..], None));
+
+        // the `!` in `Ook!` is part of a command
+        let ook = builtin("ook", &ext).unwrap();
+        let text = b"Ook. Ook! Ook! Ook.!x";
+        assert_eq!(split_input(&*ook, text, &ext), (&text[..19], Some(&b"x"[..])));
+        assert_eq!(split_input(&*ook, b"Ook! Ook.", &ext), (&b"Ook! Ook."[..], None));
+    }
+
+    #[test]
+    fn token_map() {
+        let map = Words::parse("; counting words\n+ incr\n\n- decr\n[ while\n] end while\n. say").unwrap();
+        assert_eq!(commands(&map, "incr incr while decr end  while incremental say"), bf("++[-]."));
+        assert_eq!(commands(&map, "incr while\n\tend\nwhile"), bf("+[]"));
+        assert_eq!(commands(&map, "incr endwhile"), bf("+"));
+
+        let tokens = map.tokenize("incr incr".as_bytes(), None);
+        assert_eq!(
+            tokens[2].pos,
+            InputPosition {
+                file: None,
+                line: 1,
+                pos: 6,
+                expansion: None
+            }
+        );
+
+        // single characters match anywhere
+        let map = Words::parse("+ a\n. b").unwrap();
+        assert_eq!(commands(&map, "aab"), bf("++."));
+    }
+
+    #[test]
+    fn map_errors() {
+        let err = |kind, line| Err(MapError { line, kind });
+        assert_eq!(Words::parse("+ a\n!! b").map(|_| ()), err(ErrorKind::UnknownCommand("!!".into()), 2));
+        assert_eq!(Words::parse("\n\n-").map(|_| ()), err(ErrorKind::MissingWords, 3));
+    }
+}
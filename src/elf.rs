@@ -0,0 +1,164 @@
+//! Minimal static ELF64 executables for x86-64 Linux.
+//!
+//! The file holds the ELF header, two program headers, the code, any embedded
+//! input and any output computed at compile time, all in one read-only
+//! executable segment. A second, zero-filled segment holds the embedded input
+//! counter and the tape.
+use crate::x86::{Layout, Program};
+
+const BASE: u64 = 0x40_0000;
+const PAGE: u64 = 0x1000;
+
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+const PHDR_COUNT: u64 = 2;
+const HEADERS_SIZE: u64 = EHDR_SIZE + PHDR_SIZE * PHDR_COUNT;
+
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Link `prog` into an executable image.
+pub fn link(prog: &Program) -> Vec<u8> {
+    let input = prog.input.as_deref().unwrap_or(&[]);
+    let code_addr = BASE + HEADERS_SIZE;
+    // instructions have fixed sizes, so any layout gives the code length
+    let code_len = prog.encode(&Layout::default()).len() as u64;
+    let file_len = HEADERS_SIZE + code_len + input.len() as u64 + prog.output.len() as u64;
+    let bss_addr = (BASE + file_len).div_ceil(PAGE) * PAGE;
+    let bss_len = 8 + prog.tape_len as u64;
+
+    let layout = Layout {
+        code: code_addr,
+        input: code_addr + code_len,
+        output: code_addr + code_len + input.len() as u64,
+        input_pos: bss_addr,
+        tape: bss_addr + 8,
+    };
+
+    let mut out = Vec::with_capacity(file_len as usize);
+
+    // ELF header
+    out.extend_from_slice(b"\x7fELF");
+    out.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, little-endian, version 1, System V
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&ET_EXEC.to_le_bytes());
+    out.extend_from_slice(&EM_X86_64.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes()); // version
+    out.extend_from_slice(&code_addr.to_le_bytes()); // entry
+    out.extend_from_slice(&EHDR_SIZE.to_le_bytes()); // program headers follow
+    out.extend_from_slice(&0u64.to_le_bytes()); // no section headers
+    out.extend_from_slice(&0u32.to_le_bytes()); // flags
+    out.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PHDR_COUNT as u16).to_le_bytes());
+    out.extend_from_slice(&[0; 6]); // section header size, count and names
+
+    write_phdr(&mut out, PF_R | PF_X, 0, BASE, file_len, file_len);
+    write_phdr(&mut out, PF_R | PF_W, 0, bss_addr, 0, bss_len);
+
+    out.extend_from_slice(&prog.encode(&layout));
+    out.extend_from_slice(input);
+    out.extend_from_slice(&prog.output);
+    out
+}
+
+fn write_phdr(out: &mut Vec<u8>, flags: u32, offset: u64, addr: u64, file_len: u64, mem_len: u64) {
+    out.extend_from_slice(&PT_LOAD.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&addr.to_le_bytes()); // virtual address
+    out.extend_from_slice(&addr.to_le_bytes()); // physical address
+    out.extend_from_slice(&file_len.to_le_bytes());
+    out.extend_from_slice(&mem_len.to_le_bytes());
+    out.extend_from_slice(&PAGE.to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BASE, HEADERS_SIZE, PAGE};
+    use crate::ast::AST;
+    use crate::ir;
+    use crate::partial;
+    use crate::token::tokenize;
+    use crate::x86::Program;
+    use std::convert::TryInto;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::{Command, Stdio};
+
+    fn link(raw: &str, input: Option<Vec<u8>>) -> Vec<u8> {
+        let ast = AST::from_tokens(&tokenize(raw)).unwrap();
+        super::link(&Program::lower(&ir::from_ast(&ast), 30_000, input).unwrap())
+    }
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn header() {
+        let data = link("+.", None);
+        assert_eq!(&data[..4], b"\x7fELF");
+        assert_eq!(u16_at(&data, 16), 2);
+        assert_eq!(u16_at(&data, 18), 62);
+        assert_eq!(u64_at(&data, 24), BASE + HEADERS_SIZE);
+        assert_eq!(u16_at(&data, 56), 2);
+    }
+
+    #[test]
+    fn segments() {
+        let data = link(",.", Some(b"xyz".to_vec()));
+        assert!(data.ends_with(b"xyz"));
+
+        let text = 64;
+        assert_eq!(u64_at(&data, text + 16), BASE);
+        assert_eq!(u64_at(&data, text + 32), data.len() as u64);
+
+        let bss = 64 + 56;
+        let bss_addr = u64_at(&data, bss + 16);
+        assert_eq!(bss_addr % PAGE, 0);
+        assert!(bss_addr >= BASE + data.len() as u64);
+        assert_eq!(u64_at(&data, bss + 32), 0);
+        assert_eq!(u64_at(&data, bss + 40), 8 + 30_000);
+    }
+
+    /// Link `raw`, run it with `input` and return what it wrote.
+    fn link_and_run(raw: &str, input: &[u8], name: &str) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("sateko-elf-{}-{}", name, std::process::id()));
+        std::fs::write(&path, link(raw, None)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut child = Command::new(&path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(output.status.success(), "{:?}", output.status);
+        output.stdout
+    }
+
+    #[test]
+    fn transfer_left_of_tape() {
+        // the transfer would write below the tape, into the code, if it ran
+        let raw = ",[-][-<<<<<<<<<<<<<<<<<<<<+>>>>>>>>>>>>>>>>>>>>]++++++++[>++++++++<-]>+.";
+        assert_eq!(link_and_run(raw, b"x", "transfer"), b"A");
+    }
+
+    #[test]
+    fn output() {
+        let ast = AST::from_tokens(&tokenize("+++.,")).unwrap();
+        let prefix = partial::evaluate(&ast, 30_000, partial::STEP_BUDGET);
+        let prog = Program::lower_prefix(&prefix, 30_000, Some(b"xyz".to_vec())).unwrap();
+        assert!(super::link(&prog).ends_with(b"xyz\x03"));
+    }
+}
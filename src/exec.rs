@@ -1,4 +1,5 @@
 use crate::ast::{ASTNode, ASTNodeKind, AST};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
 use std::io::{Read, Write};
@@ -11,18 +12,46 @@ use inkwell::values::BasicValueEnum;
 
 pub use crate::ast::InputPosition;
 
-struct Tape {
-    cells: Vec<u8>,
-    pos: usize,
+/// Number of cells shown around the tape position by a `#` dump.
+pub const DEBUG_WINDOW: usize = 10;
+/// Procedures are numbered by a cell value.
+const PROCEDURES: usize = 256;
+/// Deepest procedure call the interpreter allows before giving up, well
+/// short of overflowing the native stack.
+const MAX_CALL_DEPTH: usize = 1_000;
+
+pub struct Tape {
+    pub cells: Vec<u8>,
+    pub pos: usize,
 }
 
 impl Tape {
-    fn with_size(size: usize) -> Tape {
+    pub fn with_size(size: usize) -> Tape {
         Tape {
             cells: vec![0; size],
             pos: 0,
         }
     }
+
+    /// Render the tape position and the cells surrounding it, with the active
+    /// cell in brackets, e.g. `ptr 2: 0 8 [72] 0`.
+    pub fn dump(&self) -> String {
+        let window = DEBUG_WINDOW.min(self.cells.len());
+        let start = self
+            .pos
+            .saturating_sub(DEBUG_WINDOW / 2)
+            .min(self.cells.len() - window);
+
+        let mut out = format!("ptr {}:", self.pos);
+        for (i, val) in self.cells[start..start + window].iter().enumerate() {
+            if start + i == self.pos {
+                out.push_str(&format!(" [{}]", val));
+            } else {
+                out.push_str(&format!(" {}", val));
+            }
+        }
+        out
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -36,21 +65,31 @@ pub enum ErrorKind {
     OffTapeStart,
     OffTapeEnd(usize),
     IOError,
+    UndefinedProcedure(u8),
+    CallDepth,
+}
+
+impl ErrorKind {
+    fn message(&self) -> &str {
+        match self {
+            ErrorKind::OffTapeStart => "Tried to move past tape beginning",
+            ErrorKind::OffTapeEnd(_) => "Tried to move past end of tape",
+            ErrorKind::IOError => "I/O failure",
+            ErrorKind::UndefinedProcedure(_) => "Called an undefined procedure",
+            ErrorKind::CallDepth => "Procedure calls nested too deeply",
+        }
+    }
 }
 
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} ({}:{})", self, self.pos.line, self.pos.pos)
+        write!(f, "{} ({})", self.kind.message(), self.pos)
     }
 }
 
 impl Error for RuntimeError {
     fn description(&self) -> &str {
-        match self.kind {
-            ErrorKind::OffTapeStart => "Tried to move past tape beginning",
-            ErrorKind::OffTapeEnd(_) => "Tried to move past end of tape",
-            ErrorKind::IOError => "I/O failure",
-        }
+        self.kind.message()
     }
 }
 
@@ -62,7 +101,11 @@ pub struct IrBuilder<'a> {
     builder: inkwell::builder::Builder<'a>,
     tape_ptr: inkwell::values::PointerValue<'a>,
     active_cell_ptr: inkwell::values::PointerValue<'a>,
+    getchar: inkwell::values::FunctionValue<'a>,
     tape_len: u64,
+    /// Function pointers of the defined procedures, by number.
+    procedures: Option<inkwell::values::GlobalValue<'a>>,
+    proc_count: usize,
 }
 
 impl<'a> IrBuilder<'a> {
@@ -87,7 +130,7 @@ impl<'a> IrBuilder<'a> {
         ], false);
 
         module.add_function("putchar", putchar_type, None);
-        module.add_function("getchar", getchar_type, None);
+        let getchar = module.add_function("getchar", getchar_type, None);
         let memset = module.add_function("llvm.memset.p0i8.i32", memset_type, None);
 
         let function = module.add_function("main", main_type, None);
@@ -110,16 +153,63 @@ impl<'a> IrBuilder<'a> {
             builder,
             tape_ptr,
             active_cell_ptr,
+            getchar,
             tape_len: tape_len as u64,
+            procedures: None,
+            proc_count: 0,
         }
     }
 
+    /// Read `,` from `input` instead of stdin. Once it is exhausted, reads
+    /// behave like `getchar` at end of file.
+    pub fn set_input(&mut self, input: &[u8]) {
+        let i8_type = self.context.i8_type();
+        let i32_type = self.context.i32_type();
+        let i32_zero = i32_type.const_int(0, false);
+        let len = i32_type.const_int(input.len() as u64, false);
+
+        let data: Vec<_> = input.iter().map(|&b| i8_type.const_int(b as u64, false)).collect();
+        let data_global = self.module.add_global(i8_type.array_type(input.len() as u32), None, "input");
+        data_global.set_initializer(&i8_type.const_array(&data));
+        data_global.set_constant(true);
+        let pos_global = self.module.add_global(i32_type, None, "input_pos");
+        pos_global.set_initializer(&i32_zero);
+
+        let function = self.module.add_function("sateko_input_getchar", i32_type.fn_type(&[], false), None);
+        let entry_block = self.context.append_basic_block(function, "entry");
+        let more_block = self.context.append_basic_block(function, "more");
+        let eof_block = self.context.append_basic_block(function, "eof");
+        let main_block = self.builder.get_insert_block().unwrap();
+
+        self.builder.position_at_end(entry_block);
+        let pos = self.builder.build_load(pos_global.as_pointer_value(), "").into_int_value();
+        let more = self.builder.build_int_compare(inkwell::IntPredicate::ULT, pos, len, "");
+        self.builder.build_conditional_branch(more, more_block, eof_block);
+
+        self.builder.position_at_end(more_block);
+        let byte_ptr = unsafe { self.builder.build_gep(data_global.as_pointer_value(), &[i32_zero, pos], "") };
+        let byte = self.builder.build_load(byte_ptr, "").into_int_value();
+        let next_pos = self.builder.build_int_add(pos, i32_type.const_int(1, false), "");
+        self.builder.build_store(pos_global.as_pointer_value(), next_pos);
+        self.builder.build_return(Some(&self.builder.build_int_z_extend(byte, i32_type, "")));
+
+        self.builder.position_at_end(eof_block);
+        self.builder.build_return(Some(&i32_type.const_int(-1i64 as u64, true)));
+
+        self.builder.position_at_end(main_block);
+        self.getchar = function;
+    }
+
     pub fn build_from_ast(&mut self, ast: &AST) {
         let i32_type = self.context.i32_type();
         let exit_code = i32_type.const_int(0, false);
 
+        if ast.uses_procedures() {
+            self.build_procedure_table();
+        }
+
         for op in &ast.0 {
-            self.build_op(&op);
+            self.build_op(op);
         }
 
         self.builder.build_return(Some(&exit_code));
@@ -128,12 +218,16 @@ impl<'a> IrBuilder<'a> {
     fn build_op(&mut self, op: &ASTNode) -> Option<BasicBlock<'a>> {
         match op.kind {
             ASTNodeKind::Loop => return Some(self.exec_loop(op)),
+            ASTNodeKind::Procedure => self.define_procedure(op),
+            ASTNodeKind::Call => return Some(self.call_procedure(op)),
             ASTNodeKind::IncTape => self.inc_tape(op),
             ASTNodeKind::DecTape => self.dec_tape(op),
             ASTNodeKind::IncVal => self.inc_val(op),
             ASTNodeKind::DecVal => self.dec_val(op),
             ASTNodeKind::Read => self.read(op),
             ASTNodeKind::Write => self.write(op),
+            ASTNodeKind::Debug => self.debug(op),
+            ASTNodeKind::Comment => {}
         };
 
         None
@@ -141,7 +235,7 @@ impl<'a> IrBuilder<'a> {
 
     fn exec_loop(&mut self, op: &ASTNode) -> BasicBlock<'a> {
         let i8_type = self.context.i8_type();
-        let function = self.module.get_function("main").unwrap();
+        let function = self.builder.get_insert_block().unwrap().get_parent().unwrap();
 
         let loop_intro_block = self.context.append_basic_block(function, "loop_intro");
         let mut loop_body_block = self.context.append_basic_block(function, "loop_body");
@@ -174,6 +268,133 @@ impl<'a> IrBuilder<'a> {
         loop_out
     }
 
+    /// Type of the procedure functions, which take the tape and the position.
+    fn procedure_type(&self) -> inkwell::types::FunctionType<'a> {
+        let i8_ptr_type = self.context.i8_type().ptr_type(inkwell::AddressSpace::Generic);
+        let i32_ptr_type = self.context.i32_type().ptr_type(inkwell::AddressSpace::Generic);
+        self.context.void_type().fn_type(&[
+            BasicTypeEnum::PointerType(i8_ptr_type),
+            BasicTypeEnum::PointerType(i32_ptr_type),
+        ], false)
+    }
+
+    /// Add the table of procedures, which starts out empty, and
+    /// `sateko_undefined_procedure(line, col)`, which reports a call to an
+    /// empty entry and exits.
+    fn build_procedure_table(&mut self) {
+        let void_type = self.context.void_type();
+        let i32_type = self.context.i32_type();
+        let proc_ptr_type = self.procedure_type().ptr_type(inkwell::AddressSpace::Generic);
+        let table_type = proc_ptr_type.array_type(PROCEDURES as u32);
+
+        let table = self.module.add_global(table_type, None, "procedures");
+        table.set_initializer(&table_type.const_zero());
+        self.procedures = Some(table);
+
+        let exit_type = void_type.fn_type(&[BasicTypeEnum::IntType(i32_type)], false);
+        let exit = self.module.add_function("exit", exit_type, None);
+        let dprintf = declare_dprintf(self.context, &self.module);
+        let undefined_type = void_type.fn_type(&[
+            BasicTypeEnum::IntType(i32_type),
+            BasicTypeEnum::IntType(i32_type),
+        ], false);
+        let function = self.module.add_function("sateko_undefined_procedure", undefined_type, None);
+        let entry_block = self.context.append_basic_block(function, "entry");
+        let main_block = self.builder.get_insert_block().unwrap();
+
+        self.builder.position_at_end(entry_block);
+        let message = self.builder.build_global_string_ptr(
+            "Runtime error: Called an undefined procedure (%d:%d)\n",
+            "undefined_procedure",
+        );
+        self.builder.build_call(dprintf, &[
+            BasicValueEnum::IntValue(i32_type.const_int(2, false)),
+            BasicValueEnum::PointerValue(message.as_pointer_value()),
+            function.get_nth_param(0).unwrap(),
+            function.get_nth_param(1).unwrap(),
+        ], "");
+        self.builder.build_call(exit, &[BasicValueEnum::IntValue(i32_type.const_int(1, false))], "");
+        self.builder.build_unreachable();
+
+        self.builder.position_at_end(main_block);
+    }
+
+    /// Build the body of `op` as a function of its own and store it in the
+    /// table under the active cell.
+    fn define_procedure(&mut self, op: &ASTNode) {
+        let i32_type = self.context.i32_type();
+        let function = self.module.add_function(
+            &format!("sateko_proc_{}", self.proc_count),
+            self.procedure_type(),
+            None,
+        );
+        self.proc_count += 1;
+
+        let outer_block = self.builder.get_insert_block().unwrap();
+        let outer_tape_ptr = self.tape_ptr;
+        let outer_active_cell_ptr = self.active_cell_ptr;
+        self.tape_ptr = function.get_nth_param(0).unwrap().into_pointer_value();
+        self.active_cell_ptr = function.get_nth_param(1).unwrap().into_pointer_value();
+
+        let mut body_block = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(body_block);
+        for op in op.ops.as_ref().unwrap() {
+            body_block = self.build_op(op).unwrap_or(body_block);
+        }
+        self.builder.position_at_end(body_block);
+        self.builder.build_return(None);
+
+        self.tape_ptr = outer_tape_ptr;
+        self.active_cell_ptr = outer_active_cell_ptr;
+        self.builder.position_at_end(outer_block);
+
+        let entry_ptr = self.procedure_entry();
+        let function_ptr = function.as_global_value().as_pointer_value();
+        self.builder.build_store(entry_ptr, function_ptr);
+    }
+
+    /// Call the procedure in the table under the active cell, reporting an
+    /// error if there is none.
+    fn call_procedure(&mut self, op: &ASTNode) -> BasicBlock<'a> {
+        let i32_type = self.context.i32_type();
+        let function = self.builder.get_insert_block().unwrap().get_parent().unwrap();
+        let undefined_block = self.context.append_basic_block(function, "undefined_procedure");
+        let call_block = self.context.append_basic_block(function, "call");
+
+        let entry_ptr = self.procedure_entry();
+        let proc_ptr = self.builder.build_load(entry_ptr, "").into_pointer_value();
+        let undefined = self.builder.build_is_null(proc_ptr, "");
+        self.builder.build_conditional_branch(undefined, undefined_block, call_block);
+
+        self.builder.position_at_end(undefined_block);
+        let report = self.module.get_function("sateko_undefined_procedure").unwrap();
+        self.builder.build_call(report, &[
+            BasicValueEnum::IntValue(i32_type.const_int(op.pos.line as u64, false)),
+            BasicValueEnum::IntValue(i32_type.const_int(op.pos.pos as u64, false)),
+        ], "");
+        self.builder.build_unreachable();
+
+        self.builder.position_at_end(call_block);
+        let callee = inkwell::values::CallableValue::try_from(proc_ptr).unwrap();
+        self.builder.build_call(callee, &[
+            BasicValueEnum::PointerValue(self.tape_ptr),
+            BasicValueEnum::PointerValue(self.active_cell_ptr),
+        ], "");
+        call_block
+    }
+
+    /// Pointer to the procedure table entry numbered by the active cell.
+    fn procedure_entry(&self) -> inkwell::values::PointerValue<'a> {
+        let i32_type = self.context.i32_type();
+        let table = self.procedures.unwrap().as_pointer_value();
+
+        let active_cell_val = self.builder.build_load(self.active_cell_ptr, "").into_int_value();
+        let cell_ptr = unsafe { self.builder.build_gep(self.tape_ptr, &[active_cell_val], "") };
+        let cur_val = self.builder.build_load(cell_ptr, "").into_int_value();
+        let idx = self.builder.build_int_z_extend(cur_val, i32_type, "");
+        unsafe { self.builder.build_gep(table, &[i32_type.const_int(0, false), idx], "") }
+    }
+
     fn inc_tape(&self, op: &ASTNode) {
         let i32_type = self.context.i32_type();
 
@@ -218,7 +439,7 @@ impl<'a> IrBuilder<'a> {
     fn read(&self, op: &ASTNode) {
         let i8_type = self.context.i8_type();
         let i32_type = self.context.i32_type();
-        let getchar = self.module.get_function("getchar").unwrap();
+        let getchar = self.getchar;
 
         let i32_one = i32_type.const_int(1, true);
         let active_cell_val = self.builder.build_load(self.active_cell_ptr, "").into_int_value();
@@ -240,6 +461,25 @@ impl<'a> IrBuilder<'a> {
         self.builder.build_call(putchar, &[i32_cur_val], "");
     }
 
+    fn debug(&self, op: &ASTNode) {
+        let i32_type = self.context.i32_type();
+        // only programs which use `#` get the function
+        let debug = self
+            .module
+            .get_function("sateko_debug")
+            .unwrap_or_else(|| build_debug_fn(self.context, &self.module, self.tape_len as u32));
+
+        let active_cell_val = self.builder.build_load(self.active_cell_ptr, "");
+        let file = self.builder.build_global_string_ptr(&op.pos.file_prefix(), "debug_file");
+        self.builder.build_call(debug, &[
+            BasicValueEnum::PointerValue(self.tape_ptr),
+            active_cell_val,
+            BasicValueEnum::PointerValue(file.as_pointer_value()),
+            BasicValueEnum::IntValue(i32_type.const_int(op.pos.line as u64, false)),
+            BasicValueEnum::IntValue(i32_type.const_int(op.pos.pos as u64, false)),
+        ], "");
+    }
+
     pub fn get_module(&self) -> &Module<'a> {
         &self.module
     }
@@ -247,21 +487,367 @@ impl<'a> IrBuilder<'a> {
 }
 
 
-fn exec_loop(op: &ASTNode, tape: &mut Tape, verb: u8) -> Result {
+/// Interpret `ast` against `tape`, reading `,` from `input` and writing `.` to
+/// `output`. End of input stores 255 in the cell, matching the truncated
+/// `getchar` result in compiled programs.
+pub fn run(
+    ast: &AST,
+    tape: &mut Tape,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+    verb: u8,
+) -> Result {
+    let mut io = Io {
+        input,
+        output,
+        procs: vec![None; PROCEDURES],
+        depth: 0,
+    };
+    exec_ops(&ast.0, tape, &mut io, verb)
+}
+
+/// State shared by every op other than the tape.
+struct Io<'a, 'p> {
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    /// Bodies of the defined procedures, by number.
+    procs: Vec<Option<&'p [ASTNode]>>,
+    depth: usize,
+}
+
+fn exec_ops<'p>(ops: &'p [ASTNode], tape: &mut Tape, io: &mut Io<'_, 'p>, verb: u8) -> Result {
+    for op in ops {
+        exec_op(op, tape, io, verb)?;
+    }
+    Ok(())
+}
+
+fn exec_op<'p>(op: &'p ASTNode, tape: &mut Tape, io: &mut Io<'_, 'p>, verb: u8) -> Result {
+    let err = |kind| RuntimeError {
+        kind,
+        pos: op.pos.clone(),
+    };
+
+    match op.kind {
+        ASTNodeKind::Loop => exec_loop(op, tape, io, verb)?,
+        ASTNodeKind::Procedure => io.procs[tape.cells[tape.pos] as usize] = op.ops.as_deref(),
+        ASTNodeKind::Call => {
+            let num = tape.cells[tape.pos];
+            let body = io.procs[num as usize].ok_or_else(|| err(ErrorKind::UndefinedProcedure(num)))?;
+            if io.depth == MAX_CALL_DEPTH {
+                return Err(err(ErrorKind::CallDepth));
+            }
+            io.depth += 1;
+            exec_ops(body, tape, io, verb)?;
+            io.depth -= 1;
+        }
+        ASTNodeKind::IncTape => {
+            if tape.pos + 1 >= tape.cells.len() {
+                return Err(err(ErrorKind::OffTapeEnd(tape.cells.len())));
+            }
+            tape.pos += 1;
+        }
+        ASTNodeKind::DecTape => {
+            if tape.pos == 0 {
+                return Err(err(ErrorKind::OffTapeStart));
+            }
+            tape.pos -= 1;
+        }
+        ASTNodeKind::IncVal => tape.cells[tape.pos] = tape.cells[tape.pos].wrapping_add(1),
+        ASTNodeKind::DecVal => tape.cells[tape.pos] = tape.cells[tape.pos].wrapping_sub(1),
+        ASTNodeKind::Read => {
+            let mut buf = [0u8];
+            tape.cells[tape.pos] = match io.input.read(&mut buf) {
+                Ok(0) => 255,
+                Ok(_) => buf[0],
+                Err(_) => return Err(err(ErrorKind::IOError)),
+            };
+        }
+        ASTNodeKind::Write => {
+            if io.output.write_all(&[tape.cells[tape.pos]]).is_err() {
+                return Err(err(ErrorKind::IOError));
+            }
+        }
+        ASTNodeKind::Debug => {
+            // flush so the dump lines up with program output on a terminal
+            let _ = io.output.flush();
+            eprintln!("[{}{},{}] {}", op.pos.file_prefix(), op.pos.line, op.pos.pos, tape.dump());
+        }
+        ASTNodeKind::Comment => {}
+    }
+
+    Ok(())
+}
+/// `int dprintf(int fd, const char *format, ...)`, declared on first use.
+fn declare_dprintf<'a>(context: &'a Context, module: &Module<'a>) -> inkwell::values::FunctionValue<'a> {
+    if let Some(dprintf) = module.get_function("dprintf") {
+        return dprintf;
+    }
+    let i32_type = context.i32_type();
+    let i8_ptr_type = context.i8_type().ptr_type(inkwell::AddressSpace::Generic);
+    let dprintf_type = i32_type.fn_type(&[
+        BasicTypeEnum::IntType(i32_type),
+        BasicTypeEnum::PointerType(i8_ptr_type),
+    ], true);
+    module.add_function("dprintf", dprintf_type, None)
+}
+
+/// Emit `sateko_debug(tape, pos, file, line, col)`, which flushes stdio and
+/// prints the same tape dump as `Tape::dump` to stderr using `dprintf`.
+fn build_debug_fn<'a>(context: &'a Context, module: &Module<'a>, tape_len: u32) -> inkwell::values::FunctionValue<'a> {
+    let builder = context.create_builder();
+    let void_type = context.void_type();
+    let i8_type = context.i8_type();
+    let i32_type = context.i32_type();
+    let i8_ptr_type = i8_type.ptr_type(inkwell::AddressSpace::Generic);
+    let debug_type = void_type.fn_type(&[
+        BasicTypeEnum::PointerType(i8_ptr_type),
+        BasicTypeEnum::IntType(i32_type),
+        BasicTypeEnum::PointerType(i8_ptr_type),
+        BasicTypeEnum::IntType(i32_type),
+        BasicTypeEnum::IntType(i32_type),
+    ], false);
+
+    let fflush_type = i32_type.fn_type(&[BasicTypeEnum::PointerType(i8_ptr_type)], false);
+
+    let dprintf = declare_dprintf(context, module);
+    let fflush = module.add_function("fflush", fflush_type, None);
+    let function = module.add_function("sateko_debug", debug_type, None);
+    let entry_block = context.append_basic_block(function, "entry");
+    let check_block = context.append_basic_block(function, "check");
+    let cell_block = context.append_basic_block(function, "cell");
+    let done_block = context.append_basic_block(function, "done");
+
+    let tape_ptr = function.get_nth_param(0).unwrap().into_pointer_value();
+    let pos = function.get_nth_param(1).unwrap().into_int_value();
+    let file = function.get_nth_param(2).unwrap();
+    let line = function.get_nth_param(3).unwrap();
+    let col = function.get_nth_param(4).unwrap();
+    let stderr = BasicValueEnum::IntValue(i32_type.const_int(2, false));
+
+    // dprintf bypasses stdio, so anything putchar buffered must go first
+    builder.position_at_end(entry_block);
+    builder.build_call(fflush, &[BasicValueEnum::PointerValue(i8_ptr_type.const_null())], "");
+
+    // start = min(max(pos - DEBUG_WINDOW / 2, 0), tape_len - window)
+    let window = DEBUG_WINDOW.min(tape_len as usize) as u64;
+    let half = i32_type.const_int(DEBUG_WINDOW as u64 / 2, false);
+    let max_start = i32_type.const_int(tape_len as u64 - window, false);
+    let below_half = builder.build_int_compare(inkwell::IntPredicate::ULT, pos, half, "");
+    let start = builder.build_select(
+        below_half,
+        i32_type.const_int(0, false),
+        builder.build_int_sub(pos, half, ""),
+        "",
+    ).into_int_value();
+    let past_max = builder.build_int_compare(inkwell::IntPredicate::UGT, start, max_start, "");
+    let start = builder.build_select(past_max, max_start, start, "").into_int_value();
+    let end = builder.build_int_add(start, i32_type.const_int(window, false), "");
+
+    let header = builder.build_global_string_ptr("[%s%d,%d] ptr %d:", "debug_header");
+    let cell_fmt = builder.build_global_string_ptr(" %u", "debug_cell");
+    let active_fmt = builder.build_global_string_ptr(" [%u]", "debug_active");
+    let newline = builder.build_global_string_ptr("\n", "debug_newline");
+    builder.build_call(dprintf, &[
+        stderr,
+        BasicValueEnum::PointerValue(header.as_pointer_value()),
+        file,
+        line,
+        col,
+        BasicValueEnum::IntValue(pos),
+    ], "");
+    builder.build_unconditional_branch(check_block);
+
+    // for i in start..end
+    builder.position_at_end(check_block);
+    let i = builder.build_phi(i32_type, "i");
+    let in_window = builder.build_int_compare(
+        inkwell::IntPredicate::ULT,
+        i.as_basic_value().into_int_value(),
+        end,
+        "",
+    );
+    builder.build_conditional_branch(in_window, cell_block, done_block);
+
+    builder.position_at_end(cell_block);
+    let idx = i.as_basic_value().into_int_value();
+    let cell_ptr = unsafe { builder.build_gep(tape_ptr, &[idx], "") };
+    let cell_val = builder.build_load(cell_ptr, "").into_int_value();
+    let cell_val = builder.build_int_z_extend(cell_val, i32_type, "");
+    let is_active = builder.build_int_compare(inkwell::IntPredicate::EQ, idx, pos, "");
+    let fmt = builder.build_select(
+        is_active,
+        active_fmt.as_pointer_value(),
+        cell_fmt.as_pointer_value(),
+        "",
+    );
+    builder.build_call(dprintf, &[stderr, fmt, BasicValueEnum::IntValue(cell_val)], "");
+    let next = builder.build_int_add(idx, i32_type.const_int(1, false), "");
+    builder.build_unconditional_branch(check_block);
+    i.add_incoming(&[(&start, entry_block), (&next, cell_block)]);
+
+    builder.position_at_end(done_block);
+    builder.build_call(dprintf, &[
+        stderr,
+        BasicValueEnum::PointerValue(newline.as_pointer_value()),
+    ], "");
+    builder.build_return(None);
+    function
+}
+
+fn exec_loop<'p>(op: &'p ASTNode, tape: &mut Tape, io: &mut Io<'_, 'p>, verb: u8) -> Result {
     while tape.cells[tape.pos] != 0 {
         if verb > 0 {
             eprintln!(
-                "[{},{}] loop check cell {}: {}",
-                op.pos.line, op.pos.pos, tape.pos, tape.cells[tape.pos]
+                "[{}{},{}] loop check cell {}: {}",
+                op.pos.file_prefix(), op.pos.line, op.pos.pos, tape.pos, tape.cells[tape.pos]
             );
         }
-        //exec_ops(op.ops.as_ref().unwrap(), tape, verb)?;
+        exec_ops(op.ops.as_ref().unwrap(), tape, io, verb)?;
     }
     if verb > 0 {
         eprintln!(
-            "[{},{}] loop end cell {}",
-            op.pos.line, op.pos.pos, tape.pos
+            "[{}{},{}] loop end cell {}",
+            op.pos.file_prefix(), op.pos.line, op.pos.pos, tape.pos
         );
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{ErrorKind, InputPosition, IrBuilder, RuntimeError, Tape};
+    use crate::ast::AST;
+    use crate::token::{tokenize, tokenize_with, Extensions};
+    use inkwell::context::Context;
+
+    fn run(raw: &str, tape_len: usize, input: &[u8]) -> (Result<(), RuntimeError>, Vec<u8>) {
+        let ast = AST::from_tokens(&tokenize(raw)).unwrap();
+        let mut tape = Tape::with_size(tape_len);
+        let mut output = vec![];
+        let res = super::run(&ast, &mut tape, &mut &input[..], &mut output, 0);
+        (res, output)
+    }
+
+    #[test]
+    fn write() {
+        let raw = "++++++++[>++++++++<-]>+.+.";
+        assert_eq!(run(raw, 2, b""), (Ok(()), b"AB".to_vec()));
+    }
+
+    #[test]
+    fn read() {
+        assert_eq!(run(",+.,+.", 1, b"a"), (Ok(()), b"b\0".to_vec()));
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(run("-.+.", 1, b""), (Ok(()), vec![255, 0]));
+    }
+
+    #[test]
+    fn off_tape_start() {
+        let expect = Err(RuntimeError {
+            kind: ErrorKind::OffTapeStart,
+            pos: InputPosition { file: None, line: 1, pos: 3, expansion: None },
+        });
+        assert_eq!(run("><<", 2, b""), (expect, vec![]));
+    }
+
+    #[test]
+    fn off_tape_end() {
+        let expect = Err(RuntimeError {
+            kind: ErrorKind::OffTapeEnd(2),
+            pos: InputPosition { file: None, line: 1, pos: 2, expansion: None },
+        });
+        assert_eq!(run(">>", 2, b""), (expect, vec![]));
+    }
+
+    fn run_procedures(raw: &str, tape_len: usize) -> (Result<(), RuntimeError>, Vec<u8>) {
+        let ext = Extensions {
+            procedures: true,
+            ..Extensions::default()
+        };
+        let ast = AST::from_tokens(&tokenize_with(raw, &ext)).unwrap();
+        let mut tape = Tape::with_size(tape_len);
+        let mut output = vec![];
+        let res = super::run(&ast, &mut tape, &mut &b""[..], &mut output, 0);
+        (res, output)
+    }
+
+    #[test]
+    fn procedures() {
+        // procedure 0 prints the next cell, procedure 1 adds 3 to it
+        let raw = "(>.<)+(>+++<)-:+::-:";
+        assert_eq!(run_procedures(raw, 2), (Ok(()), vec![0, 6]));
+
+        // redefinition replaces the earlier body
+        assert_eq!(run_procedures("(+)(++):.", 1), (Ok(()), vec![2]));
+
+        // procedure 0 counts the next cell down by calling itself
+        let raw = "(>.-[<:>]<)>+++<:";
+        assert_eq!(run_procedures(raw, 2), (Ok(()), vec![3, 2, 1]));
+    }
+
+    #[test]
+    fn undefined_procedure() {
+        let expect = Err(RuntimeError {
+            kind: ErrorKind::UndefinedProcedure(1),
+            pos: InputPosition { file: None, line: 1, pos: 5, expansion: None },
+        });
+        assert_eq!(run_procedures("(.)+:", 1), (expect, vec![]));
+    }
+
+    #[test]
+    fn call_depth() {
+        let expect = Err(RuntimeError {
+            kind: ErrorKind::CallDepth,
+            pos: InputPosition { file: None, line: 1, pos: 2, expansion: None },
+        });
+        assert_eq!(run_procedures("(:):", 1), (expect, vec![]));
+    }
+
+    #[test]
+    fn llvm_procedures() {
+        let ext = Extensions {
+            procedures: true,
+            ..Extensions::default()
+        };
+        let ast = AST::from_tokens(&tokenize_with("(>+[-<:>]<)+(.):", &ext)).unwrap();
+        let context = Context::create();
+        let mut builder = IrBuilder::create(&context, 8);
+        builder.build_from_ast(&ast);
+        let module = builder.get_module();
+        assert!(module.verify().is_ok(), "{}", module.print_to_string().to_string());
+        assert!(module.get_function("sateko_proc_1").is_some());
+    }
+
+    #[test]
+    fn llvm_debug_only_when_used() {
+        let ext = Extensions {
+            debug_hash: true,
+            ..Extensions::default()
+        };
+        for (raw, used) in &[("+.", false), ("+#>#", true)] {
+            let ast = AST::from_tokens(&tokenize_with(raw, &ext)).unwrap();
+            let context = Context::create();
+            let mut builder = IrBuilder::create(&context, 8);
+            builder.build_from_ast(&ast);
+            let module = builder.get_module();
+            assert!(module.verify().is_ok(), "{}", module.print_to_string().to_string());
+            assert_eq!(module.get_function("sateko_debug").is_some(), *used);
+        }
+    }
+
+    #[test]
+    fn dump() {
+        let mut tape = Tape::with_size(4);
+        tape.cells[1] = 72;
+        tape.pos = 1;
+        assert_eq!(tape.dump(), "ptr 1: 0 [72] 0 0");
+
+        let mut tape = Tape::with_size(30);
+        tape.pos = 29;
+        assert_eq!(tape.dump(), "ptr 29: 0 0 0 0 0 0 0 0 0 [0]");
+    }
+}
@@ -0,0 +1,226 @@
+//! Pseudo-code listings of programs, for reading what a script does.
+//!
+//! Each line of the listing is annotated with the source range it came from.
+//! While the tape position is known, cells are shown by their absolute index;
+//! after a loop which moves the pointer by an unknown amount, they are shown
+//! relative to `p`.
+use crate::include::Sources;
+use crate::ir::{Node, NodeKind};
+use crate::token::InputPosition;
+
+struct Line {
+    range: String,
+    depth: usize,
+    text: String,
+}
+
+struct Explainer<'a> {
+    sources: &'a Sources,
+    lines: Vec<Line>,
+    /// The tape position, if it is known statically.
+    ptr: Option<isize>,
+}
+
+/// Explain `ir`, which was parsed from `sources`.
+pub fn explain(ir: &[Node], sources: &Sources) -> String {
+    let mut ex = Explainer {
+        sources,
+        lines: vec![],
+        ptr: Some(0),
+    };
+    ex.nodes(ir, 0);
+
+    let width = ex.lines.iter().map(|l| l.range.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for line in ex.lines {
+        let text = format!("{:w$}  {}{}", line.range, "    ".repeat(line.depth), line.text, w = width);
+        out.push_str(text.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+impl<'a> Explainer<'a> {
+    fn nodes(&mut self, ir: &[Node], depth: usize) {
+        for node in ir {
+            self.node(node, depth);
+        }
+    }
+
+    fn node(&mut self, node: &Node, depth: usize) {
+        let text = match &node.kind {
+            NodeKind::Add(n) if *n > 128 => format!("{} -= {}", self.cell(0), n.wrapping_neg()),
+            NodeKind::Add(n) => format!("{} += {}", self.cell(0), n),
+            NodeKind::Move(n) => match &mut self.ptr {
+                Some(p) => {
+                    *p += n;
+                    format!("p = {}", p)
+                }
+                None if *n < 0 => format!("p -= {}", -n),
+                None => format!("p += {}", n),
+            },
+            NodeKind::Clear => format!("{} = 0", self.cell(0)),
+            NodeKind::Transfer(targets) => {
+                let mut text = vec![];
+                for &(offset, factor) in targets {
+                    let (op, factor) = if factor > 128 {
+                        ("-=", factor.wrapping_neg())
+                    } else {
+                        ("+=", factor)
+                    };
+                    if factor == 1 {
+                        text.push(format!("{} {} {}", self.cell(offset), op, self.cell(0)));
+                    } else {
+                        text.push(format!("{} {} {} * {}", self.cell(offset), op, self.cell(0), factor));
+                    }
+                }
+                text.push(format!("{} = 0", self.cell(0)));
+                text.join("; ")
+            }
+            NodeKind::Read => format!("{} = read()", self.cell(0)),
+            NodeKind::Write => format!("print {}", self.cell(0)),
+            NodeKind::Debug => "dump tape".to_string(),
+            NodeKind::Loop(body) => {
+                if !balanced(body) {
+                    self.ptr = None;
+                }
+                self.push(node, depth, format!("while {} != 0 {{", self.cell(0)));
+                self.nodes(body, depth + 1);
+                self.lines.push(Line {
+                    range: String::new(),
+                    depth,
+                    text: "}".to_string(),
+                });
+                return;
+            }
+        };
+        self.push(node, depth, text);
+    }
+
+    fn push(&mut self, node: &Node, depth: usize, text: String) {
+        // spans always end with a single byte command
+        let src = self.sources.text(&node.pos);
+        let end = node.pos.advance(&src[node.span.start..node.span.end - 1]);
+        self.lines.push(Line {
+            range: range(&node.pos, &end),
+            depth,
+            text,
+        });
+    }
+
+    fn cell(&self, offset: isize) -> String {
+        match self.ptr {
+            Some(p) => format!("cell[{}]", p + offset),
+            None if offset < 0 => format!("cell[p-{}]", -offset),
+            None if offset > 0 => format!("cell[p+{}]", offset),
+            None => "cell[p]".to_string(),
+        }
+    }
+}
+
+/// Whether a loop body always ends where it started.
+fn balanced(body: &[Node]) -> bool {
+    let mut offset = 0;
+    for node in body {
+        match &node.kind {
+            NodeKind::Move(n) => offset += n,
+            NodeKind::Loop(body) if !balanced(body) => return false,
+            _ => {}
+        }
+    }
+    offset == 0
+}
+
+fn range(start: &InputPosition, end: &InputPosition) -> String {
+    if start == end {
+        format!("{}{}:{}", start.file_prefix(), start.line, start.pos)
+    } else {
+        format!("{}{}:{}-{}:{}", start.file_prefix(), start.line, start.pos, end.line, end.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ast::AST;
+    use crate::ir;
+    use crate::dialect::Brainfuck;
+    use crate::include;
+    use crate::token::Extensions;
+
+    fn explain_with(raw: &str, ext: &Extensions) -> String {
+        let lexer = Brainfuck(ext.clone());
+        let (tokens, sources) = include::tokenize("", raw.as_bytes(), ext, &lexer, &[]).unwrap();
+        let ast = AST::from_tokens(&tokens).unwrap();
+        super::explain(&ir::from_ast(&ast), &sources)
+    }
+
+    fn explain(raw: &str) -> String {
+        explain_with(raw, &Extensions::default())
+    }
+
+    #[test]
+    fn known_pointer() {
+        assert_eq!(
+            explain("++++ [>++<-] >.\n>,[-]"),
+            "1:1-1:4   cell[0] += 4
+1:6-1:12  cell[1] += cell[0] * 2; cell[0] = 0
+1:14      p = 1
+1:15      print cell[1]
+2:1       p = 2
+2:2       cell[2] = read()
+2:3-2:5   cell[2] = 0
+"
+        );
+    }
+
+    #[test]
+    fn nested_loops() {
+        assert_eq!(
+            explain("+[>+[-.]<-]"),
+            "1:1       cell[0] += 1
+1:2-1:11  while cell[0] != 0 {
+1:3           p = 1
+1:4           cell[1] += 1
+1:5-1:8       while cell[1] != 0 {
+1:6               cell[1] -= 1
+1:7               print cell[1]
+              }
+1:9           p = 0
+1:10          cell[0] -= 1
+          }
+"
+        );
+    }
+
+    #[test]
+    fn unknown_pointer() {
+        assert_eq!(
+            explain("+>+[<]\n>>[->+<<<+>>]"),
+            "1:1       cell[0] += 1
+1:2       p = 1
+1:3       cell[1] += 1
+1:4-1:6   while cell[p] != 0 {
+1:5           p -= 1
+          }
+2:1-2:2   p += 2
+2:3-2:13  cell[p+1] += cell[p]; cell[p-2] += cell[p]; cell[p] = 0
+"
+        );
+    }
+
+    #[test]
+    fn macro_calls() {
+        let ext = Extensions {
+            macros: true,
+            ..Extensions::default()
+        };
+        // the call's commands aren't folded into those around it
+        assert_eq!(
+            explain_with("#define a +\n+@a.", &ext),
+            "2:1   cell[0] += 1
+1:11  cell[0] += 1
+2:4   print cell[0]
+"
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Canonical layout of brainfuck source.
+//!
+//! Runs of commands are joined and wrapped at the line width, with each loop
+//! body indented one level. Loops with no comments or loops inside them stay
+//! on one line, like `[-]`. A comment on the same line as commands is aligned
+//! at the comment column, as are the lines after it which are indented at
+//! least as far, while other comment lines are indented with the code around
+//! them. Blank lines are kept, but never more than one in a row.
+//!
+//! Loops at the start of a program never run, so they are often used for
+//! comments which contain command characters. Those are copied as they are.
+use crate::ast::{ASTNode, ASTNodeKind, AST};
+
+const INDENT: &str = "    ";
+
+pub struct Options {
+    /// Longest line for runs of commands, including indentation.
+    pub width: usize,
+    /// Column comments after commands start in, counting from 0.
+    pub comment_column: usize,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            width: 80,
+            comment_column: 24,
+        }
+    }
+}
+
+/// Lay out `ast`, parsed with comments from `text`.
+pub fn format(ast: &AST, text: &str, opts: &Options) -> String {
+    let mut f = Formatter {
+        text,
+        opts,
+        out: String::new(),
+        line: Line::default(),
+        last_end: 0,
+        trailing: None,
+        started: false,
+    };
+    f.nodes(&ast.0, 0);
+    let rest = &text[f.last_end..];
+    f.between(0, rest);
+    f.flush(None);
+
+    let mut out = f.out.trim_end().to_string();
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+/// A line of commands being built.
+#[derive(Default)]
+struct Line {
+    depth: usize,
+    code: String,
+    /// The line holds a bracket or a copied loop, and takes no more commands.
+    closed: bool,
+}
+
+struct Formatter<'a> {
+    text: &'a str,
+    opts: &'a Options,
+    out: String,
+    line: Line,
+    /// End of the last command or bracket written.
+    last_end: usize,
+    /// Source and output columns of the comment after the last line of
+    /// commands, while the lines after it may continue it.
+    trailing: Option<(usize, usize)>,
+    /// Whether a command has been seen, so loops are no longer at the start.
+    started: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn nodes(&mut self, nodes: &[ASTNode], depth: usize) {
+        let text = self.text;
+        for node in nodes {
+            if node.kind == ASTNodeKind::Comment {
+                continue;
+            }
+            self.between(depth, &text[self.last_end..node.span.start]);
+
+            match node.kind {
+                ASTNodeKind::Loop if !self.started && depth == 0 => {
+                    self.flush(None);
+                    self.line = Line {
+                        depth,
+                        code: text[node.span.start..node.span.end].to_string(),
+                        closed: true,
+                    };
+                    self.last_end = node.span.end;
+                    continue;
+                }
+                ASTNodeKind::Loop | ASTNodeKind::Procedure => self.block(node, depth),
+                _ => {
+                    self.atom(depth, &command(&node.kind).to_string());
+                    self.last_end = node.span.end;
+                }
+            }
+            self.started = true;
+        }
+    }
+
+    /// Write a loop or procedure, on one line if it is short and simple.
+    fn block(&mut self, node: &ASTNode, depth: usize) {
+        let (open, close) = match node.kind {
+            ASTNodeKind::Loop => ("[", "]"),
+            _ => ("(", ")"),
+        };
+        let text = self.text;
+        let body = node.ops.as_ref().unwrap();
+
+        // spaces inside the brackets don't count as comments
+        let flat: Option<String> = body
+            .iter()
+            .filter(|op| {
+                op.kind != ASTNodeKind::Comment || !text[op.span.start..op.span.end].trim().is_empty()
+            })
+            .map(|op| match op.kind {
+                ASTNodeKind::Loop | ASTNodeKind::Procedure | ASTNodeKind::Comment => None,
+                ref kind => Some(command(kind)),
+            })
+            .collect();
+        if let Some(flat) = flat {
+            let flat = format!("{}{}{}", open, flat, close);
+            if INDENT.len() * depth + flat.len() <= self.opts.width {
+                self.atom(depth, &flat);
+                self.last_end = node.span.end;
+                return;
+            }
+        }
+
+        self.flush(None);
+        self.line = Line {
+            depth,
+            code: open.to_string(),
+            closed: true,
+        };
+        self.last_end = node.span.start + 1;
+        self.nodes(body, depth + 1);
+        self.between(depth + 1, &text[self.last_end..node.span.end - 1]);
+        self.flush(None);
+        self.line = Line {
+            depth,
+            code: close.to_string(),
+            closed: true,
+        };
+        self.last_end = node.span.end;
+    }
+
+    /// Add commands to the current line, starting a new one if they don't fit.
+    fn atom(&mut self, depth: usize, code: &str) {
+        let line = &self.line;
+        if line.closed || line.depth != depth || INDENT.len() * depth + line.code.len() + code.len() > self.opts.width {
+            self.flush(None);
+        }
+        self.line.depth = depth;
+        self.line.code.push_str(code);
+    }
+
+    /// Handle the text between two commands, which is only whitespace and
+    /// comments.
+    fn between(&mut self, depth: usize, text: &str) {
+        let start = self.last_end;
+        let segments: Vec<&str> = text.split('\n').collect();
+        for (i, segment) in segments.iter().enumerate() {
+            let blank = segment.trim().is_empty();
+            if i == 0 {
+                if blank {
+                    continue;
+                }
+                if self.line.code.is_empty() {
+                    self.comment_line(depth, segment);
+                } else {
+                    let indent = segment.chars().take_while(|c| c.is_whitespace()).count();
+                    let column = column(&self.text[..start]) + indent;
+                    self.flush(Some((segment.trim(), column)));
+                }
+            } else if !blank {
+                self.comment_line(depth, segment);
+            } else if i + 1 < segments.len() {
+                self.blank_line();
+            }
+        }
+    }
+
+    /// Write a line holding only a comment.
+    fn comment_line(&mut self, depth: usize, text: &str) {
+        self.flush(None);
+        let indent = text.chars().take_while(|c| c.is_whitespace()).count();
+        match self.trailing {
+            Some((source, column)) if indent >= source => {
+                self.out.push_str(&" ".repeat(column));
+            }
+            _ => {
+                self.out.push_str(&INDENT.repeat(depth));
+                self.trailing = None;
+            }
+        }
+        self.out.push_str(text.trim());
+        self.out.push('\n');
+    }
+
+    fn blank_line(&mut self) {
+        self.flush(None);
+        self.trailing = None;
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    /// Finish the current line, with `comment` after it, which started at
+    /// `column` in the source.
+    fn flush(&mut self, comment: Option<(&str, usize)>) {
+        let line = std::mem::take(&mut self.line);
+        if line.code.is_empty() {
+            return;
+        }
+        self.out.push_str(&INDENT.repeat(line.depth));
+        self.out.push_str(&line.code);
+        // copied loops are only at the top level, so their last line is
+        // never indented
+        let len = INDENT.len() * line.depth + column(&line.code);
+        match comment {
+            Some((comment, source)) => {
+                let column = self.opts.comment_column.max(len + 1);
+                self.out.push_str(&" ".repeat(column - len));
+                self.out.push_str(comment);
+                self.trailing = Some((source, column));
+            }
+            None => self.trailing = None,
+        }
+        self.out.push('\n');
+    }
+}
+
+/// The brainfuck character of a command.
+fn command(kind: &ASTNodeKind) -> char {
+    match kind {
+        ASTNodeKind::IncTape => '>',
+        ASTNodeKind::DecTape => '<',
+        ASTNodeKind::IncVal => '+',
+        ASTNodeKind::DecVal => '-',
+        ASTNodeKind::Read => ',',
+        ASTNodeKind::Write => '.',
+        ASTNodeKind::Debug => '#',
+        ASTNodeKind::Call => ':',
+        ASTNodeKind::Loop | ASTNodeKind::Procedure | ASTNodeKind::Comment => unreachable!(),
+    }
+}
+
+/// The column of the end of `text`, counting from 0.
+fn column(text: &str) -> usize {
+    text.rsplit('\n').next().unwrap().chars().count()
+}
+
+#[cfg(test)]
+mod test {
+    use super::Options;
+    use crate::ast::AST;
+    use crate::token::{tokenize, TokenKind};
+
+    fn format_with(text: &str, opts: &Options) -> String {
+        let ast = AST::from_tokens_with_comments(&tokenize(text)).unwrap();
+        let out = super::format(&ast, text, opts);
+
+        let commands = |text: &str| -> Vec<TokenKind> {
+            tokenize(text).into_iter().map(|t| t.kind).filter(|k| *k != TokenKind::Comment).collect()
+        };
+        assert_eq!(commands(&out), commands(text));
+        let again = AST::from_tokens_with_comments(&tokenize(&out)).unwrap();
+        assert_eq!(super::format(&again, &out, opts), out, "not idempotent");
+        out
+    }
+
+    fn format(text: &str) -> String {
+        format_with(text, &Options::default())
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(format(""), "");
+        assert_eq!(format("  +++\n\n\n\n---  \n"), "+++\n\n---\n");
+        assert_eq!(format("+[->+<]>[\n-\n]\n+[>[-]<-]"), "+[->+<]>[-]+\n[\n    >[-]<-\n]\n");
+        assert_eq!(format("+[- ]>[ -]"), "+[-]>[-]\n");
+        assert_eq!(
+            format_with("++++++++++[-]>>>>>", &Options { width: 8, comment_column: 24 }),
+            "++++++++\n++[-]>>>\n>>\n"
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let text = "prose
++++ add three
+[   loop
+  >+<- move it
+       along
+ middle text
+]
+. done
+";
+        assert_eq!(
+            format(text),
+            "prose
++++                     add three
+[                       loop
+    >+<-                move it
+                        along
+    middle text
+]
+.                       done
+"
+        );
+        assert_eq!(format_with("+ a\n-", &Options { width: 80, comment_column: 0 }), "+ a\n-\n");
+    }
+
+    #[test]
+    fn comment_loop() {
+        let text = "[ a comment, with commands. ]\n[ and another - ]\n+[ not - this one ]\n";
+        assert_eq!(
+            format(text),
+            "[ a comment, with commands. ]\n[ and another - ]\n+\n[                       not\n    -                   this one\n]\n"
+        );
+    }
+
+    #[test]
+    fn samples() {
+        let hello = include_str!("../bf/hello.bf");
+        // the comment on the first line of code is one column short
+        let fixed = hello.replacen("++++++++               Set", "++++++++                Set", 1);
+        assert_eq!(format(hello), fixed);
+        format(include_str!("../bf/rot13.bf"));
+    }
+}
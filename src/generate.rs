@@ -0,0 +1,235 @@
+//! Generate bf programs which print fixed text.
+//!
+//! Cell 0 is kept as a loop counter and the text is built in up to
+//! `MAX_CELLS` cells after it. The program starts with one multiplication
+//! loop which sets several cells close to values the text needs, like the
+//! prologue of a hand-written program. Each byte is then made from whichever
+//! cell can reach it in the fewest commands, counting the moves to get there,
+//! either by plain `+`/`-` or with another multiplication loop.
+const MAX_CELLS: usize = 8;
+const MAX_FACTOR: usize = 16;
+/// Only this much of the text is used to pick the starting cells.
+const SAMPLE_LEN: usize = 128;
+
+/// How to change a cell.
+#[derive(Clone, Copy)]
+enum Step {
+    Add(isize),
+    /// Add `times` to the cell `factor` times using the counter, then add `rest`.
+    Loop { factor: usize, times: isize, rest: isize },
+}
+
+/// A program which prints `text`.
+pub fn print_text(text: &[u8]) -> String {
+    let init = choose_init(&text[..text.len().min(SAMPLE_LEN)]);
+    let mut out = String::new();
+    print_with(text, &init, Some(&mut out));
+    out
+}
+
+/// Pick the counter and multipliers for the opening loop, adding cells while
+/// they make the program shorter.
+fn choose_init(text: &[u8]) -> (usize, Vec<usize>) {
+    let mut best = (0, vec![]);
+    let mut best_len = print_with(text, &best, None);
+
+    for factor in 4..=MAX_FACTOR {
+        let mut candidates: Vec<usize> = text.iter().map(|&b| (b as usize + factor / 2) / factor).collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates.retain(|&m| m > 0 && m * factor < 256);
+
+        let mut init = (factor, vec![]);
+        while init.1.len() < MAX_CELLS {
+            let next = candidates
+                .iter()
+                .map(|&m| {
+                    let mut next = init.clone();
+                    next.1.push(m);
+                    let len = print_with(text, &next, None);
+                    (len, next)
+                })
+                .min_by_key(|(len, _)| *len);
+            match next {
+                Some((len, next)) if init.1.is_empty() || len < print_with(text, &init, None) => {
+                    if len < best_len {
+                        best_len = len;
+                        best = next.clone();
+                    }
+                    init = next;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    best
+}
+
+/// The length of the program printing `text` after the opening loop `init`,
+/// writing the program to `out` when given.
+fn print_with(text: &[u8], init: &(usize, Vec<usize>), mut out: Option<&mut String>) -> usize {
+    let (factor, mults) = init;
+    // values of cells 1 and up
+    let mut cells: Vec<u8> = mults.iter().map(|m| (m * factor) as u8).collect();
+    let mut len = 0;
+    if !mults.is_empty() {
+        len += factor + 2 + mults.iter().sum::<usize>() + 2 * mults.len() + 1;
+        if let Some(out) = out.as_mut() {
+            out.push_str(&"+".repeat(*factor));
+            out.push('[');
+            for m in mults {
+                out.push('>');
+                out.push_str(&"+".repeat(*m));
+            }
+            out.push_str(&"<".repeat(mults.len()));
+            out.push_str("-]");
+        }
+    }
+    let mut pos = 0;
+
+    for &b in text {
+        let new_cell = if cells.len() < MAX_CELLS {
+            Some(cells.len() + 1)
+        } else {
+            None
+        };
+
+        let (cost, step, cell) = (1..=cells.len())
+            .chain(new_cell)
+            .map(|cell| {
+                let from = cells.get(cell - 1).copied().unwrap_or(0);
+                let (cost, step) = set_cell(pos, cell, from, b);
+                (cost, step, cell)
+            })
+            .min_by_key(|(cost, _, _)| *cost)
+            .unwrap();
+
+        len += cost + 1;
+        if let Some(out) = out.as_mut() {
+            emit(out, pos, cell, step);
+            out.push('.');
+        }
+        if cell > cells.len() {
+            cells.push(b);
+        } else {
+            cells[cell - 1] = b;
+        }
+        pos = cell;
+    }
+
+    len
+}
+
+/// The cheapest step found to move from `pos` to `cell` and change it from
+/// `from` to `to`, with its length in commands.
+fn set_cell(pos: usize, cell: usize, from: u8, to: u8) -> (usize, Step) {
+    // the nearest way round, -128..=127
+    let delta = to.wrapping_sub(from) as i8 as isize;
+    let mut best = (pos.abs_diff(cell) + delta.unsigned_abs(), Step::Add(delta));
+
+    let sign = delta.signum();
+    let size = delta.abs();
+    for factor in 2..=MAX_FACTOR {
+        let times = size / factor as isize;
+        // undershoot and add the rest, or overshoot and take it back
+        for &times in &[times, times + 1] {
+            if times < 2 {
+                continue;
+            }
+            let rest = size - factor as isize * times;
+            let cost = pos + factor + 2 + 3 * cell + times as usize + 1 + rest.unsigned_abs();
+            if cost < best.0 {
+                best = (
+                    cost,
+                    Step::Loop {
+                        factor,
+                        times: sign * times,
+                        rest: sign * rest,
+                    },
+                );
+            }
+        }
+    }
+
+    best
+}
+
+fn emit(out: &mut String, pos: usize, cell: usize, step: Step) {
+    match step {
+        Step::Add(delta) => {
+            moves(out, pos, cell);
+            adjust(out, delta);
+        }
+        Step::Loop { factor, times, rest } => {
+            moves(out, pos, 0);
+            out.push_str(&"+".repeat(factor));
+            out.push('[');
+            moves(out, 0, cell);
+            adjust(out, times);
+            moves(out, cell, 0);
+            out.push_str("-]");
+            moves(out, 0, cell);
+            adjust(out, rest);
+        }
+    }
+}
+
+fn moves(out: &mut String, from: usize, to: usize) {
+    if to > from {
+        out.push_str(&">".repeat(to - from));
+    } else {
+        out.push_str(&"<".repeat(from - to));
+    }
+}
+
+fn adjust(out: &mut String, delta: isize) {
+    if delta < 0 {
+        out.push_str(&"-".repeat(-delta as usize));
+    } else {
+        out.push_str(&"+".repeat(delta as usize));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::print_text;
+    use crate::ast::AST;
+    use crate::exec::{self, Tape};
+    use crate::token::tokenize;
+
+    fn round_trip(text: &[u8]) -> String {
+        let code = print_text(text);
+        let ast = AST::from_tokens(&tokenize(&code)).unwrap();
+        let mut tape = Tape::with_size(16);
+        let mut output = vec![];
+        exec::run(&ast, &mut tape, &mut &b""[..], &mut output, 0).unwrap();
+        assert_eq!(output, text, "{}", code);
+        code
+    }
+
+    #[test]
+    fn text() {
+        round_trip(b"");
+        round_trip(b"A");
+        round_trip(b"aaa bbb");
+        round_trip(b"The quick brown fox jumps over the lazy dog.\n");
+        round_trip(b"\x00\xff\x80\x7f\x01\xfe");
+        round_trip("sáteko ✓".as_bytes());
+    }
+
+    #[test]
+    fn all_bytes() {
+        let text: Vec<u8> = (0..=255).rev().chain(0..=255).collect();
+        round_trip(&text);
+    }
+
+    #[test]
+    fn compact() {
+        // close to the well known hand-written "Hello World!\n" in 106 commands
+        let code = round_trip(b"Hello World!\n");
+        assert!(code.len() <= 115, "{} {}", code.len(), code);
+
+        assert_eq!(round_trip(b"!!!"), "++++[>++++++++<-]>+...");
+    }
+}
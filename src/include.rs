@@ -0,0 +1,351 @@
+//! Multi-file programs.
+//!
+//! A line of the form `#include "path"` is replaced by the commands of the
+//! named file before parsing. Paths are looked up relative to the directory of
+//! the file containing the directive, then in each search directory in order.
+//! The whole directive line is dropped, so commands in the path itself, like
+//! the `.` in `print.bf`, are never run. Tokens from an included file carry
+//! its path in their positions.
+//!
+//! Macros are expanded here too, line by line, so macros defined in an
+//! included file can be used after the `#include`.
+use crate::dialect::Lexer;
+use crate::macros::{self, MacroError, Macros};
+use crate::token::{Extensions, InputPosition, Token};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+const DIRECTIVE: &str = "#include";
+
+/// The text of every file a program was read from, for looking up spans.
+#[derive(Debug, Default)]
+pub struct Sources {
+    files: Vec<(Option<Rc<str>>, Vec<u8>)>,
+}
+
+impl Sources {
+    /// Sources of a program read from `text` alone.
+    pub fn new(text: &[u8]) -> Sources {
+        Sources {
+            files: vec![(None, text.to_vec())],
+        }
+    }
+
+    /// Text of the file `pos` is in.
+    pub fn text(&self, pos: &InputPosition) -> &[u8] {
+        &self.files.iter().find(|(file, _)| *file == pos.file).unwrap().1
+    }
+}
+
+#[derive(Debug)]
+pub struct IncludeError {
+    pub pos: InputPosition,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Malformed,
+    NotFound(String),
+    /// Including the file would include it again, by way of the chain of
+    /// files listed.
+    Cycle(Vec<String>),
+    IOError(String, std::io::Error),
+    Macro(macros::ErrorKind),
+}
+
+impl fmt::Display for IncludeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::Malformed => write!(f, "Malformed include, expected {} \"path\"", DIRECTIVE)?,
+            ErrorKind::NotFound(path) => write!(f, "Included file \"{}\" not found", path)?,
+            ErrorKind::Cycle(chain) => write!(f, "Include cycle: {}", chain.join(" -> "))?,
+            ErrorKind::IOError(path, e) => write!(f, "Failed to read \"{}\": {}", path, e)?,
+            ErrorKind::Macro(kind) => write!(f, "{}", kind)?,
+        }
+        write!(f, " ({})", self.pos)
+    }
+}
+
+impl Error for IncludeError {}
+
+impl From<MacroError> for IncludeError {
+    fn from(e: MacroError) -> IncludeError {
+        IncludeError {
+            pos: e.pos,
+            kind: ErrorKind::Macro(e.kind),
+        }
+    }
+}
+
+/// Tokenize the script at `path`, with text `text`, and every file it
+/// includes with `lexer`, searching `search` for included files.
+pub fn tokenize(
+    path: &str,
+    text: &[u8],
+    ext: &Extensions,
+    lexer: &dyn Lexer,
+    search: &[PathBuf],
+) -> Result<(Vec<Token>, Sources), IncludeError> {
+    let mut loader = Loader {
+        ext,
+        lexer,
+        search,
+        stack: vec![(path.to_string(), fs::canonicalize(path).ok())],
+        sources: Sources::new(text),
+        macros: Macros::default(),
+    };
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let tokens = loader.tokenize(text, None, dir)?;
+    Ok((tokens, loader.sources))
+}
+
+struct Loader<'a> {
+    ext: &'a Extensions,
+    lexer: &'a dyn Lexer,
+    search: &'a [PathBuf],
+    /// Files currently being included, outermost first, by name and
+    /// canonical path.
+    stack: Vec<(String, Option<PathBuf>)>,
+    sources: Sources,
+    macros: Macros,
+}
+
+impl<'a> Loader<'a> {
+    fn tokenize(&mut self, text: &[u8], file: Option<Rc<str>>, dir: &Path) -> Result<Vec<Token>, IncludeError> {
+        let mut tokens = self.lexer.tokenize(text, file).into_iter().peekable();
+        let mut ret = vec![];
+
+        let mut line_start = 0;
+        for line in text.split(|&b| b == b'\n') {
+            let line_end = line_start + line.len();
+            let mut line_tokens = vec![];
+            while let Some(t) = tokens.next_if(|t| t.span.start < line_end) {
+                line_tokens.push(t);
+            }
+
+            // one token per character of the indent and directive
+            let indent = line.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            if line[indent..].starts_with(DIRECTIVE.as_bytes()) {
+                let pos = line_tokens[indent].pos.clone();
+                let path = parse_directive(&line[indent + DIRECTIVE.len()..]).ok_or_else(|| IncludeError {
+                    pos: pos.clone(),
+                    kind: ErrorKind::Malformed,
+                })?;
+                ret.extend(self.include(path, dir, &pos)?);
+            } else if self.ext.macros && line[indent..].starts_with(macros::DIRECTIVE.as_bytes()) {
+                let def = &line_tokens[indent + macros::DIRECTIVE.len()..];
+                self.macros.define(&macros::chars(text, def), &line_tokens[indent].pos)?;
+            } else if self.ext.macros {
+                ret.extend(self.macros.expand(&macros::chars(text, &line_tokens))?);
+            } else {
+                ret.extend(line_tokens);
+            }
+            line_start = line_end + 1;
+        }
+
+        Ok(ret)
+    }
+
+    fn include(&mut self, path: &str, dir: &Path, pos: &InputPosition) -> Result<Vec<Token>, IncludeError> {
+        let err = |kind| IncludeError { pos: pos.clone(), kind };
+
+        let found = std::iter::once(dir)
+            .chain(self.search.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(path))
+            .find(|p| p.is_file())
+            .ok_or_else(|| err(ErrorKind::NotFound(path.to_string())))?;
+        let name = found.display().to_string();
+        let canonical = fs::canonicalize(&found).ok();
+
+        if canonical.is_some() && self.stack.iter().any(|(_, c)| *c == canonical) {
+            let mut chain: Vec<String> = self.stack.iter().map(|(n, _)| n.clone()).collect();
+            chain.push(name);
+            return Err(err(ErrorKind::Cycle(chain)));
+        }
+
+        let text = fs::read(&found).map_err(|e| err(ErrorKind::IOError(name.clone(), e)))?;
+        let file: Rc<str> = name.clone().into();
+        if !self.sources.files.iter().any(|(f, _)| f.as_ref() == Some(&file)) {
+            self.sources.files.push((Some(file.clone()), text.clone()));
+        }
+
+        self.stack.push((name, canonical));
+        let dir = found.parent().unwrap_or_else(|| Path::new(""));
+        let tokens = self.tokenize(&text, Some(file), dir)?;
+        self.stack.pop();
+        Ok(tokens)
+    }
+}
+
+/// The path in the rest of a directive line, after `#include`.
+fn parse_directive(rest: &[u8]) -> Option<&str> {
+    let rest = std::str::from_utf8(rest).ok()?;
+    let rest = rest.trim();
+    if rest.len() < 2 || !rest.starts_with('"') || !rest.ends_with('"') {
+        return None;
+    }
+    let path = &rest[1..rest.len() - 1];
+    if path.is_empty() || path.contains('"') {
+        return None;
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ErrorKind, Sources};
+    use crate::ast::AST;
+    use crate::dialect::Brainfuck;
+    use crate::token::{Extensions, InputPosition, TokenKind};
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A scratch directory holding `files`, removed when dropped.
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Dir {
+            let dir = std::env::temp_dir().join(format!("sateko-include-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            for (path, text) in files {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, text).unwrap();
+            }
+            Dir(dir)
+        }
+
+        fn path(&self, path: &str) -> String {
+            self.0.join(path).display().to_string()
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn tokenize(dir: &Dir, main: &str, search: &[PathBuf]) -> Result<(Vec<(TokenKind, InputPosition)>, Sources), super::IncludeError> {
+        let path = dir.path(main);
+        let text = fs::read(&path).unwrap();
+        let ext = Extensions::default();
+        let (tokens, sources) = super::tokenize(&path, &text, &ext, &Brainfuck(ext.clone()), search)?;
+        let tokens = tokens
+            .into_iter()
+            .filter(|t| t.kind != TokenKind::Comment)
+            .map(|t| (t.kind, t.pos))
+            .collect();
+        Ok((tokens, sources))
+    }
+
+    fn pos(file: Option<&str>, line: usize, pos: usize) -> InputPosition {
+        InputPosition {
+            file: file.map(Into::into),
+            line,
+            pos,
+            expansion: None,
+        }
+    }
+
+    #[test]
+    fn include() {
+        let dir = Dir::new("include", &[
+            ("main.bf", "+\n  #include \"lib/print.bf\"  \n-"),
+            ("lib/print.bf", "#include \"dot.bf\"\n>"),
+            ("lib/dot.bf", "."),
+        ]);
+        let print = dir.path("lib/print.bf");
+        let dot = dir.path("lib/dot.bf");
+
+        let (tokens, sources) = tokenize(&dir, "main.bf", &[]).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenKind::IncVal, pos(None, 1, 1)),
+                (TokenKind::Write, pos(Some(&dot), 1, 1)),
+                (TokenKind::IncTape, pos(Some(&print), 2, 1)),
+                (TokenKind::DecVal, pos(None, 3, 1)),
+            ]
+        );
+        assert_eq!(sources.text(&pos(Some(&print), 1, 1)), b"#include \"dot.bf\"\n>");
+        assert_eq!(sources.text(&pos(None, 1, 1)), b"+\n  #include \"lib/print.bf\"  \n-");
+    }
+
+    #[test]
+    fn search_path() {
+        let dir = Dir::new("search", &[("src/main.bf", "#include \"print.bf\""), ("lib/print.bf", ".")]);
+
+        let err = tokenize(&dir, "src/main.bf", &[]).unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::NotFound(ref p) if p == "print.bf"));
+        assert_eq!(err.pos, pos(None, 1, 1));
+
+        let (tokens, _) = tokenize(&dir, "src/main.bf", &[dir.0.join("lib")]).unwrap();
+        assert_eq!(tokens, vec![(TokenKind::Write, pos(Some(&dir.path("lib/print.bf")), 1, 1))]);
+    }
+
+    #[test]
+    fn repeated() {
+        let dir = Dir::new("repeated", &[("main.bf", "#include \"a.bf\"\n#include \"a.bf\""), ("a.bf", "+")]);
+        let (tokens, _) = tokenize(&dir, "main.bf", &[]).unwrap();
+        assert_eq!(tokens.len(), 2);
+    }
+
+    #[test]
+    fn cycle() {
+        let dir = Dir::new("cycle", &[
+            ("main.bf", "#include \"a.bf\""),
+            ("a.bf", "+\n #include \"b.bf\""),
+            ("b.bf", "#include \"a.bf\""),
+        ]);
+        let err = tokenize(&dir, "main.bf", &[]).unwrap_err();
+        assert_eq!(err.pos, pos(Some(&dir.path("b.bf")), 1, 1));
+        let chain = vec![dir.path("main.bf"), dir.path("a.bf"), dir.path("b.bf"), dir.path("a.bf")];
+        assert!(matches!(err.kind, ErrorKind::Cycle(ref c) if *c == chain));
+    }
+
+    #[test]
+    fn malformed() {
+        let dir = Dir::new("malformed", &[("main.bf", "+\n\t#include lib.bf")]);
+        let err = tokenize(&dir, "main.bf", &[]).unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Malformed));
+        assert_eq!(err.pos, pos(None, 2, 2));
+        assert_eq!(err.to_string(), "Malformed include, expected #include \"path\" (2:2)");
+    }
+
+    #[test]
+    fn parse_directive() {
+        assert_eq!(super::parse_directive(b" \"a b.bf\" "), Some("a b.bf"));
+        assert_eq!(super::parse_directive(b" \"\""), None);
+        assert_eq!(super::parse_directive(b" a.bf"), None);
+        assert_eq!(super::parse_directive(b" \"a.bf\" +"), None);
+    }
+
+    #[test]
+    fn macros() {
+        let dir = Dir::new("macros", &[
+            ("main.bf", "#include \"lib.bf\"\n@nl\n@open"),
+            ("lib.bf", "#define nl +*10.\n#define open [-"),
+        ]);
+        let path = dir.path("main.bf");
+        let text = fs::read(&path).unwrap();
+        let ext = Extensions {
+            macros: true,
+            ..Extensions::default()
+        };
+        let (tokens, _) = super::tokenize(&path, &text, &ext, &Brainfuck(ext.clone()), &[]).unwrap();
+        let kinds: Vec<TokenKind> = tokens.iter().map(|t| t.kind.clone()).collect();
+        assert_eq!(kinds[..10], vec![TokenKind::IncVal; 10][..]);
+        assert_eq!(kinds[10..], [TokenKind::Write, TokenKind::StartLoop, TokenKind::DecVal]);
+
+        let err = AST::from_tokens(&tokens).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Unclosed loop ({}:2:14, in macro open called from 3:1)", dir.path("lib.bf"))
+        );
+    }
+}
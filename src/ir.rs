@@ -0,0 +1,248 @@
+use crate::ast::{ASTNode, ASTNodeKind, AST};
+use crate::token::{InputPosition, Span};
+
+/// Optimized form of the AST shared by the code generators. Runs of `+`/`-`,
+/// of `>` and of `<` are folded into single nodes, `[-]` becomes `Clear` and
+/// loops like `[->++<]` become `Transfer`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub kind: NodeKind,
+    /// Position of the first command folded into this node.
+    pub pos: InputPosition,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeKind {
+    /// Wrapping add to the active cell.
+    Add(u8),
+    /// Move the tape position; negative values move left.
+    Move(isize),
+    /// Set the active cell to zero.
+    Clear,
+    /// Add the active cell times each factor to the cell at each offset, then
+    /// clear the active cell.
+    Transfer(Vec<(isize, u8)>),
+    Read,
+    Write,
+    Debug,
+    Loop(Vec<Node>),
+}
+
+/// Procedures are only supported by the tree interpreter and the LLVM backend,
+/// so `ast` must not use them.
+pub fn from_ast(ast: &AST) -> Vec<Node> {
+    lower(&ast.0)
+}
+
+fn lower(ops: &[ASTNode]) -> Vec<Node> {
+    let mut ret: Vec<Node> = vec![];
+
+    for op in ops {
+        let kind = match op.kind {
+            ASTNodeKind::IncVal => NodeKind::Add(1),
+            ASTNodeKind::DecVal => NodeKind::Add(255),
+            ASTNodeKind::IncTape => NodeKind::Move(1),
+            ASTNodeKind::DecTape => NodeKind::Move(-1),
+            ASTNodeKind::Read => NodeKind::Read,
+            ASTNodeKind::Write => NodeKind::Write,
+            ASTNodeKind::Debug => NodeKind::Debug,
+            ASTNodeKind::Procedure | ASTNodeKind::Call => panic!("procedures have no IR form"),
+            ASTNodeKind::Comment => continue,
+            ASTNodeKind::Loop => {
+                let body = lower(op.ops.as_ref().unwrap());
+                if is_clear(&body) {
+                    NodeKind::Clear
+                } else if let Some(targets) = transfer_targets(&body) {
+                    NodeKind::Transfer(targets)
+                } else {
+                    NodeKind::Loop(body)
+                }
+            }
+        };
+
+        // spans are offsets into one stretch of source, so nodes from
+        // different files or macro calls stay apart
+        let last = ret
+            .last()
+            .filter(|n| n.pos.file == op.pos.file && n.pos.expansion == op.pos.expansion);
+        let folded = match (last.map(|n| &n.kind), &kind) {
+            (Some(NodeKind::Add(a)), NodeKind::Add(b)) => Some(NodeKind::Add(a.wrapping_add(*b))),
+            // a move only checks where it ends, so `<>` must not fold away
+            // at the start of the tape
+            (Some(NodeKind::Move(a)), NodeKind::Move(b)) if a.signum() == b.signum() => {
+                Some(NodeKind::Move(a + b))
+            }
+            _ => None,
+        };
+
+        match folded {
+            Some(kind) => {
+                let last = ret.last_mut().unwrap();
+                last.kind = kind;
+                last.span.end = op.span.end;
+                if last.kind == NodeKind::Add(0) {
+                    ret.pop();
+                }
+            }
+            None => ret.push(Node {
+                kind,
+                pos: op.pos.clone(),
+                span: op.span,
+            }),
+        }
+    }
+
+    ret
+}
+
+/// Whether any node in `ir`, including inside loops, matches `pred`.
+pub fn contains(ir: &[Node], pred: &dyn Fn(&NodeKind) -> bool) -> bool {
+    ir.iter().any(|node| match &node.kind {
+        NodeKind::Loop(body) => contains(body, pred),
+        kind => pred(kind),
+    })
+}
+
+/// Escape `data` for a byte string literal, which Rust and Python write the
+/// same way.
+pub fn escape_bytes(data: &[u8]) -> String {
+    data.iter().flat_map(|b| std::ascii::escape_default(*b)).map(char::from).collect()
+}
+
+/// `s` as a double-quoted string literal, in the syntax JavaScript and Python
+/// share.
+pub fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Lower `raw`, with `#` enabled, for the backend tests.
+#[cfg(test)]
+pub fn parse(raw: &str) -> Vec<Node> {
+    use crate::token::{tokenize_with, Extensions};
+
+    let ext = Extensions {
+        debug_hash: true,
+        ..Extensions::default()
+    };
+    from_ast(&AST::from_tokens(&tokenize_with(raw, &ext)).unwrap())
+}
+
+/// `[-]` and `[+]` (or any odd step) always leave the cell at zero.
+fn is_clear(body: &[Node]) -> bool {
+    match body {
+        [Node {
+            kind: NodeKind::Add(n),
+            ..
+        }] => n % 2 == 1,
+        _ => false,
+    }
+}
+
+/// A loop body made only of adds and moves, which returns to its starting
+/// cell and steps that cell by one, runs once per unit of the starting value.
+fn transfer_targets(body: &[Node]) -> Option<Vec<(isize, u8)>> {
+    let mut offset = 0;
+    let mut deltas: Vec<(isize, u8)> = vec![];
+
+    for node in body {
+        match node.kind {
+            NodeKind::Move(n) => offset += n,
+            NodeKind::Add(n) => match deltas.iter_mut().find(|(o, _)| *o == offset) {
+                Some((_, d)) => *d = d.wrapping_add(n),
+                None => deltas.push((offset, n)),
+            },
+            _ => return None,
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+
+    // counting up from v to zero takes 256 - v steps, so each factor flips sign
+    let step = deltas.iter().find(|(o, _)| *o == 0).map(|(_, d)| *d);
+    let sign = match step {
+        Some(255) => 1u8,
+        Some(1) => 255u8,
+        _ => return None,
+    };
+
+    Some(
+        deltas
+            .into_iter()
+            .filter(|&(o, d)| o != 0 && d != 0)
+            .map(|(o, d)| (o, d.wrapping_mul(sign)))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::NodeKind;
+    use crate::ast::AST;
+    use crate::token::tokenize;
+
+    fn kinds(raw: &str) -> Vec<NodeKind> {
+        let ast = AST::from_tokens(&tokenize(raw)).unwrap();
+        super::from_ast(&ast).into_iter().map(|n| n.kind).collect()
+    }
+
+    #[test]
+    fn fold_runs() {
+        assert_eq!(
+            kinds("+++>>-<<<.,"),
+            vec![
+                NodeKind::Add(3),
+                NodeKind::Move(2),
+                NodeKind::Add(255),
+                NodeKind::Move(-3),
+                NodeKind::Write,
+                NodeKind::Read,
+            ]
+        );
+    }
+
+    #[test]
+    fn cancel_runs() {
+        assert_eq!(kinds("+-.+ +-"), vec![NodeKind::Write, NodeKind::Add(1)]);
+        assert_eq!(kinds("><<"), vec![NodeKind::Move(1), NodeKind::Move(-2)]);
+    }
+
+    #[test]
+    fn clear() {
+        assert_eq!(kinds("[-][+++]"), vec![NodeKind::Clear, NodeKind::Clear]);
+        match &kinds("[--]")[..] {
+            [NodeKind::Loop(body)] => assert_eq!(body[0].kind, NodeKind::Add(254)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn transfer() {
+        assert_eq!(kinds("[->++>>+<<<]"), vec![NodeKind::Transfer(vec![(1, 2), (3, 1)])]);
+        assert_eq!(kinds("[<->+]"), vec![NodeKind::Transfer(vec![(-1, 1)])]);
+        assert!(matches!(kinds("[->+<<]")[..], [NodeKind::Loop(_)]));
+        assert!(matches!(kinds("[->+<.]")[..], [NodeKind::Loop(_)]));
+        assert!(matches!(kinds("[-->+<]")[..], [NodeKind::Loop(_)]));
+    }
+
+    #[test]
+    fn span() {
+        let ast = AST::from_tokens(&tokenize(".\n++ +")).unwrap();
+        let ir = super::from_ast(&ast);
+        assert_eq!((ir[1].pos.line, ir[1].pos.pos), (2, 1));
+        assert_eq!((ir[1].span.start, ir[1].span.end), (2, 6));
+    }
+}
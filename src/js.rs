@@ -0,0 +1,198 @@
+//! JavaScript code generation.
+//!
+//! The output runs under Node.js with no dependencies. Input comes from the
+//! embedded input when there is some, otherwise from the file named by the
+//! first argument or stdin, and is read in full before the program starts.
+//! Cells are a `Uint8Array`, so they wrap, and `,` stores 255 at end of
+//! input. Moving off the tape is not checked.
+use crate::exec::DEBUG_WINDOW;
+use crate::ir::{self, Node, NodeKind};
+use std::fmt::{self, Write};
+
+/// Translate `ir` to a script for Node.js. When `input` is given it is
+/// embedded, and neither stdin nor a file is read.
+pub fn generate(ir: &[Node], tape_len: u32, input: Option<&[u8]>) -> String {
+    let mut out = String::new();
+    write_program(&mut out, ir, tape_len, input).unwrap();
+    out
+}
+
+fn write_program(out: &mut String, ir: &[Node], tape_len: u32, input: Option<&[u8]>) -> fmt::Result {
+    let reads = ir::contains(ir, &|kind| *kind == NodeKind::Read);
+    let debug = ir::contains(ir, &|kind| *kind == NodeKind::Debug);
+
+    writeln!(out, "\"use strict\";")?;
+    writeln!(out)?;
+    if reads && input.is_none() {
+        writeln!(out, "const fs = require(\"fs\");")?;
+        writeln!(out)?;
+    }
+    writeln!(out, "const TAPE_LEN = {};", tape_len)?;
+
+    if reads {
+        match input {
+            Some(input) => {
+                let bytes: Vec<String> = input.iter().map(|b| b.to_string()).collect();
+                writeln!(out, "const input = Buffer.from([{}]);", bytes.join(", "))?;
+            }
+            None => writeln!(
+                out,
+                "const input = fs.readFileSync(process.argv.length > 2 ? process.argv[2] : 0);"
+            )?,
+        }
+        writeln!(out, "let inputPos = 0;")?;
+        writeln!(out)?;
+        writeln!(out, "function read() {{")?;
+        writeln!(out, "    return inputPos < input.length ? input[inputPos++] : 255;")?;
+        writeln!(out, "}}")?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "const output = [];")?;
+    writeln!(out)?;
+    writeln!(out, "function flush() {{")?;
+    writeln!(out, "    process.stdout.write(Buffer.from(output));")?;
+    writeln!(out, "    output.length = 0;")?;
+    writeln!(out, "}}")?;
+
+    if debug {
+        write_debug(out, tape_len)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "const tape = new Uint8Array(TAPE_LEN);")?;
+    writeln!(out, "let p = 0;")?;
+    write_nodes(out, ir, 0)?;
+    writeln!(out, "flush();")
+}
+
+/// Emit `debug`, which writes any buffered output before dumping the tape to
+/// stderr.
+fn write_debug(out: &mut String, tape_len: u32) -> fmt::Result {
+    let window = (DEBUG_WINDOW as u32).min(tape_len);
+    writeln!(out)?;
+    writeln!(out, "function debug(tape, p, file, line, col) {{")?;
+    writeln!(out, "    flush();")?;
+    writeln!(out, "    const start = Math.min(Math.max(p - {}, 0), {});", DEBUG_WINDOW / 2, tape_len - window)?;
+    writeln!(out, "    let dump = `[${{file}}${{line}},${{col}}] ptr ${{p}}:`;")?;
+    writeln!(out, "    for (let i = start; i < start + {}; i++) {{", window)?;
+    writeln!(out, "        dump += i === p ? ` [${{tape[i]}}]` : ` ${{tape[i]}}`;")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    process.stderr.write(dump + \"\\n\");")?;
+    writeln!(out, "}}")
+}
+
+fn write_nodes(out: &mut String, ir: &[Node], depth: usize) -> fmt::Result {
+    let indent = "    ".repeat(depth);
+
+    for node in ir {
+        match &node.kind {
+            NodeKind::Add(n) if *n > 128 => writeln!(out, "{}tape[p] -= {};", indent, n.wrapping_neg())?,
+            NodeKind::Add(n) => writeln!(out, "{}tape[p] += {};", indent, n)?,
+            NodeKind::Move(n) if *n < 0 => writeln!(out, "{}p -= {};", indent, -n)?,
+            NodeKind::Move(n) => writeln!(out, "{}p += {};", indent, n)?,
+            NodeKind::Clear => writeln!(out, "{}tape[p] = 0;", indent)?,
+            NodeKind::Transfer(targets) => {
+                // skipped at zero, as the loop was, so no cell off the tape is touched
+                writeln!(out, "{}if (tape[p] !== 0) {{", indent)?;
+                let inner = format!("{}    ", indent);
+                for &(offset, factor) in targets {
+                    let cell = match offset {
+                        o if o < 0 => format!("tape[p - {}]", -o),
+                        o => format!("tape[p + {}]", o),
+                    };
+                    let (op, factor) = if factor > 128 {
+                        ("-=", factor.wrapping_neg())
+                    } else {
+                        ("+=", factor)
+                    };
+                    if factor == 1 {
+                        writeln!(out, "{}{} {} tape[p];", inner, cell, op)?;
+                    } else {
+                        writeln!(out, "{}{} {} tape[p] * {};", inner, cell, op, factor)?;
+                    }
+                }
+                writeln!(out, "{}tape[p] = 0;", inner)?;
+                writeln!(out, "{}}}", indent)?;
+            }
+            NodeKind::Read => writeln!(out, "{}tape[p] = read();", indent)?,
+            NodeKind::Write => writeln!(out, "{}output.push(tape[p]);", indent)?,
+            NodeKind::Debug => {
+                let file = ir::quote(&node.pos.file_prefix());
+                writeln!(out, "{}debug(tape, p, {}, {}, {});", indent, file, node.pos.line, node.pos.pos)?
+            }
+            NodeKind::Loop(body) => {
+                writeln!(out, "{}while (tape[p] !== 0) {{", indent)?;
+                write_nodes(out, body, depth + 1)?;
+                writeln!(out, "{}}}", indent)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ir;
+
+    fn generate(raw: &str, input: Option<&[u8]>) -> String {
+        super::generate(&ir::parse(raw), 300, input)
+    }
+
+    fn body(src: &str) -> &str {
+        let start = src.find("let p = 0;\n").unwrap();
+        &src[start..]
+    }
+
+    #[test]
+    fn statements() {
+        let src = generate(">>+++<--<[->+<<---<>>]>[<.]", None);
+        assert!(src.contains("const TAPE_LEN = 300;\n"));
+        assert_eq!(
+            body(&src),
+            "let p = 0;
+p += 2;
+tape[p] += 3;
+p -= 1;
+tape[p] -= 2;
+p -= 1;
+if (tape[p] !== 0) {
+    tape[p + 1] += tape[p];
+    tape[p - 1] -= tape[p] * 3;
+    tape[p] = 0;
+}
+p += 1;
+while (tape[p] !== 0) {
+    p -= 1;
+    output.push(tape[p]);
+}
+flush();
+"
+        );
+        assert!(!src.contains("function read"));
+        assert!(!src.contains("function debug"));
+    }
+
+    #[test]
+    fn input() {
+        let src = generate(",", None);
+        assert!(src.contains("const input = fs.readFileSync(process.argv.length > 2 ? process.argv[2] : 0);\n"));
+        assert!(src.contains("tape[p] = read();\n"));
+
+        let src = generate(",", Some(b"hi"));
+        assert!(src.contains("const input = Buffer.from([104, 105]);\n"));
+        assert!(!src.contains("require"));
+    }
+
+    #[test]
+    fn debug() {
+        let src = generate("+\n #", None);
+        assert!(src.contains("function debug(tape, p, file, line, col) {"));
+        assert!(src.contains("debug(tape, p, \"\", 2, 2);\n"));
+
+        let mut ir = ir::parse("#");
+        ir[0].pos.file = Some("lib/\"a\tb\".bf".into());
+        let src = super::generate(&ir, 300, None);
+        assert!(src.contains("debug(tape, p, \"lib/\\\"a\\u0009b\\\".bf:\", 1, 1);\n"));
+    }
+}
@@ -0,0 +1,245 @@
+//! Warnings about common mistakes in scripts.
+//!
+//! Each kind of warning has a code which stays the same between versions, so
+//! it can be silenced by name. A loop at the very start of a script is the
+//! usual way to write a comment with command characters in it, so it is never
+//! warned about, and neither is anything inside a loop which never runs.
+use crate::ast::{ASTNode, ASTNodeKind, AST};
+use crate::bounds::{self, Edge};
+use crate::token::InputPosition;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lint {
+    /// A loop starts where the current cell is known to be zero.
+    DeadLoop,
+    /// A loop body never changes the current cell or moves, so the loop never
+    /// ends once it starts.
+    InfiniteLoop,
+    /// A command is undone by the one after it, like `+-` or `<>`.
+    NoOp,
+    /// A command character is part of a word, as in `Hello, world.` written in
+    /// a comment.
+    CommandInComment,
+    /// A move goes left of the first cell every time it runs.
+    OffTapeStart,
+}
+
+pub const LINTS: &[Lint] = &[
+    Lint::DeadLoop,
+    Lint::InfiniteLoop,
+    Lint::NoOp,
+    Lint::CommandInComment,
+    Lint::OffTapeStart,
+];
+
+impl Lint {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Lint::DeadLoop => "L001",
+            Lint::InfiniteLoop => "L002",
+            Lint::NoOp => "L003",
+            Lint::CommandInComment => "L004",
+            Lint::OffTapeStart => "L005",
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            Lint::DeadLoop => "Loop never runs, the current cell is always zero here",
+            Lint::InfiniteLoop => "Loop never ends once started, its body changes neither the cell nor the pointer",
+            Lint::NoOp => "Command is undone by the next one",
+            Lint::CommandInComment => "Command character inside a word, probably part of a comment",
+            Lint::OffTapeStart => "Pointer always moves left of the first cell here",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    pub pos: InputPosition,
+    pub lint: Lint,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} ({})", self.lint.code(), self.lint.message(), self.pos)
+    }
+}
+
+/// Check `ast`, parsed from `text`, returning warnings in source order.
+pub fn lint(ast: &AST, text: &[u8]) -> Vec<Warning> {
+    let mut linter = Linter {
+        text,
+        warnings: vec![],
+    };
+    let start = ast.0.iter().position(|op| op.kind != ASTNodeKind::Comment);
+    let skip = match start {
+        Some(i) if ast.0[i].kind == ASTNodeKind::Loop => i + 1,
+        _ => 0,
+    };
+    linter.body(&ast.0[skip..], true, true);
+
+    // only the start of the tape is known, so leave the end out of reach
+    for escape in bounds::analyse(ast, isize::MAX as usize).escapes {
+        if escape.edge == Edge::Start {
+            linter.warn(&escape.pos, Lint::OffTapeStart);
+        }
+    }
+
+    let mut warnings = linter.warnings;
+    warnings.sort_by_key(|w| (w.pos.line, w.pos.pos));
+    warnings
+}
+
+struct Linter<'a> {
+    text: &'a [u8],
+    warnings: Vec<Warning>,
+}
+
+impl<'a> Linter<'a> {
+    fn warn(&mut self, pos: &InputPosition, lint: Lint) {
+        self.warnings.push(Warning { pos: pos.clone(), lint });
+    }
+
+    /// Check a body which starts with the current cell zero when `zero` is set
+    /// and every cell zero when `blank` is set.
+    fn body(&mut self, nodes: &[ASTNode], mut zero: bool, mut blank: bool) {
+        let mut prev: Option<&ASTNode> = None;
+        for node in nodes.iter().filter(|op| op.kind != ASTNodeKind::Comment) {
+            if let Some(p) = prev {
+                if undoes(&p.kind, &node.kind) {
+                    self.warn(&p.pos, Lint::NoOp);
+                    // `+-+` is one mistake, not two
+                    prev = None;
+                } else {
+                    prev = Some(node);
+                }
+            } else {
+                prev = Some(node);
+            }
+            self.in_word(node);
+
+            match node.kind {
+                ASTNodeKind::Loop => {
+                    let body = node.ops.as_ref().unwrap();
+                    if zero {
+                        self.warn(&node.pos, Lint::DeadLoop);
+                        continue;
+                    }
+                    if !changes(body) {
+                        self.warn(&node.pos, Lint::InfiniteLoop);
+                    }
+                    self.body(body, false, false);
+                    zero = true;
+                    blank = false;
+                }
+                ASTNodeKind::Procedure => self.body(node.ops.as_ref().unwrap(), false, false),
+                ASTNodeKind::IncTape | ASTNodeKind::DecTape => zero = blank,
+                _ => {
+                    zero = false;
+                    blank = false;
+                }
+            }
+        }
+    }
+
+    /// Warn if the command `node` sits between letters.
+    fn in_word(&mut self, node: &ASTNode) {
+        if node.pos.file.is_some() || node.pos.expansion.is_some() {
+            return;
+        }
+        let letter = |i: usize| self.text.get(i).is_some_and(|&b| b.is_ascii_alphabetic() || b >= 0x80);
+        // the opening bracket, and the closing one of a loop
+        let mut ends = vec![node.span.start];
+        if node.ops.is_some() {
+            ends.push(node.span.end - 1);
+        }
+        for i in ends {
+            if (i > 0 && letter(i - 1)) || letter(i + 1) {
+                self.warn(&node.pos, Lint::CommandInComment);
+                return;
+            }
+        }
+    }
+}
+
+fn undoes(a: &ASTNodeKind, b: &ASTNodeKind) -> bool {
+    use ASTNodeKind::*;
+    matches!((a, b), (IncVal, DecVal) | (DecVal, IncVal) | (IncTape, DecTape) | (DecTape, IncTape))
+}
+
+/// Whether a loop body can change the current cell or move, counting any
+/// loop inside as a change.
+fn changes(body: &[ASTNode]) -> bool {
+    body.iter().any(|op| {
+        !matches!(
+            op.kind,
+            ASTNodeKind::Write | ASTNodeKind::Debug | ASTNodeKind::Comment | ASTNodeKind::Procedure
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::Lint;
+    use crate::ast::AST;
+    use crate::token::tokenize;
+
+    fn lint(text: &str) -> Vec<(Lint, usize, usize)> {
+        let ast = AST::from_tokens(&tokenize(text)).unwrap();
+        super::lint(&ast, text.as_bytes())
+            .into_iter()
+            .map(|w| (w.lint, w.pos.line, w.pos.pos))
+            .collect()
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(lint("[ comment, loop ]\n>>[-]+[-][.]"), vec![(Lint::DeadLoop, 2, 3), (Lint::DeadLoop, 2, 10)]);
+        assert_eq!(lint("+[.]>[]"), vec![(Lint::InfiniteLoop, 1, 2), (Lint::InfiniteLoop, 1, 6)]);
+        assert_eq!(lint("+[[.]]"), vec![(Lint::InfiniteLoop, 1, 3)]);
+        assert_eq!(lint(",[.,]+[>]"), vec![]);
+        // nothing inside a dead loop is checked
+        assert_eq!(lint("+[-][[.]+-]"), vec![(Lint::DeadLoop, 1, 5)]);
+    }
+
+    #[test]
+    fn no_ops() {
+        assert_eq!(lint("+-+ >\n<"), vec![(Lint::NoOp, 1, 1), (Lint::NoOp, 1, 5)]);
+        assert_eq!(lint("+>+<-"), vec![]);
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(
+            lint("+++ Hello, world. \n. x-ray"),
+            vec![(Lint::CommandInComment, 1, 10), (Lint::CommandInComment, 1, 17), (Lint::CommandInComment, 2, 4)]
+        );
+        assert_eq!(lint("+[sic]"), vec![(Lint::CommandInComment, 1, 2), (Lint::InfiniteLoop, 1, 2)]);
+    }
+
+    #[test]
+    fn off_tape() {
+        assert_eq!(lint(">+<<"), vec![(Lint::OffTapeStart, 1, 4)]);
+        assert_eq!(lint(">+[-<+>]<<<"), vec![(Lint::OffTapeStart, 1, 10)]);
+        // unknown after a loop which moves
+        assert_eq!(lint("+[>]<<"), vec![]);
+        // inside a loop, whenever it runs
+        assert_eq!(lint(",[<]"), vec![(Lint::OffTapeStart, 1, 3)]);
+        assert_eq!(lint(",[>,]<<"), vec![]);
+        // the program stops at the first
+        assert_eq!(lint("<<"), vec![(Lint::OffTapeStart, 1, 1)]);
+    }
+
+    #[test]
+    fn codes() {
+        let codes: Vec<&str> = super::LINTS.iter().map(|l| l.code()).collect();
+        assert_eq!(codes, ["L001", "L002", "L003", "L004", "L005"]);
+        let ast = AST::from_tokens(&tokenize("<")).unwrap();
+        assert_eq!(
+            super::lint(&ast, b"<")[0].to_string(),
+            "L005: Pointer always moves left of the first cell here (1:1)"
+        );
+    }
+}
@@ -0,0 +1,543 @@
+//! Macros for reusable snippets of brainfuck.
+//!
+//! With the `macros` extension, a line `#define NAME body` or
+//! `#define NAME(a, b) body` defines a macro, and `@NAME` or `@NAME(1, 2)`
+//! anywhere after it expands to the commands of its body. A command or call
+//! followed by `*` and a count is repeated that many times, e.g. `+*65`, or
+//! `>*n` in the body of a macro with a parameter `n`. Parameters are integers,
+//! used as counts and as arguments to other macros. Macros cannot call
+//! themselves, directly or otherwise. A script expands to at most
+//! `MAX_EXPANSION` commands and calls.
+//!
+//! Commands from a macro body keep their position in the definition, along
+//! with the call they were expanded from.
+use crate::token::{Expansion, InputPosition, Token, TokenKind};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+pub const DIRECTIVE: &str = "#define";
+
+/// The most commands and macro calls a script may expand to.
+pub const MAX_EXPANSION: usize = 1_000_000;
+
+/// A character of source text with the token it was read as.
+pub type Char = (char, Token);
+
+/// The characters of `tokens`, which were read from `text`.
+pub fn chars(text: &[u8], tokens: &[Token]) -> Vec<Char> {
+    tokens
+        .iter()
+        .map(|t| {
+            let c = String::from_utf8_lossy(&text[t.span.start..t.span.end]).chars().next().unwrap();
+            (c, t.clone())
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MacroError {
+    pub pos: InputPosition,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ErrorKind {
+    MalformedDefinition,
+    MalformedCall,
+    MalformedCount,
+    Redefined(String),
+    Undefined(String),
+    UnknownParameter(String),
+    Arguments { name: String, expected: usize, found: usize },
+    Recursive(String),
+    TooLarge,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::MalformedDefinition => write!(f, "Malformed macro definition"),
+            ErrorKind::MalformedCall => write!(f, "Malformed macro call"),
+            ErrorKind::MalformedCount => write!(f, "Malformed repetition count"),
+            ErrorKind::Redefined(name) => write!(f, "Macro {} is already defined", name),
+            ErrorKind::Undefined(name) => write!(f, "Undefined macro {}", name),
+            ErrorKind::UnknownParameter(name) => write!(f, "Unknown parameter {}", name),
+            ErrorKind::Arguments { name, expected, found } => {
+                write!(f, "Macro {} takes {} arguments but was given {}", name, expected, found)
+            }
+            ErrorKind::Recursive(name) => write!(f, "Macro {} calls itself", name),
+            ErrorKind::TooLarge => write!(f, "Macros expand to more than {} commands", MAX_EXPANSION),
+        }
+    }
+}
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.kind, self.pos)
+    }
+}
+
+impl Error for MacroError {}
+
+type Result<T> = std::result::Result<T, MacroError>;
+
+#[derive(Debug)]
+enum Count {
+    Literal(usize),
+    Param(String),
+}
+
+#[derive(Debug)]
+enum ItemKind {
+    Token(Token),
+    Call {
+        name: String,
+        args: Vec<Count>,
+        pos: InputPosition,
+    },
+}
+
+#[derive(Debug)]
+struct Item {
+    kind: ItemKind,
+    times: Count,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Item>,
+}
+
+/// The macros defined so far.
+#[derive(Default)]
+pub struct Macros {
+    defs: HashMap<String, Macro>,
+    /// Commands and calls expanded so far.
+    expanded: usize,
+}
+
+impl Macros {
+    /// Define a macro from the characters of a definition after `#define`.
+    /// `pos` is the position of the directive.
+    pub fn define(&mut self, chars: &[Char], pos: &InputPosition) -> Result<()> {
+        let err = |kind| MacroError { pos: pos.clone(), kind };
+
+        let mut i = skip_space(chars, 0);
+        if i == 0 && !chars.is_empty() {
+            return Err(err(ErrorKind::MalformedDefinition));
+        }
+        let (name, next) = ident(chars, i).ok_or_else(|| err(ErrorKind::MalformedDefinition))?;
+        i = next;
+
+        let mut params = vec![];
+        if chars.get(i).map(|c| c.0) == Some('(') {
+            let (list, next) = list(chars, i + 1, ident)
+                .ok_or_else(|| err(ErrorKind::MalformedDefinition))?;
+            for (j, param) in list.iter().enumerate() {
+                if list[..j].contains(param) {
+                    return Err(err(ErrorKind::MalformedDefinition));
+                }
+            }
+            params = list;
+            i = next;
+        }
+        if i < chars.len() && !chars[i].0.is_whitespace() {
+            return Err(err(ErrorKind::MalformedDefinition));
+        }
+
+        if self.defs.contains_key(&name) {
+            return Err(err(ErrorKind::Redefined(name)));
+        }
+        let body = parse(&chars[i..], &params, false)?;
+        self.defs.insert(name, Macro { params, body });
+        Ok(())
+    }
+
+    /// Expand the macro calls and repetitions in a line of code, keeping its
+    /// comments.
+    pub fn expand(&mut self, chars: &[Char]) -> Result<Vec<Token>> {
+        let items = parse(chars, &[], true)?;
+        let mut out = vec![];
+        let mut expanded = self.expanded;
+        self.expand_items(&items, &HashMap::new(), &None, &mut vec![], &mut expanded, &mut out)?;
+        self.expanded = expanded;
+        Ok(out)
+    }
+
+    fn expand_items(
+        &self,
+        items: &[Item],
+        args: &HashMap<&str, usize>,
+        expansion: &Option<Rc<Expansion>>,
+        stack: &mut Vec<String>,
+        expanded: &mut usize,
+        out: &mut Vec<Token>,
+    ) -> Result<()> {
+        let count = |c: &Count| match c {
+            Count::Literal(n) => *n,
+            Count::Param(p) => args[p.as_str()],
+        };
+
+        for item in items {
+            for _ in 0..count(&item.times) {
+                *expanded += 1;
+                match &item.kind {
+                    ItemKind::Token(t) => {
+                        let mut t = t.clone();
+                        if expansion.is_some() {
+                            t.pos.expansion = expansion.clone();
+                        }
+                        if *expanded > MAX_EXPANSION {
+                            return Err(MacroError {
+                                pos: t.pos,
+                                kind: ErrorKind::TooLarge,
+                            });
+                        }
+                        out.push(t);
+                    }
+                    ItemKind::Call { name, args: call_args, pos } => {
+                        let mut pos = pos.clone();
+                        if expansion.is_some() {
+                            pos.expansion = expansion.clone();
+                        }
+                        let err = |kind| MacroError { pos: pos.clone(), kind };
+
+                        if *expanded > MAX_EXPANSION {
+                            return Err(err(ErrorKind::TooLarge));
+                        }
+                        let def = self.defs.get(name).ok_or_else(|| err(ErrorKind::Undefined(name.clone())))?;
+                        if def.params.len() != call_args.len() {
+                            return Err(err(ErrorKind::Arguments {
+                                name: name.clone(),
+                                expected: def.params.len(),
+                                found: call_args.len(),
+                            }));
+                        }
+                        if stack.contains(name) {
+                            return Err(err(ErrorKind::Recursive(name.clone())));
+                        }
+
+                        let inner_args = def
+                            .params
+                            .iter()
+                            .map(String::as_str)
+                            .zip(call_args.iter().map(count))
+                            .collect();
+                        let inner = Some(Rc::new(Expansion {
+                            name: name.clone(),
+                            call: pos.clone(),
+                        }));
+                        stack.push(name.clone());
+                        self.expand_items(&def.body, &inner_args, &inner, stack, expanded, out)?;
+                        stack.pop();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read commands, calls and repetitions from `chars`. Counts may name any of
+/// `params`. Comments are dropped unless `comments` is set.
+fn parse(chars: &[Char], params: &[String], comments: bool) -> Result<Vec<Item>> {
+    let mut items = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let (c, t) = &chars[i];
+        let kind = if *c == '@' && ident(chars, i + 1).is_some() {
+            let err = |kind| MacroError { pos: t.pos.clone(), kind };
+            let (name, next) = ident(chars, i + 1).unwrap();
+            i = next;
+            let mut args = vec![];
+            if chars.get(i).map(|c| c.0) == Some('(') {
+                let (list, next) = list(chars, i + 1, count).ok_or_else(|| err(ErrorKind::MalformedCall))?;
+                args = list;
+                i = next;
+            }
+            for arg in &args {
+                check_param(arg, params, &t.pos)?;
+            }
+            ItemKind::Call {
+                name,
+                args,
+                pos: t.pos.clone(),
+            }
+        } else if t.kind != TokenKind::Comment {
+            i += 1;
+            ItemKind::Token(t.clone())
+        } else {
+            i += 1;
+            if comments {
+                items.push(Item {
+                    kind: ItemKind::Token(t.clone()),
+                    times: Count::Literal(1),
+                });
+            }
+            continue;
+        };
+
+        let mut times = Count::Literal(1);
+        if chars.get(i).map(|c| c.0) == Some('*') {
+            if let Some((n, next)) = count(chars, i + 1) {
+                check_param(&n, params, &chars[i].1.pos)?;
+                times = n;
+                i = next;
+            } else if chars.get(i + 1).map(|c| c.0.is_ascii_digit()) == Some(true) {
+                // digits that don't fit in a count
+                return Err(MacroError {
+                    pos: chars[i].1.pos.clone(),
+                    kind: ErrorKind::MalformedCount,
+                });
+            }
+        }
+        items.push(Item { kind, times });
+    }
+
+    Ok(items)
+}
+
+fn check_param(count: &Count, params: &[String], pos: &InputPosition) -> Result<()> {
+    match count {
+        Count::Param(p) if !params.contains(p) => Err(MacroError {
+            pos: pos.clone(),
+            kind: ErrorKind::UnknownParameter(p.clone()),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn skip_space(chars: &[Char], mut i: usize) -> usize {
+    while i < chars.len() && chars[i].0.is_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+/// A name at `i`, and the index after it.
+fn ident(chars: &[Char], i: usize) -> Option<(String, usize)> {
+    let start = chars.get(i)?.0;
+    if !(start.is_ascii_alphabetic() || start == '_') {
+        return None;
+    }
+    let len = chars[i..]
+        .iter()
+        .take_while(|(c, _)| c.is_ascii_alphanumeric() || *c == '_')
+        .count();
+    Some((chars[i..i + len].iter().map(|c| c.0).collect(), i + len))
+}
+
+/// A number or parameter name at `i`, and the index after it.
+fn count(chars: &[Char], i: usize) -> Option<(Count, usize)> {
+    if let Some((name, next)) = ident(chars, i) {
+        return Some((Count::Param(name), next));
+    }
+    let len = chars[i..]
+        .iter()
+        .take_while(|(c, _)| c.is_ascii_digit())
+        .count();
+    let digits: String = chars[i..i + len].iter().map(|c| c.0).collect();
+    Some((Count::Literal(digits.parse().ok()?), i + len))
+}
+
+/// Reads a value at an index, returning it and the index after it.
+type Reader<T> = fn(&[Char], usize) -> Option<(T, usize)>;
+
+/// A comma separated list ending in `)`, starting after the `(`, and the
+/// index after the `)`.
+fn list<T>(chars: &[Char], mut i: usize, elem: Reader<T>) -> Option<(Vec<T>, usize)> {
+    let mut ret = vec![];
+    i = skip_space(chars, i);
+    if chars.get(i)?.0 == ')' {
+        return Some((ret, i + 1));
+    }
+    loop {
+        let (e, next) = elem(chars, skip_space(chars, i))?;
+        ret.push(e);
+        i = skip_space(chars, next);
+        match chars.get(i)?.0 {
+            ',' => i += 1,
+            ')' => return Some((ret, i + 1)),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{chars, ErrorKind, MacroError, Macros};
+    use crate::token::{tokenize_with, Expansion, Extensions, InputPosition, TokenKind};
+    use std::rc::Rc;
+
+    fn pos(line: usize, pos: usize) -> InputPosition {
+        InputPosition {
+            file: None,
+            line,
+            pos,
+            expansion: None,
+        }
+    }
+
+    /// Define the macros in all but the last line of `src`, then expand the
+    /// last line, dropping comments.
+    fn expand(src: &str) -> Result<Vec<(TokenKind, InputPosition)>, MacroError> {
+        let ext = Extensions {
+            macros: true,
+            ..Extensions::default()
+        };
+        let tokens = tokenize_with(src, &ext);
+        let lines: Vec<&str> = src.split('\n').collect();
+        let mut macros = Macros::default();
+        for n in 0..lines.len() {
+            let line_tokens: Vec<_> = tokens.iter().filter(|t| t.pos.line == n + 1).cloned().collect();
+            let line_chars = chars(src.as_bytes(), &line_tokens);
+            if n + 1 < lines.len() {
+                macros.define(&line_chars[super::DIRECTIVE.len()..], &pos(n + 1, 1))?;
+            } else {
+                return Ok(macros
+                    .expand(&line_chars)?
+                    .into_iter()
+                    .filter(|t| t.kind != TokenKind::Comment)
+                    .map(|t| (t.kind, t.pos))
+                    .collect());
+            }
+        }
+        unreachable!()
+    }
+
+    fn kinds(src: &str) -> String {
+        expand(src)
+            .unwrap()
+            .into_iter()
+            .map(|(kind, _)| match kind {
+                TokenKind::IncTape => '>',
+                TokenKind::DecTape => '<',
+                TokenKind::IncVal => '+',
+                TokenKind::DecVal => '-',
+                TokenKind::StartLoop => '[',
+                TokenKind::EndLoop => ']',
+                TokenKind::Write => '.',
+                TokenKind::Read => ',',
+                _ => '?',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn repetition() {
+        assert_eq!(kinds("+*3 >*0 -*1 .*2 ,*"), "+++-..,");
+    }
+
+    #[test]
+    fn calls() {
+        assert_eq!(kinds("#define nl ++++++++++.[-] print a newline\n@nl*2"), "++++++++++.[-]++++++++++.[-]");
+        assert_eq!(
+            kinds("#define right(n) >*n\n#define copy(n) [-@right(n)+<*n]\n@copy( 2 ) @right(1)"),
+            "[->>+<<]>"
+        );
+        assert_eq!(kinds("#define a(x, y) +*x-*y\n@a(2,1)@a(0, 3)"), "++----");
+        assert_eq!(kinds("#define a(x) +*x\n#define b(y) @a(y)*y\n@b(2)"), "++++");
+        assert_eq!(kinds("@ plain comment @"), "");
+    }
+
+    #[test]
+    fn expansion_positions() {
+        let res = expand("#define a <\n#define b -@a\n +@b").unwrap();
+        let call_b = Rc::new(Expansion {
+            name: "b".to_string(),
+            call: pos(3, 3),
+        });
+        let call_a = Rc::new(Expansion {
+            name: "a".to_string(),
+            call: InputPosition {
+                expansion: Some(call_b.clone()),
+                ..pos(2, 12)
+            },
+        });
+        assert_eq!(
+            res,
+            vec![
+                (TokenKind::IncVal, pos(3, 2)),
+                (
+                    TokenKind::DecVal,
+                    InputPosition {
+                        expansion: Some(call_b),
+                        ..pos(2, 11)
+                    }
+                ),
+                (
+                    TokenKind::DecTape,
+                    InputPosition {
+                        expansion: Some(call_a),
+                        ..pos(1, 11)
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            res[2].1.to_string(),
+            "1:11, in macro a called from 2:12, in macro b called from 3:3"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = |line, col, kind| Err(MacroError { pos: pos(line, col), kind });
+
+        assert_eq!(expand("@a"), err(1, 1, ErrorKind::Undefined("a".to_string())));
+        assert_eq!(expand("#define a(x)\n@a(1, 2)"), err(2, 1, ErrorKind::Arguments {
+            name: "a".to_string(),
+            expected: 1,
+            found: 2,
+        }));
+        assert_eq!(expand("#define a +\n#define a -\n"), err(2, 1, ErrorKind::Redefined("a".to_string())));
+        assert_eq!(expand("#define 1a\n"), err(1, 1, ErrorKind::MalformedDefinition));
+        assert_eq!(expand("#define a(x, x)\n"), err(1, 1, ErrorKind::MalformedDefinition));
+        assert_eq!(expand("#define a+\n"), err(1, 1, ErrorKind::MalformedDefinition));
+        assert_eq!(expand("#define a(x) +*y\n"), err(1, 15, ErrorKind::UnknownParameter("y".to_string())));
+        assert_eq!(expand("@a(1"), err(1, 1, ErrorKind::MalformedCall));
+        assert_eq!(expand("+*n"), err(1, 2, ErrorKind::UnknownParameter("n".to_string())));
+        assert_eq!(expand("+*99999999999999999999999."), err(1, 2, ErrorKind::MalformedCount));
+        assert_eq!(expand("@a(99999999999999999999999)"), err(1, 1, ErrorKind::MalformedCall));
+
+        assert_eq!(expand("+*4000000000."), err(1, 1, ErrorKind::TooLarge));
+        assert_eq!(expand("#define a(n) +*n\n@a(9999999999)"), Err(MacroError {
+            pos: InputPosition {
+                expansion: Some(Rc::new(Expansion {
+                    name: "a".to_string(),
+                    call: pos(2, 1),
+                })),
+                ..pos(1, 14)
+            },
+            kind: ErrorKind::TooLarge,
+        }));
+        assert_eq!(expand("#define e\n@e*9999999999"), err(2, 1, ErrorKind::TooLarge));
+        assert_eq!(kinds(&format!("+*{}", super::MAX_EXPANSION)).len(), super::MAX_EXPANSION);
+
+        let res = expand("#define a @b\n#define b +@a\n@a");
+        let call_a = Rc::new(Expansion {
+            name: "a".to_string(),
+            call: pos(3, 1),
+        });
+        let call_b = Rc::new(Expansion {
+            name: "b".to_string(),
+            call: InputPosition {
+                expansion: Some(call_a),
+                ..pos(1, 11)
+            },
+        });
+        assert_eq!(
+            res,
+            Err(MacroError {
+                pos: InputPosition {
+                    expansion: Some(call_b),
+                    ..pos(2, 12)
+                },
+                kind: ErrorKind::Recursive("a".to_string()),
+            })
+        );
+    }
+}
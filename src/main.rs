@@ -1,59 +1,880 @@
 #![allow(unused)]
 
 mod ast;
+mod bfc;
+mod bounds;
+mod bytecode;
+mod c;
+mod dialect;
+mod elf;
 mod exec;
+mod explain;
+mod format;
+mod generate;
+mod include;
+mod ir;
+mod js;
+mod lint;
+mod macros;
+mod minify;
+mod partial;
+mod python;
+mod repl;
+mod rust;
 mod token;
+mod translate;
+mod wasm;
+mod x86;
 
-use argparse::{ArgumentParser, IncrBy, Store};
+use argparse::{ArgumentParser, Collect, IncrBy, List, Store, StoreOption, StoreTrue};
 use std::fs::File;
-use std::io::Read;
+use std::io::{stderr, stdin, stdout, BufWriter, Read};
+use std::path::PathBuf;
+use std::str::FromStr;
 use inkwell::context::Context;
-use crate::exec::IrBuilder;
+use crate::dialect::Lexer;
+use crate::exec::{IrBuilder, Tape};
+use crate::token::Extensions;
 
-const LLVM_OUTPUT: &'static str = "out.ll";
-const COMPILER: &'static str = "llc";
+const LLVM_OUTPUT: &str = "out.ll";
+const BYTECODE_OUTPUT: &str = "out.bfc";
+const ASM_OUTPUT: &str = "out.s";
+const EXE_OUTPUT: &str = "a.out";
+const C_OUTPUT: &str = "out.c";
+const WASM_OUTPUT: &str = "out.wasm";
+const WAT_OUTPUT: &str = "out.wat";
+const RUST_OUTPUT: &str = "main.rs";
+const JS_OUTPUT: &str = "out.js";
+const PYTHON_OUTPUT: &str = "out.py";
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    Build,
+    Run,
+    Repl,
+    Explain,
+    GenPrint,
+    Translate,
+    Fmt,
+    Minify,
+    Lint,
+}
+
+const COMMANDS: &[(&str, Command)] = &[
+    ("build", Command::Build),
+    ("run", Command::Run),
+    ("repl", Command::Repl),
+    ("explain", Command::Explain),
+    ("gen-print", Command::GenPrint),
+    ("translate", Command::Translate),
+    ("fmt", Command::Fmt),
+    ("minify", Command::Minify),
+    ("lint", Command::Lint),
+];
+
+impl Command {
+    fn name(&self) -> &'static str {
+        COMMANDS.iter().find(|(_, c)| c == self).unwrap().0
+    }
+}
+
+impl FromStr for Command {
+    type Err = ();
+    fn from_str(src: &str) -> Result<Command, ()> {
+        match COMMANDS.iter().find(|(name, _)| *name == src) {
+            Some((_, c)) => Ok(*c),
+            None => Err(()),
+        }
+    }
+}
+
+/// Interpreter used by the run subcommand.
+#[derive(Debug)]
+enum Engine {
+    Tree,
+    Bytecode,
+}
+
+impl FromStr for Engine {
+    type Err = ();
+    fn from_str(src: &str) -> Result<Engine, ()> {
+        match src {
+            "tree" => Ok(Engine::Tree),
+            "bytecode" => Ok(Engine::Bytecode),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Output format of the build subcommand.
+#[derive(Debug)]
+enum Emit {
+    Exe,
+    Llvm,
+    Bytecode,
+    Asm,
+    C,
+    Wasm,
+    Wat,
+    Rust,
+    Js,
+    Python,
+}
+
+impl FromStr for Emit {
+    type Err = ();
+    fn from_str(src: &str) -> Result<Emit, ()> {
+        match src {
+            "exe" => Ok(Emit::Exe),
+            "llvm" => Ok(Emit::Llvm),
+            "bytecode" => Ok(Emit::Bytecode),
+            "asm" => Ok(Emit::Asm),
+            "c" => Ok(Emit::C),
+            "wasm" => Ok(Emit::Wasm),
+            "wat" => Ok(Emit::Wat),
+            "rust" => Ok(Emit::Rust),
+            "js" => Ok(Emit::Js),
+            "python" => Ok(Emit::Python),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A program ready for one of the interpreters.
+enum Loaded {
+    Tree(ast::AST),
+    Bytecode(bytecode::Program),
+}
 
 fn main() {
+    let mut verbose = 0;
+    let mut subcommand = Command::Build;
+    let mut args = vec![];
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("sateko brainfuck.");
+        ap.refer(&mut verbose)
+            .add_option(&["-d", "--debug"], IncrBy(1), "enable debug output");
+        ap.refer(&mut subcommand).required().add_argument(
+            "command",
+            Store,
+            r#"command to run ("build", "run", "repl", "explain", "gen-print", "translate", "fmt", "minify" or "lint")"#,
+        );
+        ap.refer(&mut args)
+            .add_argument("arguments", List, "arguments for command");
+        ap.stop_on_first_argument(true);
+        ap.parse_args_or_exit();
+    }
+
+    args.insert(0, format!("sateko {}", subcommand.name()));
+    match subcommand {
+        Command::Build => build_command(verbose, args),
+        Command::Run => run_command(verbose, args),
+        Command::Repl => repl_command(verbose, args),
+        Command::Explain => explain_command(args),
+        Command::GenPrint => gen_print_command(args),
+        Command::Translate => translate_command(args),
+        Command::Fmt => fmt_command(args),
+        Command::Minify => minify_command(args),
+        Command::Lint => lint_command(args),
+    }
+}
+
+fn parse_subcommand_args(ap: &ArgumentParser, args: Vec<String>) {
+    if let Err(code) = ap.parse(args, &mut stdout(), &mut stderr()) {
+        std::process::exit(code);
+    }
+}
+
+/// Exit as for a bad argument if `tape_len` leaves no cell to start on.
+fn check_tape_len(tape_len: usize) {
+    if tape_len == 0 {
+        println!("Tape length must be at least 1");
+        std::process::exit(2);
+    }
+}
+
+fn build_command(verbose: u8, args: Vec<String>) {
     let mut fname = String::new();
     let mut tape_len = 30_000;
-    let mut verbose = 0;
-    let mut compiler = String::new();
+    let mut emit = Emit::Exe;
+    let mut output = String::new();
+    let mut strip = false;
+    let mut ext = Extensions::default();
+    let mut include_dirs: Vec<PathBuf> = vec![];
+    let mut dialect = "bf".to_string();
+    let mut token_map = String::new();
+    let mut precompute_steps = partial::STEP_BUDGET;
 
     {
-        let mut args = ArgumentParser::new();
-        args.set_description("sateko brainfuck.");
-        args.refer(&mut fname)
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Compile a script to an executable, LLVM IR, bytecode, x86-64 assembly, C, WebAssembly, Rust, JavaScript or Python.");
+        ap.refer(&mut fname)
             .add_argument("FILE", Store, "path to script")
             .required();
-        args.refer(&mut tape_len).add_option(
+        ap.refer(&mut emit).add_option(
+            &["--emit"],
+            Store,
+            r#"output format ("exe", "llvm", "bytecode", "asm", "c", "wasm", "wat", "rust", "js" or "python")"#,
+        );
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store, "output path");
+        ap.refer(&mut strip).add_option(
+            &["--strip"],
+            StoreTrue,
+            "leave source positions out of bytecode",
+        );
+        ap.refer(&mut tape_len).add_option(
             &["-t", "--tape-length"],
             Store,
             "number of cells on tape",
         );
-        args.refer(&mut verbose)
-            .add_option(&["-d", "--debug"], IncrBy(1), "enable debug output");
-        args.refer(&mut compiler)
-            .add_option(&["-c", "--compiler"], Store, "llvm compiler path");
+        ap.refer(&mut precompute_steps).add_option(
+            &["--precompute-steps"],
+            Store,
+            "steps to run at compile time before the first ',' for exe and asm output, or 0 for none",
+        );
+        ap.refer(&mut ext.debug_hash).add_option(
+            &["--debug-hash"],
+            StoreTrue,
+            "treat '#' as a command that dumps the tape to stderr",
+        );
+        ap.refer(&mut ext.bang_input).add_option(
+            &["--bang-input"],
+            StoreTrue,
+            "use text after the first '!' as program input",
+        );
+        ap.refer(&mut ext.macros).add_option(
+            &["--macros"],
+            StoreTrue,
+            "expand #define macros and '*' repetition before parsing",
+        );
+        ap.refer(&mut dialect).add_option(
+            &["--dialect"],
+            Store,
+            r#"surface syntax of the script ("bf", "ook", "blub" or "alphuck")"#,
+        );
+        ap.refer(&mut token_map).add_option(
+            &["--token-map"],
+            Store,
+            "file giving the words for each command, in place of --dialect",
+        );
+        ap.refer(&mut include_dirs).add_option(
+            &["-I", "--include-dir"],
+            Collect,
+            "directory to search for included files",
+        );
+        ap.refer(&mut ext.procedures).add_option(
+            &["--procedures"],
+            StoreTrue,
+            "define procedures with '(' and ')' and call them with ':'",
+        );
+        parse_subcommand_args(&ap, args);
+    }
+    check_tape_len(tape_len as usize);
 
-        args.parse_args_or_exit();
+    let lexer = match load_lexer(&dialect, &token_map, &ext) {
+        Some(lexer) => lexer,
+        None => return,
+    };
+    let (ops, input, _) = match read_file(&fname).and_then(|raw| parse_source(&fname, &raw, &ext, &*lexer, &include_dirs)) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+
+    if ops.uses_procedures() && !matches!(emit, Emit::Llvm) {
+        println!("Procedures are only supported by --emit=llvm");
+        return;
     }
 
-    let mut raw = String::new();
-    let mut f = match File::open(&fname) {
-        Ok(f) => f,
+    let bounds = bounds::analyse(&ops, tape_len as usize);
+    for escape in &bounds.escapes {
+        println!("{}: {}", fname, escape);
+    }
+    if verbose > 0 {
+        println!("{}", bounds);
+    }
+
+    match emit {
+        Emit::Exe => build_exe(&ops, input, tape_len, &output, precompute_steps, verbose),
+        Emit::Llvm => build_llvm(&fname, &ops, input, tape_len, &output),
+        Emit::Bytecode => build_bytecode(&ops, input, tape_len, &output, !strip),
+        Emit::Asm => build_asm(&ops, input, tape_len, &output, precompute_steps, verbose),
+        Emit::C => build_source(c::generate, &ops, input, tape_len, &output, C_OUTPUT),
+        Emit::Wasm => build_wasm(&ops, input, tape_len, &output, false),
+        Emit::Wat => build_wasm(&ops, input, tape_len, &output, true),
+        Emit::Rust => build_source(rust::generate, &ops, input, tape_len, &output, RUST_OUTPUT),
+        Emit::Js => build_source(js::generate, &ops, input, tape_len, &output, JS_OUTPUT),
+        Emit::Python => build_source(python::generate, &ops, input, tape_len, &output, PYTHON_OUTPUT),
+    }
+}
+
+fn build_llvm(fname: &str, ops: &ast::AST, input: Option<Vec<u8>>, tape_len: u32, output: &str) {
+    let output = if output.is_empty() { LLVM_OUTPUT } else { output };
+    let context = Context::create();
+    let mut irbuilder = IrBuilder::create(&context, tape_len);
+    if let Some(input) = input {
+        irbuilder.set_input(&input);
+    }
+    irbuilder.build_from_ast(ops);
+    let module = irbuilder.get_module();
+    module.set_name(fname);
+    module.set_source_file_name(fname);
+    if let Err(e) = module.print_to_file(output) {
+        println!("Failed to generate LLVM IR: {}", e);
+    }
+}
+
+fn build_bytecode(ops: &ast::AST, input: Option<Vec<u8>>, tape_len: u32, output: &str, positions: bool) {
+    let output = if output.is_empty() { BYTECODE_OUTPUT } else { output };
+    let image = bfc::Image {
+        tape_len,
+        program: bytecode::Program::compile(&ir::from_ast(ops)),
+        input,
+    };
+
+    let res = File::create(output).and_then(|f| image.write_to(&mut BufWriter::new(f), positions));
+    if let Err(e) = res {
+        println!("Failed to write \"{}\": {}", output, e);
+    }
+}
+
+/// Lower `ops` for the x86-64 backend, after running up to `steps` of it.
+fn lower_x86(ops: &ast::AST, input: Option<Vec<u8>>, tape_len: u32, steps: usize, verbose: u8) -> Option<x86::Program> {
+    let prefix = partial::evaluate(ops, tape_len as usize, steps);
+    if verbose > 0 {
+        println!("Ran {} steps at compile time, writing {} bytes of output", prefix.steps, prefix.output.len());
+    }
+    match x86::Program::lower_prefix(&prefix, tape_len, input) {
+        Ok(prog) => Some(prog),
         Err(e) => {
-            println!("Failed to open \"{}\": {}", fname, e);
+            println!("Code generation failed: {}", e);
+            None
+        }
+    }
+}
+
+fn build_exe(ops: &ast::AST, input: Option<Vec<u8>>, tape_len: u32, output: &str, steps: usize, verbose: u8) {
+    let output = if output.is_empty() { EXE_OUTPUT } else { output };
+    let prog = match lower_x86(ops, input, tape_len, steps, verbose) {
+        Some(prog) => prog,
+        None => return,
+    };
+
+    if let Err(e) = write_executable(output, &elf::link(&prog)) {
+        println!("Failed to write \"{}\": {}", output, e);
+    }
+}
+
+#[cfg(unix)]
+fn write_executable(path: &str, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o755)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_executable(path: &str, data: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, data)
+}
+
+/// Write a program from one of the source code generators.
+fn build_source(
+    generate: fn(&[ir::Node], u32, Option<&[u8]>) -> String,
+    ops: &ast::AST,
+    input: Option<Vec<u8>>,
+    tape_len: u32,
+    output: &str,
+    default_output: &str,
+) {
+    let output = if output.is_empty() { default_output } else { output };
+    let src = generate(&ir::from_ast(ops), tape_len, input.as_deref());
+    if let Err(e) = std::fs::write(output, src) {
+        println!("Failed to write \"{}\": {}", output, e);
+    }
+}
+
+fn build_wasm(ops: &ast::AST, input: Option<Vec<u8>>, tape_len: u32, output: &str, text: bool) {
+    let module = wasm::Module::lower(&ir::from_ast(ops), tape_len, input);
+    let res = if text {
+        let output = if output.is_empty() { WAT_OUTPUT } else { output };
+        std::fs::write(output, module.to_wat()).map_err(|e| (output, e))
+    } else {
+        let output = if output.is_empty() { WASM_OUTPUT } else { output };
+        std::fs::write(output, module.encode()).map_err(|e| (output, e))
+    };
+    if let Err((output, e)) = res {
+        println!("Failed to write \"{}\": {}", output, e);
+    }
+}
+
+fn build_asm(ops: &ast::AST, input: Option<Vec<u8>>, tape_len: u32, output: &str, steps: usize, verbose: u8) {
+    let output = if output.is_empty() { ASM_OUTPUT } else { output };
+    let prog = match lower_x86(ops, input, tape_len, steps, verbose) {
+        Some(prog) => prog,
+        None => return,
+    };
+
+    if let Err(e) = std::fs::write(output, prog.to_asm()) {
+        println!("Failed to write \"{}\": {}", output, e);
+    }
+}
+
+fn run_command(verbose: u8, args: Vec<String>) {
+    let mut fname = String::new();
+    let mut tape_len: Option<usize> = None;
+    let mut engine = Engine::Tree;
+    let mut ext = Extensions::default();
+    let mut include_dirs: Vec<PathBuf> = vec![];
+    let mut dialect = "bf".to_string();
+    let mut token_map = String::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Interpret a script or compiled bytecode.");
+        ap.refer(&mut fname)
+            .add_argument("FILE", Store, "path to script or bytecode")
+            .required();
+        ap.refer(&mut tape_len).add_option(
+            &["-t", "--tape-length"],
+            StoreOption,
+            "number of cells on tape",
+        );
+        ap.refer(&mut engine).add_option(
+            &["-e", "--engine"],
+            Store,
+            r#"interpreter to use ("tree" or "bytecode")"#,
+        );
+        ap.refer(&mut ext.debug_hash).add_option(
+            &["--debug-hash"],
+            StoreTrue,
+            "treat '#' as a command that dumps the tape to stderr",
+        );
+        ap.refer(&mut ext.bang_input).add_option(
+            &["--bang-input"],
+            StoreTrue,
+            "use text after the first '!' as program input",
+        );
+        ap.refer(&mut ext.macros).add_option(
+            &["--macros"],
+            StoreTrue,
+            "expand #define macros and '*' repetition before parsing",
+        );
+        ap.refer(&mut dialect).add_option(
+            &["--dialect"],
+            Store,
+            r#"surface syntax of the script ("bf", "ook", "blub" or "alphuck")"#,
+        );
+        ap.refer(&mut token_map).add_option(
+            &["--token-map"],
+            Store,
+            "file giving the words for each command, in place of --dialect",
+        );
+        ap.refer(&mut include_dirs).add_option(
+            &["-I", "--include-dir"],
+            Collect,
+            "directory to search for included files",
+        );
+        ap.refer(&mut ext.procedures).add_option(
+            &["--procedures"],
+            StoreTrue,
+            "define procedures with '(' and ')' and call them with ':'",
+        );
+        parse_subcommand_args(&ap, args);
+    }
+    check_tape_len(tape_len.unwrap_or(1));
+
+    let raw = match read_file(&fname) {
+        Some(raw) => raw,
+        None => return,
+    };
+
+    // compiled bytecode skips parsing and always runs on the bytecode engine
+    let (program, input) = if bfc::is_bytecode(&raw) {
+        match bfc::Image::read_from(&mut &raw[..]) {
+            Ok(image) => {
+                tape_len = tape_len.or(Some(image.tape_len as usize));
+                (Loaded::Bytecode(image.program), image.input)
+            }
+            Err(e) => {
+                println!("Failed to load \"{}\": {}", fname, e);
+                return;
+            }
+        }
+    } else {
+        let lexer = match load_lexer(&dialect, &token_map, &ext) {
+            Some(lexer) => lexer,
+            None => return,
+        };
+        match parse_source(&fname, &raw, &ext, &*lexer, &include_dirs) {
+            Some((ops, input, _)) => match engine {
+                Engine::Tree => (Loaded::Tree(ops), input),
+                Engine::Bytecode if ops.uses_procedures() => {
+                    println!("Procedures are not supported by the bytecode engine");
+                    return;
+                }
+                Engine::Bytecode => {
+                    let prog = bytecode::Program::compile(&ir::from_ast(&ops));
+                    (Loaded::Bytecode(prog), input)
+                }
+            },
+            None => return,
+        }
+    };
+
+    let mut tape = Tape::with_size(tape_len.unwrap_or(30_000));
+    let stdin = stdin();
+    let mut input: Box<dyn Read> = match input {
+        Some(input) => Box::new(std::io::Cursor::new(input)),
+        None => Box::new(stdin.lock()),
+    };
+    let stdout = stdout();
+    let mut output = stdout.lock();
+    let res = match program {
+        Loaded::Tree(ops) => exec::run(&ops, &mut tape, &mut input, &mut output, verbose),
+        Loaded::Bytecode(prog) => prog.run(&mut tape, &mut input, &mut output),
+    };
+    if let Err(e) = res {
+        println!("Runtime error: {}", e);
+    }
+}
+
+fn repl_command(verbose: u8, args: Vec<String>) {
+    let mut tape_len = 30_000;
+    let mut ext = Extensions::default();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Interactively run brainfuck against a persistent tape.");
+        ap.refer(&mut tape_len).add_option(
+            &["-t", "--tape-length"],
+            Store,
+            "number of cells on tape",
+        );
+        ap.refer(&mut ext.debug_hash).add_option(
+            &["--debug-hash"],
+            StoreTrue,
+            "treat '#' as a command that dumps the tape to stderr",
+        );
+        parse_subcommand_args(&ap, args);
+    }
+    check_tape_len(tape_len);
+
+    let stdin = stdin();
+    let mut repl = repl::Repl::new(stdin.lock(), stdout(), tape_len, ext, verbose);
+    if let Err(e) = repl.run() {
+        println!("I/O failure: {}", e);
+    }
+}
+
+fn explain_command(args: Vec<String>) {
+    let mut fname = String::new();
+    let mut ext = Extensions::default();
+    let mut include_dirs: Vec<PathBuf> = vec![];
+    let mut dialect = "bf".to_string();
+    let mut token_map = String::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Print a script as annotated pseudo-code.");
+        ap.refer(&mut fname)
+            .add_argument("FILE", Store, "path to script")
+            .required();
+        ap.refer(&mut ext.debug_hash).add_option(
+            &["--debug-hash"],
+            StoreTrue,
+            "treat '#' as a command that dumps the tape to stderr",
+        );
+        ap.refer(&mut ext.bang_input).add_option(
+            &["--bang-input"],
+            StoreTrue,
+            "use text after the first '!' as program input",
+        );
+        ap.refer(&mut ext.macros).add_option(
+            &["--macros"],
+            StoreTrue,
+            "expand #define macros and '*' repetition before parsing",
+        );
+        ap.refer(&mut dialect).add_option(
+            &["--dialect"],
+            Store,
+            r#"surface syntax of the script ("bf", "ook", "blub" or "alphuck")"#,
+        );
+        ap.refer(&mut token_map).add_option(
+            &["--token-map"],
+            Store,
+            "file giving the words for each command, in place of --dialect",
+        );
+        ap.refer(&mut include_dirs).add_option(
+            &["-I", "--include-dir"],
+            Collect,
+            "directory to search for included files",
+        );
+        parse_subcommand_args(&ap, args);
+    }
+
+    let raw = match read_file(&fname) {
+        Some(raw) => raw,
+        None => return,
+    };
+    let lexer = match load_lexer(&dialect, &token_map, &ext) {
+        Some(lexer) => lexer,
+        None => return,
+    };
+    if let Some((ops, _, sources)) = parse_source(&fname, &raw, &ext, &*lexer, &include_dirs) {
+        print!("{}", explain::explain(&ir::from_ast(&ops), &sources));
+    }
+}
+
+fn gen_print_command(args: Vec<String>) {
+    let mut text = String::new();
+    let mut fname = String::new();
+    let mut output = String::new();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Generate a script which prints some text.");
+        ap.refer(&mut text)
+            .add_argument("TEXT", Store, "text to print");
+        ap.refer(&mut fname)
+            .add_option(&["-f", "--file"], Store, "print the contents of a file instead");
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store, "output path");
+        parse_subcommand_args(&ap, args);
+    }
+
+    let text = if fname.is_empty() {
+        text.into_bytes()
+    } else {
+        match read_file(&fname) {
+            Some(raw) => raw,
+            None => return,
+        }
+    };
+
+    let code = generate::print_text(&text);
+    if output.is_empty() {
+        println!("{}", code);
+    } else if let Err(e) = std::fs::write(&output, code + "\n") {
+        println!("Failed to write \"{}\": {}", output, e);
+    }
+}
+
+fn translate_command(args: Vec<String>) {
+    let mut fname = String::new();
+    let mut from = "bf".to_string();
+    let mut to = "bf".to_string();
+    let mut from_map = String::new();
+    let mut to_map = String::new();
+    let mut comments = false;
+    let mut output = String::new();
+    let mut ext = Extensions::default();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Rewrite a script in another dialect.");
+        ap.refer(&mut fname)
+            .add_argument("FILE", Store, "path to script")
+            .required();
+        ap.refer(&mut from).add_option(
+            &["--from"],
+            Store,
+            r#"dialect of the script ("bf", "ook", "blub" or "alphuck")"#,
+        );
+        ap.refer(&mut to).add_option(
+            &["--to"],
+            Store,
+            r#"dialect to write ("bf", "ook", "blub" or "alphuck")"#,
+        );
+        ap.refer(&mut from_map).add_option(
+            &["--from-map"],
+            Store,
+            "token map file of the script, in place of --from",
+        );
+        ap.refer(&mut to_map).add_option(
+            &["--to-map"],
+            Store,
+            "token map file to write, in place of --to",
+        );
+        ap.refer(&mut comments).add_option(
+            &["--comments"],
+            StoreTrue,
+            "keep the text between commands",
+        );
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store, "output path");
+        ap.refer(&mut ext.debug_hash).add_option(
+            &["--debug-hash"],
+            StoreTrue,
+            "treat '#' as a command that dumps the tape to stderr",
+        );
+        ap.refer(&mut ext.procedures).add_option(
+            &["--procedures"],
+            StoreTrue,
+            "define procedures with '(' and ')' and call them with ':'",
+        );
+        parse_subcommand_args(&ap, args);
+    }
+
+    let (from, to) = match (load_lexer(&from, &from_map, &ext), load_lexer(&to, &to_map, &ext)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return,
+    };
+    let raw = match read_file(&fname) {
+        Some(raw) => raw,
+        None => return,
+    };
+    let tokens = from.tokenize(&raw, None);
+    let ops = match ast::AST::from_tokens(&tokens) {
+        Ok(ops) => ops,
+        Err(e) => {
+            println!("Parse failed: {}", e);
             return;
         }
     };
-    if let Err(e) = f.read_to_string(&mut raw) {
-        println!("Failed to read \"{}\": {}", fname, e);
-        return;
+
+    let res = if comments {
+        translate::translate_with_comments(&tokens, &raw, &*to)
+    } else {
+        translate::translate(&ops, &*to)
+    };
+    let text = match res {
+        Ok(text) => text,
+        Err(e) => {
+            println!("Translation failed: {}", e);
+            return;
+        }
     };
+    if output.is_empty() {
+        use std::io::Write;
+        if let Err(e) = stdout().write_all(&text) {
+            println!("Failed to write output: {}", e);
+        }
+    } else if let Err(e) = std::fs::write(&output, text) {
+        println!("Failed to write \"{}\": {}", output, e);
+    }
+}
+
+fn fmt_command(args: Vec<String>) {
+    let mut fnames: Vec<String> = vec![];
+    let mut check = false;
+    let mut opts = format::Options::default();
+    let mut ext = Extensions::default();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Rewrite scripts in the canonical layout.");
+        ap.refer(&mut fnames)
+            .add_argument("FILE", List, "paths to scripts")
+            .required();
+        ap.refer(&mut check).add_option(
+            &["--check"],
+            StoreTrue,
+            "list scripts which are not formatted instead of rewriting them, and fail if there are any",
+        );
+        ap.refer(&mut opts.width).add_option(
+            &["-w", "--width"],
+            Store,
+            "longest line for runs of commands",
+        );
+        ap.refer(&mut opts.comment_column).add_option(
+            &["--comment-column"],
+            Store,
+            "column to align comments after commands at",
+        );
+        ap.refer(&mut ext.debug_hash).add_option(
+            &["--debug-hash"],
+            StoreTrue,
+            "treat '#' as a command that dumps the tape to stderr",
+        );
+        ap.refer(&mut ext.procedures).add_option(
+            &["--procedures"],
+            StoreTrue,
+            "define procedures with '(' and ')' and call them with ':'",
+        );
+        parse_subcommand_args(&ap, args);
+    }
+
+    let mut failed = false;
+    for fname in &fnames {
+        let raw = match read_file(fname).map(String::from_utf8) {
+            Some(Ok(raw)) => raw,
+            // rewriting it would replace the invalid bytes in its comments
+            Some(Err(_)) => {
+                println!("{} is not UTF-8, so it can't be formatted", fname);
+                failed = true;
+                continue;
+            }
+            None => {
+                failed = true;
+                continue;
+            }
+        };
+        let ops = match ast::AST::from_tokens_with_comments(&token::tokenize_with(&raw, &ext)) {
+            Ok(ops) => ops,
+            Err(e) => {
+                println!("Parse failed: {}: {}", fname, e);
+                failed = true;
+                continue;
+            }
+        };
 
-    let ts = token::tokenize(&raw);
-    let ops = match ast::AST::from_tokens(&ts) {
+        let formatted = format::format(&ops, &raw, &opts);
+        if formatted == raw {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", fname);
+            failed = true;
+        } else if let Err(e) = std::fs::write(fname, formatted) {
+            println!("Failed to write \"{}\": {}", fname, e);
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn minify_command(args: Vec<String>) {
+    let mut fname = String::new();
+    let mut output = String::new();
+    let mut ext = Extensions::default();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Print the smallest equivalent script, without comments or dead code.");
+        ap.refer(&mut fname)
+            .add_argument("FILE", Store, "path to script")
+            .required();
+        ap.refer(&mut output)
+            .add_option(&["-o", "--output"], Store, "output path");
+        ap.refer(&mut ext.debug_hash).add_option(
+            &["--debug-hash"],
+            StoreTrue,
+            "treat '#' as a command that dumps the tape to stderr",
+        );
+        ap.refer(&mut ext.procedures).add_option(
+            &["--procedures"],
+            StoreTrue,
+            "define procedures with '(' and ')' and call them with ':'",
+        );
+        parse_subcommand_args(&ap, args);
+    }
+
+    let raw = match read_file(&fname) {
+        Some(raw) => raw,
+        None => return,
+    };
+    let ops = match ast::AST::from_tokens(&token::tokenize_with(&raw, &ext)) {
         Ok(ops) => ops,
         Err(e) => {
             println!("Parse failed: {}", e);
@@ -61,22 +882,130 @@ fn main() {
         }
     };
 
-    let context = Context::create();
-    let mut irbuilder = IrBuilder::create(&context, tape_len);
-    irbuilder.build_from_ast(&ops);
-    let module = irbuilder.get_module();
-    module.set_name(&fname);
-    module.set_source_file_name(&fname);
-    if let Err(e) = module.print_to_file(LLVM_OUTPUT) {
-        println!("Failed to generate LLVM IR: {}", e);
-        return;
+    let code = minify::minify(&ops);
+    if output.is_empty() {
+        println!("{}", code);
+    } else if let Err(e) = std::fs::write(&output, code + "\n") {
+        println!("Failed to write \"{}\": {}", output, e);
+    }
+}
+
+fn lint_command(args: Vec<String>) {
+    let mut fname = String::new();
+    let mut allow: Vec<String> = vec![];
+    let mut ext = Extensions::default();
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Warn about common mistakes in a script.");
+        ap.refer(&mut fname)
+            .add_argument("FILE", Store, "path to script")
+            .required();
+        ap.refer(&mut allow).add_option(
+            &["-A", "--allow"],
+            Collect,
+            "code of a warning to leave out, e.g. L003",
+        );
+        ap.refer(&mut ext.debug_hash).add_option(
+            &["--debug-hash"],
+            StoreTrue,
+            "treat '#' as a command that dumps the tape to stderr",
+        );
+        ap.refer(&mut ext.procedures).add_option(
+            &["--procedures"],
+            StoreTrue,
+            "define procedures with '(' and ')' and call them with ':'",
+        );
+        parse_subcommand_args(&ap, args);
+    }
+
+    if let Some(code) = allow.iter().find(|code| !lint::LINTS.iter().any(|l| l.code() == *code)) {
+        println!("Unknown warning \"{}\"", code);
+        std::process::exit(2);
+    }
+    let raw = match read_file(&fname) {
+        Some(raw) => raw,
+        None => std::process::exit(2),
+    };
+    let ops = match ast::AST::from_tokens(&token::tokenize_with(&raw, &ext)) {
+        Ok(ops) => ops,
+        Err(e) => {
+            println!("Parse failed: {}", e);
+            std::process::exit(2);
+        }
     };
 
-    // out.ll -> out.s
-    let compiler = "llc";
-    std::process::Command::new(compiler)
-        .arg(LLVM_OUTPUT);
+    let warnings: Vec<lint::Warning> = lint::lint(&ops, &raw)
+        .into_iter()
+        .filter(|w| !allow.iter().any(|code| code == w.lint.code()))
+        .collect();
+    for warning in &warnings {
+        println!("{}: {}", fname, warning);
+    }
+    if !warnings.is_empty() {
+        std::process::exit(1);
+    }
+}
 
-    // out.s -> a.out
+fn read_file(fname: &str) -> Option<Vec<u8>> {
+    let mut raw = vec![];
+    let mut f = match File::open(fname) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("Failed to open \"{}\": {}", fname, e);
+            return None;
+        }
+    };
+    if let Err(e) = f.read_to_end(&mut raw) {
+        println!("Failed to read \"{}\": {}", fname, e);
+        return None;
+    };
+    Some(raw)
+}
+
+/// The lexer for the built-in `dialect`, or for the commands in the file
+/// `token_map` when one is given.
+fn load_lexer(dialect: &str, token_map: &str, ext: &Extensions) -> Option<Box<dyn Lexer>> {
+    if token_map.is_empty() {
+        let lexer = dialect::builtin(dialect, ext);
+        if lexer.is_none() {
+            println!("Unknown dialect \"{}\", expected one of {}", dialect, dialect::DIALECTS.join(", "));
+        }
+        return lexer;
+    }
+
+    let raw = read_file(token_map)?;
+    match dialect::Words::parse(&String::from_utf8_lossy(&raw)) {
+        Ok(words) => Some(Box::new(words)),
+        Err(e) => {
+            println!("Failed to load \"{}\": {}", token_map, e);
+            None
+        }
+    }
+}
 
+/// Parse the script `fname`, with text `raw`, returning its AST, any input
+/// embedded in the source and the text of the files it includes.
+fn parse_source(
+    fname: &str,
+    raw: &[u8],
+    ext: &Extensions,
+    lexer: &dyn Lexer,
+    include_dirs: &[PathBuf],
+) -> Option<(ast::AST, Option<Vec<u8>>, include::Sources)> {
+    let (code, input) = dialect::split_input(lexer, raw, ext);
+    let (ts, sources) = match include::tokenize(fname, code, ext, lexer, include_dirs) {
+        Ok(tokenized) => tokenized,
+        Err(e) => {
+            println!("Parse failed: {}", e);
+            return None;
+        }
+    };
+    match ast::AST::from_tokens(&ts) {
+        Ok(ops) => Some((ops, input.map(|i| i.to_vec()), sources)),
+        Err(e) => {
+            println!("Parse failed: {}", e);
+            None
+        }
+    }
 }
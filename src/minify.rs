@@ -0,0 +1,149 @@
+//! Smallest equivalent source for a script.
+//!
+//! Comments are dropped, and adjacent commands which undo each other, like
+//! `+-` or `<>`, are cancelled until none are left. A loop is removed when the
+//! current cell is known to be zero where it starts: at the start of the
+//! program, before anything but moves, and straight after another loop ends.
+//! Changes to the tape and procedure definitions after the last command with
+//! an effect outside the program are removed too. The result behaves the same
+//! as long as the original stays on the tape.
+use crate::ast::{ASTNode, ASTNodeKind, AST};
+
+enum Op {
+    Command(char),
+    Block(char, Vec<Op>, char),
+}
+
+/// The minified source of `ast`.
+pub fn minify(ast: &AST) -> String {
+    let mut ops = simplify(&ast.0, true, true);
+    while let Some(Op::Command('+' | '-' | '<' | '>') | Op::Block('(', _, _)) = ops.last() {
+        ops.pop();
+    }
+
+    let mut out = String::new();
+    write(&mut out, &ops);
+    out
+}
+
+/// Simplify the body `nodes`, which starts with the current cell zero when
+/// `zero` is set, and with every cell zero when `blank` is set.
+fn simplify(nodes: &[ASTNode], zero: bool, blank: bool) -> Vec<Op> {
+    let mut ret: Vec<Op> = vec![];
+    // whether the cell is known to be zero after each prefix of `ret`
+    let mut zeros = vec![zero];
+    for node in nodes {
+        let zero = *zeros.last().unwrap();
+        let c = match node.kind {
+            ASTNodeKind::Loop if zero => continue,
+            ASTNodeKind::Loop => {
+                ret.push(Op::Block('[', simplify(node.ops.as_ref().unwrap(), false, false), ']'));
+                zeros.push(true);
+                continue;
+            }
+            ASTNodeKind::Procedure => {
+                ret.push(Op::Block('(', simplify(node.ops.as_ref().unwrap(), false, false), ')'));
+                zeros.push(zero);
+                continue;
+            }
+            ASTNodeKind::Comment => continue,
+            ASTNodeKind::IncTape => '>',
+            ASTNodeKind::DecTape => '<',
+            ASTNodeKind::IncVal => '+',
+            ASTNodeKind::DecVal => '-',
+            ASTNodeKind::Read => ',',
+            ASTNodeKind::Write => '.',
+            ASTNodeKind::Debug => '#',
+            ASTNodeKind::Call => ':',
+        };
+
+        let undone = match ret.last() {
+            Some(Op::Command(last)) => matches!((last, c), ('+', '-') | ('-', '+') | ('<', '>') | ('>', '<')),
+            _ => false,
+        };
+        if undone {
+            ret.pop();
+            zeros.pop();
+        } else {
+            let moved = matches!(c, '<' | '>');
+            let blank = blank && ret.iter().all(|op| matches!(op, Op::Command('<' | '>')));
+            ret.push(Op::Command(c));
+            zeros.push(moved && blank);
+        }
+    }
+    ret
+}
+
+fn write(out: &mut String, ops: &[Op]) {
+    for op in ops {
+        match op {
+            Op::Command(c) => out.push(*c),
+            Op::Block(open, body, close) => {
+                out.push(*open);
+                write(out, body);
+                out.push(*close);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ast::AST;
+    use crate::exec::{self, Tape};
+    use crate::token::tokenize;
+
+    fn run(code: &str, input: &[u8]) -> Vec<u8> {
+        let ast = AST::from_tokens(&tokenize(code)).unwrap();
+        let mut tape = Tape::with_size(1_000);
+        let mut output = vec![];
+        exec::run(&ast, &mut tape, &mut &input[..], &mut output, 0).unwrap();
+        output
+    }
+
+    /// Minify `code`, checking it does the same for each of `inputs`.
+    fn minify(code: &str, inputs: &[&[u8]]) -> String {
+        let min = super::minify(&AST::from_tokens(&tokenize(code)).unwrap());
+        for input in inputs {
+            assert_eq!(run(&min, input), run(code, input), "{}", min);
+        }
+        min
+    }
+
+    #[test]
+    fn cancel() {
+        assert_eq!(minify("+ + - > < . a comment", &[]), "+.");
+        assert_eq!(minify("+>+<<>>-<-.", &[]), ".");
+        assert_eq!(minify("++[>+<-]+>++--<-.", &[]), "++[>+<-].");
+        assert_eq!(minify("+[-+]+-.", &[]), "+[].");
+    }
+
+    #[test]
+    fn dead_loops() {
+        assert_eq!(minify("[.,]>>[.]+[-][.]>[-].", &[]), ">>+[-]>[-].");
+        assert_eq!(minify("+-[.]+.", &[]), "+.");
+        assert_eq!(minify("+[->+<][>.<]+[-].", &[]), "+[->+<]+[-].");
+        assert_eq!(minify("-,+[-.,+]", &[b"abc"]), "-,+[-.,+]");
+    }
+
+    #[test]
+    fn dead_end() {
+        assert_eq!(minify("+.>>+++[-]<<", &[]), "+.>>+++[-]");
+        assert_eq!(minify(",+>", &[b"a"]), ",");
+        assert_eq!(minify("+++", &[]), "");
+    }
+
+    #[test]
+    fn samples() {
+        // everything but the comment loop at the start
+        let text = include_str!("../bf/hello.bf");
+        let code = &text[text.find("\n]\n").unwrap() + 2..];
+        let hello = minify(text, &[b""]);
+        assert_eq!(hello.len(), code.chars().filter(|c| "+-<>[].,".contains(*c)).count());
+
+        let inputs: &[&[u8]] = &[b"", b"Hello, World!", b"Uryyb, Jbeyq!\n"];
+        minify(include_str!("../bf/rot13.bf"), inputs);
+        minify(include_str!("../bf/io.bf"), inputs);
+        minify(include_str!("../bf/puta.bf"), inputs);
+    }
+}
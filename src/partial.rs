@@ -0,0 +1,171 @@
+//! Running the start of a program at compile time.
+//!
+//! Everything a program does before it first reads input is the same on every
+//! run, so the compiler can do it once. The program is interpreted until it
+//! reaches a `,`, a `#`, a procedure or a move off the tape, or runs out of
+//! steps, and what is left is cut out of the AST, resuming inside any loops it
+//! stopped in. The output and tape at that point are the starting state for
+//! the rest.
+use crate::ast::{ASTNode, ASTNodeKind, AST};
+
+/// Default number of steps to run for, which takes well under a second.
+pub const STEP_BUDGET: usize = 1_000_000;
+
+#[derive(Debug, PartialEq)]
+pub struct Prefix {
+    /// Output written so far.
+    pub output: Vec<u8>,
+    /// The tape, up to its last non-zero cell.
+    pub tape: Vec<u8>,
+    pub pos: usize,
+    /// The rest of the program, to run from the state above.
+    pub rest: AST,
+    /// Commands and loop tests run.
+    pub steps: usize,
+}
+
+/// A body being run, and the next command in it.
+struct Frame<'a> {
+    body: &'a [ASTNode],
+    next: usize,
+    /// The loop the body belongs to, or `None` at the top level.
+    node: Option<&'a ASTNode>,
+}
+
+/// Run `ast` on a tape of `tape_len` cells for at most `budget` steps.
+pub fn evaluate(ast: &AST, tape_len: usize, budget: usize) -> Prefix {
+    let mut tape = vec![0u8; tape_len];
+    let mut pos = 0;
+    let mut output = vec![];
+    let mut steps = 0;
+    let mut stack = vec![Frame {
+        body: &ast.0,
+        next: 0,
+        node: None,
+    }];
+
+    while steps < budget {
+        let frame = stack.last_mut().unwrap();
+        let body = frame.body;
+        let node = match body.get(frame.next) {
+            Some(node) => node,
+            None if frame.node.is_none() => break,
+            None => {
+                steps += 1;
+                if tape[pos] == 0 {
+                    stack.pop();
+                } else {
+                    frame.next = 0;
+                }
+                continue;
+            }
+        };
+
+        match node.kind {
+            ASTNodeKind::IncVal => tape[pos] = tape[pos].wrapping_add(1),
+            ASTNodeKind::DecVal => tape[pos] = tape[pos].wrapping_sub(1),
+            ASTNodeKind::IncTape if pos + 1 < tape_len => pos += 1,
+            ASTNodeKind::DecTape if pos > 0 => pos -= 1,
+            ASTNodeKind::Write => output.push(tape[pos]),
+            ASTNodeKind::Comment => {}
+            ASTNodeKind::Loop => {
+                frame.next += 1;
+                steps += 1;
+                if tape[pos] != 0 {
+                    stack.push(Frame {
+                        body: node.ops.as_ref().unwrap(),
+                        next: 0,
+                        node: Some(node),
+                    });
+                }
+                continue;
+            }
+            // left for run time, where errors are reported as usual
+            _ => break,
+        }
+        frame.next += 1;
+        steps += 1;
+    }
+
+    // finish each loop body, then test the loop again
+    let mut rest = vec![];
+    for frame in stack.iter().rev() {
+        rest.extend(frame.body[frame.next..].iter().cloned());
+        rest.extend(frame.node.cloned());
+    }
+
+    let used = tape.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1);
+    tape.truncate(used);
+    Prefix {
+        output,
+        tape,
+        pos,
+        rest: AST(rest),
+        steps,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{evaluate, Prefix, STEP_BUDGET};
+    use crate::ast::AST;
+    use crate::exec::{self, RuntimeError, Tape};
+    use crate::token::tokenize;
+
+    const TAPE_LEN: usize = 100;
+
+    fn run(ast: &AST, tape: &mut Tape, input: &[u8]) -> (Result<(), RuntimeError>, Vec<u8>) {
+        let mut output = vec![];
+        let res = exec::run(ast, tape, &mut &input[..], &mut output, 0);
+        (res, output)
+    }
+
+    /// Evaluate `code` for `budget` steps, checking that running the rest
+    /// from there does the same as the whole program for each of `inputs`.
+    fn evaluate_checked(code: &str, budget: usize, inputs: &[&[u8]]) -> Prefix {
+        let ast = AST::from_tokens(&tokenize(code)).unwrap();
+        let prefix = evaluate(&ast, TAPE_LEN, budget);
+        for input in inputs {
+            let mut tape = Tape::with_size(TAPE_LEN);
+            tape.cells[..prefix.tape.len()].copy_from_slice(&prefix.tape);
+            tape.pos = prefix.pos;
+            let (res, rest) = run(&prefix.rest, &mut tape, input);
+            let whole = run(&ast, &mut Tape::with_size(TAPE_LEN), input);
+            assert_eq!((res, [&prefix.output[..], &rest].concat()), whole, "{} steps", budget);
+        }
+        prefix
+    }
+
+    #[test]
+    fn hello() {
+        let prefix = evaluate_checked(include_str!("../bf/hello.bf"), STEP_BUDGET, &[b""]);
+        assert_eq!(prefix.output, b"Hello Sateko!\n");
+        assert!(prefix.rest.0.is_empty());
+        assert_eq!(prefix.tape, [0, 0, 72, 111, 83, 33, 10]);
+    }
+
+    #[test]
+    fn stops_at_read() {
+        let prefix = evaluate_checked("++.>+++[<+.>-,+]<.", STEP_BUDGET, &[b"", b"\x00", b"\x01\xff"]);
+        assert_eq!(prefix.output, [2, 3]);
+        assert_eq!((prefix.tape, prefix.pos), (vec![3, 2], 1));
+        assert_eq!(prefix.rest.0.len(), 5);
+    }
+
+    #[test]
+    fn budget() {
+        let code = "+++[>++[>+.<-]<-]>>.,.";
+        for budget in 0..60 {
+            evaluate_checked(code, budget, &[b"x"]);
+        }
+        let prefix = evaluate_checked(code, 0, &[]);
+        assert_eq!((prefix.steps, prefix.rest.0.len()), (0, 9));
+    }
+
+    #[test]
+    fn errors_left() {
+        let prefix = evaluate_checked("+.<+", STEP_BUDGET, &[b""]);
+        assert_eq!((prefix.output, prefix.rest.0.len()), (vec![1], 2));
+        evaluate_checked("+[>+]", STEP_BUDGET, &[b""]);
+    }
+}
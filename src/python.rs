@@ -0,0 +1,189 @@
+//! Python code generation.
+//!
+//! The output is a Python 3 script with no dependencies. Input comes from the
+//! embedded input when there is some, otherwise from the file named by the
+//! first argument or stdin, and is read in full before the program starts.
+//! Cells are masked to a byte after each change, and `,` stores 255 at end of
+//! input. Moving off the tape is not checked, and moving left of it wraps
+//! around to the end as Python indexing does.
+use crate::exec::DEBUG_WINDOW;
+use crate::ir::{self, Node, NodeKind};
+use std::fmt::{self, Write};
+
+/// A Python 3 script running `ir`, which takes its input from `input` instead
+/// of stdin or a file when that is given.
+pub fn generate(ir: &[Node], tape_len: u32, input: Option<&[u8]>) -> String {
+    let mut out = String::new();
+    write_program(&mut out, ir, tape_len, input).unwrap();
+    out
+}
+
+fn write_program(out: &mut String, ir: &[Node], tape_len: u32, input: Option<&[u8]>) -> fmt::Result {
+    let reads = ir::contains(ir, &|kind| *kind == NodeKind::Read);
+    let debug = ir::contains(ir, &|kind| *kind == NodeKind::Debug);
+
+    writeln!(out, "import sys")?;
+    writeln!(out)?;
+    writeln!(out, "TAPE_LEN = {}", tape_len)?;
+    if let (Some(input), true) = (input, reads) {
+        writeln!(out, "INPUT = b\"{}\"", ir::escape_bytes(input))?;
+    }
+
+    if debug {
+        write_debug(out, tape_len)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out)?;
+    writeln!(out, "def main():")?;
+    writeln!(out, "    tape = bytearray(TAPE_LEN)")?;
+    writeln!(out, "    p = 0")?;
+    if reads {
+        match input {
+            Some(_) => writeln!(out, "    data = INPUT")?,
+            None => {
+                writeln!(out, "    if len(sys.argv) > 1:")?;
+                writeln!(out, "        with open(sys.argv[1], \"rb\") as f:")?;
+                writeln!(out, "            data = f.read()")?;
+                writeln!(out, "    else:")?;
+                writeln!(out, "        data = sys.stdin.buffer.read()")?;
+            }
+        }
+        writeln!(out, "    data = iter(data)")?;
+    }
+    writeln!(out, "    out = sys.stdout.buffer")?;
+    write_nodes(out, ir, 1)?;
+    writeln!(out, "    out.flush()")?;
+    writeln!(out)?;
+    writeln!(out)?;
+    writeln!(out, "main()")
+}
+
+/// Emit `debug`, printing the cells around `p` as the interpreter's `#` does.
+fn write_debug(out: &mut String, tape_len: u32) -> fmt::Result {
+    let window = (DEBUG_WINDOW as u32).min(tape_len);
+    writeln!(out)?;
+    writeln!(out)?;
+    writeln!(out, "def debug(tape, p, file, line, col):")?;
+    writeln!(out, "    sys.stdout.buffer.flush()")?;
+    writeln!(out, "    start = min(max(p - {}, 0), {})", DEBUG_WINDOW / 2, tape_len - window)?;
+    writeln!(out, "    dump = \"[%s%d,%d] ptr %d:\" % (file, line, col, p)")?;
+    writeln!(out, "    for i in range(start, start + {}):", window)?;
+    writeln!(out, "        dump += \" [%d]\" % tape[i] if i == p else \" %d\" % tape[i]")?;
+    writeln!(out, "    sys.stderr.write(dump + \"\\n\")")
+}
+
+fn write_nodes(out: &mut String, ir: &[Node], depth: usize) -> fmt::Result {
+    let indent = "    ".repeat(depth);
+
+    if ir.is_empty() {
+        return writeln!(out, "{}pass", indent);
+    }
+
+    for node in ir {
+        match &node.kind {
+            NodeKind::Add(n) => writeln!(out, "{}tape[p] = (tape[p] + {}) & 255", indent, n)?,
+            NodeKind::Move(n) if *n < 0 => writeln!(out, "{}p -= {}", indent, -n)?,
+            NodeKind::Move(n) => writeln!(out, "{}p += {}", indent, n)?,
+            NodeKind::Clear => writeln!(out, "{}tape[p] = 0", indent)?,
+            NodeKind::Transfer(targets) => {
+                // a loop at zero never ran, so this mustn't index past the tape either
+                writeln!(out, "{}if tape[p]:", indent)?;
+                let inner = format!("{}    ", indent);
+                for &(offset, factor) in targets {
+                    let cell = match offset {
+                        o if o < 0 => format!("tape[p - {}]", -o),
+                        o => format!("tape[p + {}]", o),
+                    };
+                    if factor == 1 {
+                        writeln!(out, "{}{} = ({} + tape[p]) & 255", inner, cell, cell)?;
+                    } else {
+                        writeln!(out, "{}{} = ({} + tape[p] * {}) & 255", inner, cell, cell, factor)?;
+                    }
+                }
+                writeln!(out, "{}tape[p] = 0", inner)?;
+            }
+            NodeKind::Read => writeln!(out, "{}tape[p] = next(data, 255)", indent)?,
+            NodeKind::Write => writeln!(out, "{}out.write(tape[p:p + 1])", indent)?,
+            NodeKind::Debug => {
+                let file = ir::quote(&node.pos.file_prefix());
+                writeln!(out, "{}debug(tape, p, {}, {}, {})", indent, file, node.pos.line, node.pos.pos)?
+            }
+            NodeKind::Loop(body) => {
+                writeln!(out, "{}while tape[p]:", indent)?;
+                write_nodes(out, body, depth + 1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ir;
+
+    fn generate(raw: &str, input: Option<&[u8]>) -> String {
+        super::generate(&ir::parse(raw), 300, input)
+    }
+
+    #[test]
+    fn statements() {
+        let src = generate(">>+++<--<[->+<<---<>>]>[<.][]", None);
+        assert_eq!(
+            src,
+            "import sys
+
+TAPE_LEN = 300
+
+
+def main():
+    tape = bytearray(TAPE_LEN)
+    p = 0
+    out = sys.stdout.buffer
+    p += 2
+    tape[p] = (tape[p] + 3) & 255
+    p -= 1
+    tape[p] = (tape[p] + 254) & 255
+    p -= 1
+    if tape[p]:
+        tape[p + 1] = (tape[p + 1] + tape[p]) & 255
+        tape[p - 1] = (tape[p - 1] + tape[p] * 253) & 255
+        tape[p] = 0
+    p += 1
+    while tape[p]:
+        p -= 1
+        out.write(tape[p:p + 1])
+    while tape[p]:
+        pass
+    out.flush()
+
+
+main()
+"
+        );
+    }
+
+    #[test]
+    fn input() {
+        let src = generate(",", None);
+        assert!(src.contains("        data = sys.stdin.buffer.read()\n    data = iter(data)\n"));
+        assert!(src.contains("    tape[p] = next(data, 255)\n"));
+
+        let src = generate(",", Some(b"a\"\n"));
+        assert!(src.contains("INPUT = b\"a\\\"\\n\"\n"));
+        assert!(src.contains("    data = INPUT\n"));
+        assert!(!src.contains("stdin"));
+    }
+
+    #[test]
+    fn debug() {
+        let src = generate("+\n #", None);
+        assert!(src.contains("def debug(tape, p, file, line, col):"));
+        assert!(src.contains("    debug(tape, p, \"\", 2, 2)\n"));
+
+        let mut ir = ir::parse("#");
+        ir[0].pos.file = Some("lib/\u{e9}.bf".into());
+        let src = super::generate(&ir, 300, None);
+        assert!(src.contains("    debug(tape, p, \"lib/\u{e9}.bf:\", 1, 1)\n"));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::ast::{ErrorKind, AST};
+use crate::exec::{self, Tape};
+use crate::token::{self, Extensions};
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const PROMPT: &str = "bf> ";
+const CONTINUE_PROMPT: &str = "... ";
+
+const HELP: &str = "\
+Enter brainfuck to run it against the tape. Lines are collected until all
+loops are closed.
+
+  :tape        show the tape around the current position
+  :reset       clear the tape and return to the first cell
+  :load FILE   run a script against the tape
+  :help        show this message
+  :quit        exit
+";
+
+/// An interactive session which runs each entry against a persistent tape.
+///
+/// `,` reads from the same stream as the session itself, so a program can
+/// consume the lines typed after it.
+pub struct Repl<R, W> {
+    input: R,
+    output: Tracked<W>,
+    tape: Tape,
+    ext: Extensions,
+    verb: u8,
+}
+
+impl<R: BufRead, W: Write> Repl<R, W> {
+    pub fn new(input: R, output: W, tape_len: usize, ext: Extensions, verb: u8) -> Self {
+        Repl {
+            input,
+            output: Tracked {
+                inner: output,
+                last: None,
+            },
+            tape: Tape::with_size(tape_len),
+            ext,
+            verb,
+        }
+    }
+
+    /// Read and run entries until end of input or `:quit`.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut pending = String::new();
+
+        loop {
+            let prompt = if pending.is_empty() { PROMPT } else { CONTINUE_PROMPT };
+            write!(self.output, "{}", prompt)?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                if !pending.is_empty() {
+                    self.eval(&pending)?;
+                }
+                writeln!(self.output)?;
+                return Ok(());
+            }
+
+            if pending.is_empty() && line.trim_start().starts_with(':') {
+                if !self.command(line.trim())? {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            pending.push_str(&line);
+            if pending.trim().is_empty() {
+                pending.clear();
+            } else if !self.needs_more(&pending) {
+                self.eval(&pending)?;
+                pending.clear();
+            }
+        }
+    }
+
+    fn needs_more(&self, src: &str) -> bool {
+        let ts = token::tokenize_with(src, &self.ext);
+        match AST::from_tokens(&ts) {
+            Err(e) => e.kind == ErrorKind::UnclosedLoop,
+            Ok(_) => false,
+        }
+    }
+
+    /// Handle a `:` command, returning false when the session should end.
+    fn command(&mut self, line: &str) -> io::Result<bool> {
+        let mut words = line.splitn(2, char::is_whitespace);
+        let name = words.next().unwrap_or("");
+        let arg = words.next().map(str::trim).unwrap_or("");
+
+        match name {
+            ":quit" | ":q" => return Ok(false),
+            ":help" | ":h" => write!(self.output, "{}", HELP)?,
+            ":tape" | ":t" => writeln!(self.output, "{}", self.tape.dump())?,
+            ":reset" | ":r" => {
+                self.tape = Tape::with_size(self.tape.cells.len());
+                writeln!(self.output, "{}", self.tape.dump())?;
+            }
+            ":load" | ":l" if !arg.is_empty() => match fs::read(arg) {
+                Ok(src) => self.eval(&src)?,
+                Err(e) => writeln!(self.output, "Failed to read \"{}\": {}", arg, e)?,
+            },
+            _ => writeln!(self.output, "Unknown command \"{}\", try :help", line)?,
+        }
+
+        Ok(true)
+    }
+
+    /// Parse and run `src`, then show the tape.
+    fn eval<S: AsRef<[u8]> + ?Sized>(&mut self, src: &S) -> io::Result<()> {
+        let ts = token::tokenize_with(src, &self.ext);
+        let ast = match AST::from_tokens(&ts) {
+            Ok(ast) => ast,
+            Err(e) => return writeln!(self.output, "Parse failed: {}", e),
+        };
+
+        self.output.last = None;
+        let res = exec::run(&ast, &mut self.tape, &mut self.input, &mut self.output, self.verb);
+        if matches!(self.output.last, Some(b) if b != b'\n') {
+            writeln!(self.output)?;
+        }
+        if let Err(e) = res {
+            writeln!(self.output, "Runtime error: {}", e)?;
+        }
+        writeln!(self.output, "{}", self.tape.dump())
+    }
+}
+
+/// Writer which remembers the last byte written, so the tape view can start
+/// on a fresh line after program output.
+struct Tracked<W> {
+    inner: W,
+    last: Option<u8>,
+}
+
+impl<W: Write> Write for Tracked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.last = Some(buf[n - 1]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Repl;
+    use crate::token::Extensions;
+
+    fn session(input: &str) -> String {
+        let mut output = vec![];
+        Repl::new(input.as_bytes(), &mut output, 4, Extensions::default(), 0)
+            .run()
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn persistent_tape() {
+        let out = session("++>+\n<+\n");
+        assert_eq!(out, "bf> ptr 1: 2 [1] 0 0\nbf> ptr 0: [3] 1 0 0\nbf> \n");
+    }
+
+    #[test]
+    fn multi_line_loop() {
+        let out = session("++[\n>+<-\n]\n");
+        assert_eq!(out, "bf> ... ... ptr 0: [0] 2 0 0\nbf> \n");
+    }
+
+    #[test]
+    fn output_and_input() {
+        let out = session(",.\nA\n");
+        assert_eq!(out, "bf> A\nptr 0: [65] 0 0 0\nbf> bf> \n");
+    }
+
+    #[test]
+    fn commands() {
+        let out = session("+\n:tape\n:reset\n:bogus\n:quit\n+\n");
+        assert_eq!(
+            out,
+            "bf> ptr 0: [1] 0 0 0\n\
+             bf> ptr 0: [1] 0 0 0\n\
+             bf> ptr 0: [0] 0 0 0\n\
+             bf> Unknown command \":bogus\", try :help\n\
+             bf> "
+        );
+    }
+
+    #[test]
+    fn errors_keep_tape() {
+        let out = session("+]\n+<\n");
+        assert_eq!(
+            out,
+            "bf> Parse failed: Unopened loop (1:2)\n\
+             bf> Runtime error: Tried to move past tape beginning (1:2)\n\
+             ptr 0: [1] 0 0 0\n\
+             bf> \n"
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! Rust code generation.
+//!
+//! The output is a standalone `main.rs` with no dependencies. Cells are `u8`
+//! with wrapping arithmetic, `,` stores 255 at end of file, and moving off
+//! the tape panics.
+use crate::exec::DEBUG_WINDOW;
+use crate::ir::{self, Node, NodeKind};
+use std::fmt::{self, Write};
+
+/// Write out a `main.rs` which runs `ir`, reading the compiled-in `input`
+/// rather than stdin if there is one.
+pub fn generate(ir: &[Node], tape_len: u32, input: Option<&[u8]>) -> String {
+    let mut out = String::new();
+    write_program(&mut out, ir, tape_len, input).unwrap();
+    out
+}
+
+fn write_program(out: &mut String, ir: &[Node], tape_len: u32, input: Option<&[u8]>) -> fmt::Result {
+    let reads = ir::contains(ir, &|kind| *kind == NodeKind::Read);
+    let writes = ir::contains(ir, &|kind| *kind == NodeKind::Write);
+    let debug = ir::contains(ir, &|kind| *kind == NodeKind::Debug);
+    let moves = ir::contains(ir, &|kind| matches!(kind, NodeKind::Move(_)));
+
+    if reads {
+        writeln!(out, "use std::io::{{self, Read, Write}};")?;
+        writeln!(out)?;
+    } else if writes || debug {
+        writeln!(out, "use std::io::{{self, Write}};")?;
+        writeln!(out)?;
+    }
+    writeln!(out, "const TAPE_LEN: usize = {};", tape_len)?;
+    if let (Some(input), true) = (input, reads) {
+        writeln!(out, "const INPUT: &[u8] = b\"{}\";", ir::escape_bytes(input))?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "fn main() {{")?;
+    writeln!(out, "    let mut tape = vec![0u8; TAPE_LEN];")?;
+    if moves {
+        writeln!(out, "    let mut p: usize = 0;")?;
+    } else {
+        writeln!(out, "    let p: usize = 0;")?;
+    }
+    if reads {
+        match input {
+            Some(_) => writeln!(out, "    let mut input = INPUT;")?,
+            None => {
+                writeln!(out, "    let stdin = io::stdin();")?;
+                writeln!(out, "    let mut input = stdin.lock();")?;
+            }
+        }
+    }
+    if reads || writes || debug {
+        writeln!(out, "    let stdout = io::stdout();")?;
+        writeln!(out, "    let mut output = io::BufWriter::new(stdout.lock());")?;
+    }
+    write_nodes(out, ir, 1)?;
+    if reads || writes || debug {
+        writeln!(out, "    output.flush().unwrap();")?;
+    }
+    writeln!(out, "}}")?;
+
+    if reads {
+        writeln!(out)?;
+        writeln!(out, "fn read(input: &mut impl Read, output: &mut impl Write) -> u8 {{")?;
+        writeln!(out, "    output.flush().unwrap();")?;
+        writeln!(out, "    let mut buf = [0];")?;
+        writeln!(out, "    match input.read(&mut buf) {{")?;
+        writeln!(out, "        Ok(1) => buf[0],")?;
+        writeln!(out, "        _ => 255,")?;
+        writeln!(out, "    }}")?;
+        writeln!(out, "}}")?;
+    }
+    if debug {
+        write_debug(out, tape_len)?;
+    }
+    Ok(())
+}
+
+/// A `debug` function printing the same window as `Tape::dump`, after
+/// flushing the output passed to it.
+fn write_debug(out: &mut String, tape_len: u32) -> fmt::Result {
+    let window = (DEBUG_WINDOW as u32).min(tape_len);
+    writeln!(out)?;
+    writeln!(
+        out,
+        "fn debug(tape: &[u8], p: usize, file: &str, line: u32, col: u32, output: &mut impl Write) {{"
+    )?;
+    writeln!(out, "    output.flush().unwrap();")?;
+    writeln!(out, "    let start = p.saturating_sub({}).min({});", DEBUG_WINDOW / 2, tape_len - window)?;
+    writeln!(out, "    let mut dump = format!(\"[{{}}{{}},{{}}] ptr {{}}:\", file, line, col, p);")?;
+    writeln!(out, "    for (i, val) in tape[start..start + {}].iter().enumerate() {{", window)?;
+    writeln!(out, "        if start + i == p {{")?;
+    writeln!(out, "            dump.push_str(&format!(\" [{{}}]\", val));")?;
+    writeln!(out, "        }} else {{")?;
+    writeln!(out, "            dump.push_str(&format!(\" {{}}\", val));")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    eprintln!(\"{{}}\", dump);")?;
+    writeln!(out, "}}")
+}
+
+fn write_nodes(out: &mut String, ir: &[Node], depth: usize) -> fmt::Result {
+    let indent = "    ".repeat(depth);
+
+    for node in ir {
+        match &node.kind {
+            NodeKind::Add(n) => writeln!(out, "{}tape[p] = tape[p].wrapping_add({});", indent, n)?,
+            NodeKind::Move(n) if *n < 0 => writeln!(out, "{}p -= {};", indent, -n)?,
+            NodeKind::Move(n) => writeln!(out, "{}p += {};", indent, n)?,
+            NodeKind::Clear => writeln!(out, "{}tape[p] = 0;", indent)?,
+            NodeKind::Transfer(targets) => {
+                // the loop this came from never moves off a zero cell
+                writeln!(out, "{}if tape[p] != 0 {{", indent)?;
+                for &(offset, factor) in targets {
+                    // `p - n` fails to compile where `p` is known to be 0
+                    let cell = match offset {
+                        o if o < 0 => format!("tape[p.wrapping_sub({})]", -o),
+                        o => format!("tape[p + {}]", o),
+                    };
+                    if factor == 1 {
+                        writeln!(out, "{}    {} = {}.wrapping_add(tape[p]);", indent, cell, cell)?;
+                    } else {
+                        writeln!(
+                            out,
+                            "{}    {} = {}.wrapping_add(tape[p].wrapping_mul({}));",
+                            indent, cell, cell, factor
+                        )?;
+                    }
+                }
+                writeln!(out, "{}    tape[p] = 0;", indent)?;
+                writeln!(out, "{}}}", indent)?;
+            }
+            NodeKind::Read => writeln!(out, "{}tape[p] = read(&mut input, &mut output);", indent)?,
+            NodeKind::Write => writeln!(out, "{}output.write_all(&[tape[p]]).unwrap();", indent)?,
+            NodeKind::Debug => writeln!(
+                out,
+                "{}debug(&tape, p, {:?}, {}, {}, &mut output);",
+                indent,
+                node.pos.file_prefix(),
+                node.pos.line,
+                node.pos.pos
+            )?,
+            NodeKind::Loop(body) => {
+                writeln!(out, "{}while tape[p] != 0 {{", indent)?;
+                write_nodes(out, body, depth + 1)?;
+                writeln!(out, "{}}}", indent)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ast::AST;
+    use crate::ir;
+    use crate::token::tokenize;
+    use std::process::Command;
+
+    fn generate(raw: &str, input: Option<&[u8]>) -> String {
+        super::generate(&ir::parse(raw), 300, input)
+    }
+
+    #[test]
+    fn statements() {
+        let src = generate(">>+++<--<[->+<<---<>>]>[<]", None);
+        assert_eq!(
+            src,
+            "const TAPE_LEN: usize = 300;
+
+fn main() {
+    let mut tape = vec![0u8; TAPE_LEN];
+    let mut p: usize = 0;
+    p += 2;
+    tape[p] = tape[p].wrapping_add(3);
+    p -= 1;
+    tape[p] = tape[p].wrapping_add(254);
+    p -= 1;
+    if tape[p] != 0 {
+        tape[p + 1] = tape[p + 1].wrapping_add(tape[p]);
+        tape[p.wrapping_sub(1)] = tape[p.wrapping_sub(1)].wrapping_add(tape[p].wrapping_mul(253));
+        tape[p] = 0;
+    }
+    p += 1;
+    while tape[p] != 0 {
+        p -= 1;
+    }
+}
+"
+        );
+    }
+
+    /// Compile `src` with rustc and run it, returning what it writes.
+    fn compile_and_run(src: &str, name: &str) -> Vec<u8> {
+        let dir = std::env::temp_dir().join(format!("sateko-rust-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.rs"), src).unwrap();
+        let status = Command::new("rustc")
+            .arg("main.rs")
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{}", src);
+        let output = Command::new(dir.join("main")).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(output.status.success(), "{}", src);
+        output.stdout
+    }
+
+    #[test]
+    fn transfer_edges() {
+        // transfers off either end of the tape which never run
+        let ast = AST::from_tokens(&tokenize("[-<+>]+.")).unwrap();
+        let src = super::generate(&ir::from_ast(&ast), 2, None);
+        assert_eq!(compile_and_run(&src, "start"), [1]);
+
+        let ast = AST::from_tokens(&tokenize(">[->+<]+.")).unwrap();
+        let src = super::generate(&ir::from_ast(&ast), 2, None);
+        assert_eq!(compile_and_run(&src, "end"), [1]);
+    }
+
+    #[test]
+    fn io() {
+        let src = generate(",.", None);
+        assert!(src.starts_with("use std::io::{self, Read, Write};\n"));
+        assert!(src.contains("    let mut input = stdin.lock();\n"));
+        assert!(src.contains("    tape[p] = read(&mut input, &mut output);\n"));
+        assert!(src.contains("    output.write_all(&[tape[p]]).unwrap();\n"));
+        assert!(src.contains("fn read(input: &mut impl Read, output: &mut impl Write) -> u8 {"));
+
+        let src = generate(",.", Some(b"a\"\n"));
+        assert!(src.contains("const INPUT: &[u8] = b\"a\\\"\\n\";\n"));
+        assert!(src.contains("    let mut input = INPUT;\n"));
+        assert!(!src.contains("stdin"));
+
+        let src = generate(".", Some(b"unused"));
+        assert!(src.starts_with("use std::io::{self, Write};\n"));
+        assert!(src.contains("    let p: usize = 0;\n"));
+        assert!(!src.contains("INPUT"));
+        assert!(!src.contains("fn read"));
+    }
+
+    #[test]
+    fn debug() {
+        let src = generate("+\n #", None);
+        assert!(src.contains("    debug(&tape, p, \"\", 2, 2, &mut output);\n"));
+        assert!(src.contains("    let start = p.saturating_sub(5).min(290);\n"));
+
+        let mut ir = ir::parse("#");
+        ir[0].pos.file = Some("lib/\"a\".bf".into());
+        let src = super::generate(&ir, 300, None);
+        assert!(src.contains("    debug(&tape, p, \"lib/\\\"a\\\".bf:\", 1, 1, &mut output);\n"));
+    }
+}
@@ -1,13 +1,82 @@
+use std::fmt;
+use std::rc::Rc;
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Token {
     pub kind: TokenKind,
     pub pos: InputPosition,
+    pub span: Span,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct InputPosition {
+    /// Path of the included file the position is in, or `None` for the
+    /// script itself.
+    pub file: Option<Rc<str>>,
     pub line: usize,
     pub pos: usize,
+    /// The macro call this came from, for commands in a macro body.
+    pub expansion: Option<Rc<Expansion>>,
+}
+
+/// A call of a macro, which produced the positions of its expansion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expansion {
+    pub name: String,
+    pub call: InputPosition,
+}
+
+impl InputPosition {
+    /// The position reached by stepping over `text` from this one, counting
+    /// lines and columns the same way as `tokenize_with`.
+    pub fn advance(&self, text: &[u8]) -> InputPosition {
+        let (mut line, mut pos) = (self.line, self.pos);
+        for chunk in text.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                if c == '\n' {
+                    line += 1;
+                    pos = 1;
+                } else {
+                    pos += 1;
+                }
+            }
+            pos += chunk.invalid().len();
+        }
+        InputPosition {
+            file: self.file.clone(),
+            line,
+            pos,
+            expansion: self.expansion.clone(),
+        }
+    }
+
+    /// `path:` for positions in included files, to go in front of the line
+    /// and column in messages.
+    pub fn file_prefix(&self) -> String {
+        match &self.file {
+            Some(file) => format!("{}:", file),
+            None => String::new(),
+        }
+    }
+}
+
+/// `path:line:col` for use in messages, followed by the macro calls it was
+/// expanded from, innermost first.
+impl fmt::Display for InputPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}:{}", self.file_prefix(), self.line, self.pos)?;
+        if let Some(expansion) = &self.expansion {
+            write!(f, ", in macro {} called from {}", expansion.name, expansion.call)?;
+        }
+        Ok(())
+    }
+}
+
+/// Half-open range of byte offsets into the source text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -20,9 +89,27 @@ pub enum TokenKind {
     DecVal,
     Read,
     Write,
+    Debug,
+    StartProc,
+    EndProc,
+    Call,
     Comment,
 }
 
+/// Opt-in language extensions recognized by `tokenize_with`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Extensions {
+    /// Treat `#` as a command that dumps the tape to stderr.
+    pub debug_hash: bool,
+    /// Treat everything after the first `!` as input to the program.
+    pub bang_input: bool,
+    /// Treat `(` and `)` as the start and end of a procedure definition and
+    /// `:` as a call, as in pbrain.
+    pub procedures: bool,
+    /// Expand `#define`d macros and `*` repetitions before parsing.
+    pub macros: bool,
+}
+
 impl From<u8> for TokenKind {
     fn from(d: u8) -> TokenKind {
         TokenKind::from(d as char)
@@ -45,22 +132,65 @@ impl From<char> for TokenKind {
     }
 }
 
-pub fn tokenize(s: &str) -> Vec<Token> {
+pub fn tokenize<S: AsRef<[u8]> + ?Sized>(s: &S) -> Vec<Token> {
+    tokenize_with(s, &Extensions::default())
+}
+
+/// Tokenize source bytes. Text need not be UTF-8: each valid character is one
+/// column, and each byte of an invalid sequence is a column of its own.
+pub fn tokenize_with<S: AsRef<[u8]> + ?Sized>(s: &S, ext: &Extensions) -> Vec<Token> {
+    tokenize_file(s, ext, None)
+}
+
+/// Tokenize the text of `file`, which every token position refers to.
+pub fn tokenize_file<S: AsRef<[u8]> + ?Sized>(s: &S, ext: &Extensions, file: Option<Rc<str>>) -> Vec<Token> {
+    tokenize_chars(s.as_ref(), file, &|c| match c {
+        '#' if ext.debug_hash => TokenKind::Debug,
+        '(' if ext.procedures => TokenKind::StartProc,
+        ')' if ext.procedures => TokenKind::EndProc,
+        ':' if ext.procedures => TokenKind::Call,
+        c => TokenKind::from(c),
+    })
+}
+
+/// Tokenize the text of `file` with one token per character, of the kind
+/// given by `kind`. Newlines are never tokens.
+pub fn tokenize_chars(s: &[u8], file: Option<Rc<str>>, kind: &dyn Fn(char) -> TokenKind) -> Vec<Token> {
     let mut ret = vec![];
-    let mut line = 0;
-    let mut pos;
+    let mut line = 1;
+    let mut pos = 0;
+    let mut start = 0;
 
-    for l in s.lines() {
-        line += 1;
-        pos = 0;
-        for c in l.chars() {
+    for chunk in s.utf8_chunks() {
+        let valid = chunk.valid().chars().map(Some);
+        let invalid = chunk.invalid().iter().map(|_| None);
+
+        for c in valid.chain(invalid) {
+            let span = Span {
+                start,
+                end: start + c.map_or(1, char::len_utf8),
+            };
+            start = span.end;
+
+            let kind = match c {
+                Some('\n') => {
+                    line += 1;
+                    pos = 0;
+                    continue;
+                }
+                Some(c) => kind(c),
+                None => TokenKind::Comment,
+            };
             pos += 1;
             ret.push(Token {
-                kind: TokenKind::from(c),
+                kind,
                 pos: InputPosition {
-                    line: line,
-                    pos: pos,
+                    file: file.clone(),
+                    line,
+                    pos,
+                    expansion: None,
                 },
+                span,
             });
         }
     }
@@ -70,7 +200,7 @@ pub fn tokenize(s: &str) -> Vec<Token> {
 
 #[cfg(test)]
 mod test {
-    use super::{InputPosition, Token, TokenKind};
+    use super::{Extensions, InputPosition, Span, Token, TokenKind};
 
     #[test]
     fn start_loop() {
@@ -131,41 +261,159 @@ mod test {
         let expect: Vec<Token> = vec![
             Token {
                 kind: TokenKind::StartLoop,
-                pos: InputPosition { line: 1, pos: 1 },
+                pos: InputPosition { file: None, line: 1, pos: 1, expansion: None },
+                span: Span { start: 0, end: 1 },
             },
             Token {
                 kind: TokenKind::IncTape,
-                pos: InputPosition { line: 1, pos: 2 },
+                pos: InputPosition { file: None, line: 1, pos: 2, expansion: None },
+                span: Span { start: 1, end: 2 },
             },
             Token {
                 kind: TokenKind::IncVal,
-                pos: InputPosition { line: 1, pos: 3 },
+                pos: InputPosition { file: None, line: 1, pos: 3, expansion: None },
+                span: Span { start: 2, end: 3 },
             },
             Token {
                 kind: TokenKind::Read,
-                pos: InputPosition { line: 1, pos: 4 },
+                pos: InputPosition { file: None, line: 1, pos: 4, expansion: None },
+                span: Span { start: 3, end: 4 },
             },
             Token {
                 kind: TokenKind::Comment,
-                pos: InputPosition { line: 2, pos: 1 },
+                pos: InputPosition { file: None, line: 2, pos: 1, expansion: None },
+                span: Span { start: 5, end: 6 },
             },
             Token {
                 kind: TokenKind::Write,
-                pos: InputPosition { line: 2, pos: 2 },
+                pos: InputPosition { file: None, line: 2, pos: 2, expansion: None },
+                span: Span { start: 6, end: 7 },
             },
             Token {
                 kind: TokenKind::DecVal,
-                pos: InputPosition { line: 2, pos: 3 },
+                pos: InputPosition { file: None, line: 2, pos: 3, expansion: None },
+                span: Span { start: 7, end: 8 },
             },
             Token {
                 kind: TokenKind::DecTape,
-                pos: InputPosition { line: 2, pos: 4 },
+                pos: InputPosition { file: None, line: 2, pos: 4, expansion: None },
+                span: Span { start: 8, end: 9 },
             },
             Token {
                 kind: TokenKind::EndLoop,
-                pos: InputPosition { line: 2, pos: 5 },
+                pos: InputPosition { file: None, line: 2, pos: 5, expansion: None },
+                span: Span { start: 9, end: 10 },
             },
         ];
         assert_eq!(tokens, expect);
     }
+
+    #[test]
+    fn debug_hash() {
+        let ext = Extensions {
+            debug_hash: true,
+            ..Extensions::default()
+        };
+        let kinds: Vec<TokenKind> = super::tokenize_with("+#", &ext)
+            .into_iter()
+            .map(|t| t.kind)
+            .collect();
+        assert_eq!(kinds, vec![TokenKind::IncVal, TokenKind::Debug]);
+
+        let kinds: Vec<TokenKind> = super::tokenize("+#").into_iter().map(|t| t.kind).collect();
+        assert_eq!(kinds, vec![TokenKind::IncVal, TokenKind::Comment]);
+    }
+
+    #[test]
+    fn procedures() {
+        let ext = Extensions {
+            procedures: true,
+            ..Extensions::default()
+        };
+        let kinds: Vec<TokenKind> = super::tokenize_with("(+):", &ext)
+            .into_iter()
+            .map(|t| t.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![TokenKind::StartProc, TokenKind::IncVal, TokenKind::EndProc, TokenKind::Call]
+        );
+
+        let kinds: Vec<TokenKind> = super::tokenize("(:)").into_iter().map(|t| t.kind).collect();
+        assert_eq!(kinds, vec![TokenKind::Comment; 3]);
+    }
+
+    #[test]
+    fn spans() {
+        let s = "é+\r\n-";
+        let tokens = super::tokenize(s);
+        let expect: Vec<Token> = vec![
+            Token {
+                kind: TokenKind::Comment,
+                pos: InputPosition { file: None, line: 1, pos: 1, expansion: None },
+                span: Span { start: 0, end: 2 },
+            },
+            Token {
+                kind: TokenKind::IncVal,
+                pos: InputPosition { file: None, line: 1, pos: 2, expansion: None },
+                span: Span { start: 2, end: 3 },
+            },
+            Token {
+                kind: TokenKind::Comment,
+                pos: InputPosition { file: None, line: 1, pos: 3, expansion: None },
+                span: Span { start: 3, end: 4 },
+            },
+            Token {
+                kind: TokenKind::DecVal,
+                pos: InputPosition { file: None, line: 2, pos: 1, expansion: None },
+                span: Span { start: 5, end: 6 },
+            },
+        ];
+        assert_eq!(tokens, expect);
+    }
+
+    #[test]
+    fn non_utf8() {
+        let s = b"\xe9+\xff\xfe\n-";
+        let tokens = super::tokenize(s);
+        let expect: Vec<Token> = vec![
+            Token {
+                kind: TokenKind::Comment,
+                pos: InputPosition { file: None, line: 1, pos: 1, expansion: None },
+                span: Span { start: 0, end: 1 },
+            },
+            Token {
+                kind: TokenKind::IncVal,
+                pos: InputPosition { file: None, line: 1, pos: 2, expansion: None },
+                span: Span { start: 1, end: 2 },
+            },
+            Token {
+                kind: TokenKind::Comment,
+                pos: InputPosition { file: None, line: 1, pos: 3, expansion: None },
+                span: Span { start: 2, end: 3 },
+            },
+            Token {
+                kind: TokenKind::Comment,
+                pos: InputPosition { file: None, line: 1, pos: 4, expansion: None },
+                span: Span { start: 3, end: 4 },
+            },
+            Token {
+                kind: TokenKind::DecVal,
+                pos: InputPosition { file: None, line: 2, pos: 1, expansion: None },
+                span: Span { start: 5, end: 6 },
+            },
+        ];
+        assert_eq!(tokens, expect);
+    }
+
+    #[test]
+    fn advance() {
+        let s = b"+\xe9[\n\xe9 -]";
+        let start = InputPosition { file: None, line: 1, pos: 1, expansion: None };
+        let tokens = super::tokenize(s);
+        for t in &tokens {
+            assert_eq!(start.advance(&s[..t.span.start]), t.pos);
+        }
+        assert_eq!(start.advance(b""), start);
+    }
 }
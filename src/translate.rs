@@ -0,0 +1,298 @@
+//! Translation of scripts between dialects.
+//!
+//! Commands are written as the target dialect spells them, with whitespace
+//! around commands of more than one character. Without comments, the commands
+//! of a script are wrapped at `LINE_WIDTH` columns. With comments, the text
+//! between commands is copied where it was, except for any characters which
+//! the target dialect would read as commands.
+use crate::ast::{ASTNode, ASTNodeKind, AST};
+use crate::dialect::{Brainfuck, Lexer};
+use crate::token::{Extensions, InputPosition, Span, Token, TokenKind};
+use std::error::Error;
+use std::fmt;
+
+const LINE_WIDTH: usize = 80;
+
+/// Write the commands of `ast` in the dialect of `to`.
+pub fn translate(ast: &AST, to: &dyn Lexer) -> Result<Vec<u8>, TranslateError> {
+    let mut out = Output::new(to);
+    translate_nodes(&mut out, &ast.0)?;
+    if out.column > 0 {
+        out.text.push(b'\n');
+        out.comment.push(false);
+    }
+    out.finish()
+}
+
+fn translate_nodes(out: &mut Output, nodes: &[ASTNode]) -> Result<(), TranslateError> {
+    for node in nodes {
+        let kind = match node.kind {
+            ASTNodeKind::Loop | ASTNodeKind::Procedure => {
+                let (start, end) = match node.kind {
+                    ASTNodeKind::Loop => (TokenKind::StartLoop, TokenKind::EndLoop),
+                    _ => (TokenKind::StartProc, TokenKind::EndProc),
+                };
+                out.wrapped_command(start, &node.pos)?;
+                translate_nodes(out, node.ops.as_ref().unwrap())?;
+                // the closing command has no position of its own
+                out.wrapped_command(end, &node.pos)?;
+                continue;
+            }
+            ASTNodeKind::Call => TokenKind::Call,
+            ASTNodeKind::IncTape => TokenKind::IncTape,
+            ASTNodeKind::DecTape => TokenKind::DecTape,
+            ASTNodeKind::IncVal => TokenKind::IncVal,
+            ASTNodeKind::DecVal => TokenKind::DecVal,
+            ASTNodeKind::Read => TokenKind::Read,
+            ASTNodeKind::Write => TokenKind::Write,
+            ASTNodeKind::Debug => TokenKind::Debug,
+            ASTNodeKind::Comment => continue,
+        };
+        out.wrapped_command(kind, &node.pos)?;
+    }
+    Ok(())
+}
+
+/// Write `tokens`, read from `text`, in the dialect of `to`, keeping the text
+/// between commands.
+pub fn translate_with_comments(tokens: &[Token], text: &[u8], to: &dyn Lexer) -> Result<Vec<u8>, TranslateError> {
+    let mut out = Output::new(to);
+    let mut last = 0;
+    for t in tokens.iter().filter(|t| t.kind != TokenKind::Comment) {
+        out.comment(&text[last..t.span.start]);
+        out.command(t.kind.clone(), &t.pos)?;
+        last = t.span.end;
+    }
+    out.comment(&text[last..]);
+    out.finish()
+}
+
+struct Output<'a> {
+    to: &'a dyn Lexer,
+    text: Vec<u8>,
+    /// Whether each byte of `text` was copied from a comment.
+    comment: Vec<bool>,
+    /// The commands written so far, with their source positions.
+    commands: Vec<(TokenKind, InputPosition)>,
+    column: usize,
+}
+
+impl<'a> Output<'a> {
+    fn new(to: &'a dyn Lexer) -> Output<'a> {
+        Output {
+            to,
+            text: vec![],
+            comment: vec![],
+            commands: vec![],
+            column: 0,
+        }
+    }
+
+    fn push(&mut self, text: &[u8], comment: bool) {
+        for &b in text {
+            self.column = if b == b'\n' { 0 } else { self.column + 1 };
+        }
+        self.text.extend_from_slice(text);
+        self.comment.extend(text.iter().map(|_| comment));
+    }
+
+    /// Whether a command of more than one character after the text so far
+    /// needs a space before it.
+    fn needs_space(&self) -> bool {
+        self.text.last().is_some_and(|b| !b.is_ascii_whitespace())
+    }
+
+    fn command(&mut self, kind: TokenKind, pos: &InputPosition) -> Result<(), TranslateError> {
+        let word = self.to.spell(&kind).ok_or_else(|| TranslateError {
+            pos: pos.clone(),
+            kind: ErrorKind::NoSpelling(kind.clone()),
+        })?;
+        if word.chars().count() > 1 && self.needs_space() {
+            self.push(b" ", false);
+        }
+        self.push(word.as_bytes(), false);
+        self.commands.push((kind, pos.clone()));
+        Ok(())
+    }
+
+    /// Write a command, starting a new line first if it would not fit.
+    fn wrapped_command(&mut self, kind: TokenKind, pos: &InputPosition) -> Result<(), TranslateError> {
+        let len = self.to.spell(&kind).map_or(0, |w| w.chars().count());
+        if self.column > 0 && self.column + len + usize::from(len > 1) > LINE_WIDTH {
+            self.push(b"\n", false);
+        }
+        self.command(kind, pos)
+    }
+
+    fn comment(&mut self, text: &[u8]) {
+        let spaced = self.commands.last().and_then(|(kind, _)| self.to.spell(kind)).is_some_and(|w| w.chars().count() > 1);
+        if spaced && text.first().is_some_and(|b| !b.is_ascii_whitespace()) {
+            self.push(b" ", false);
+        }
+        self.push(text, true);
+    }
+
+    /// Drop the characters of comments which the target reads as commands,
+    /// and check that the rest reads back as the commands written.
+    fn finish(mut self) -> Result<Vec<u8>, TranslateError> {
+        loop {
+            let tokens = self.to.tokenize(&self.text, None);
+            let commands: Vec<&Token> = tokens.iter().filter(|t| t.kind != TokenKind::Comment).collect();
+
+            let stray: Vec<Span> = commands
+                .iter()
+                .map(|t| t.span)
+                .filter(|s| self.comment[s.start..s.end].contains(&true))
+                .collect();
+            if stray.is_empty() {
+                let read = commands.iter().map(|t| &t.kind);
+                let written = self.commands.iter().map(|(kind, _)| kind);
+                if let Some(i) = read.zip(written).position(|(a, b)| a != b) {
+                    return Err(self.ambiguous(i));
+                }
+                if commands.len() != self.commands.len() {
+                    return Err(self.ambiguous(commands.len().min(self.commands.len())));
+                }
+                return Ok(self.text);
+            }
+
+            for span in stray.iter().rev() {
+                for i in (span.start..span.end).rev() {
+                    if self.comment[i] {
+                        self.text.remove(i);
+                        self.comment.remove(i);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The commands read back differ from those written from the `i`th on.
+    fn ambiguous(&self, i: usize) -> TranslateError {
+        let pos = match self.commands.get(i).or_else(|| self.commands.last()) {
+            Some((_, pos)) => pos.clone(),
+            None => InputPosition {
+                file: None,
+                line: 1,
+                pos: 1,
+                expansion: None,
+            },
+        };
+        TranslateError {
+            pos,
+            kind: ErrorKind::Ambiguous,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TranslateError {
+    pub pos: InputPosition,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ErrorKind {
+    /// The target dialect has no way to write the command.
+    NoSpelling(TokenKind),
+    /// The translated commands read back as different commands, as when words
+    /// of a token map run together.
+    Ambiguous,
+}
+
+impl fmt::Display for TranslateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::NoSpelling(kind) => {
+                let all = Brainfuck(Extensions {
+                    debug_hash: true,
+                    procedures: true,
+                    ..Extensions::default()
+                });
+                let c = all.spell(kind).unwrap_or_default();
+                write!(f, "The target dialect has no command '{}'", c)?
+            }
+            ErrorKind::Ambiguous => write!(f, "Translated commands read back differently in the target dialect")?,
+        }
+        write!(f, " ({})", self.pos)
+    }
+}
+
+impl Error for TranslateError {}
+
+#[cfg(test)]
+mod test {
+    use super::{translate, translate_with_comments, ErrorKind};
+    use crate::ast::AST;
+    use crate::dialect::{builtin, Lexer, Words};
+    use crate::token::{Extensions, TokenKind};
+
+    fn lexer(name: &str) -> Box<dyn Lexer> {
+        builtin(name, &Extensions::default()).unwrap()
+    }
+
+    fn commands(lexer: &dyn Lexer, text: &[u8]) -> Vec<TokenKind> {
+        let tokens = lexer.tokenize(text, None);
+        tokens.into_iter().map(|t| t.kind).filter(|k| *k != TokenKind::Comment).collect()
+    }
+
+    fn both(from: &str, to: &str, text: &str) -> (String, String) {
+        let (from, to) = (lexer(from), lexer(to));
+        let tokens = from.tokenize(text.as_bytes(), None);
+        let ast = AST::from_tokens(&tokens).unwrap();
+        let plain = translate(&ast, &*to).unwrap();
+        let commented = translate_with_comments(&tokens, text.as_bytes(), &*to).unwrap();
+        assert_eq!(commands(&*to, &plain), commands(&*from, text.as_bytes()));
+        assert_eq!(commands(&*to, &commented), commands(&*from, text.as_bytes()));
+        (String::from_utf8(plain).unwrap(), String::from_utf8(commented).unwrap())
+    }
+
+    #[test]
+    fn ook_to_bf() {
+        let text = "add one: Ook. Ook.\nloop, moving it: Ook! Ook? Ook. Ook? Ook. Ook. Ook? Ook. Ook! Ook! Ook? Ook!";
+        let (plain, commented) = both("ook", "bf", text);
+        assert_eq!(plain, "+[>+<-]\n");
+        assert_eq!(commented, "add one: +\nloop moving it: [ > + < - ]");
+    }
+
+    #[test]
+    fn bf_to_ook() {
+        let (plain, commented) = both("bf", "ook", "Ook? +-");
+        assert_eq!(plain, "Ook. Ook. Ook! Ook!\n");
+        // the word in the comment would pair up with the command after it
+        assert_eq!(commented, "Ook. Ook. Ook! Ook!");
+
+        let (_, commented) = both("bf", "ook", "+ hi\n[-]");
+        assert_eq!(commented, "Ook. Ook. hi\nOok! Ook? Ook! Ook! Ook? Ook!");
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = include_str!("../bf/hello.bf");
+        for dialect in &["ook", "blub", "alphuck"] {
+            let (translated, _) = both("bf", dialect, text);
+            let (bf, _) = both(dialect, "bf", &translated);
+            assert_eq!(commands(&*lexer("bf"), bf.as_bytes()), commands(&*lexer("bf"), text.as_bytes()));
+            assert!(bf.lines().all(|l| l.len() <= super::LINE_WIDTH));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let ext = Extensions {
+            debug_hash: true,
+            ..Extensions::default()
+        };
+        let bf = builtin("bf", &ext).unwrap();
+        let ast = AST::from_tokens(&bf.tokenize(b"+\n #", None)).unwrap();
+        let err = translate(&ast, &*lexer("ook")).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::NoSpelling(TokenKind::Debug));
+        assert_eq!(err.to_string(), "The target dialect has no command '#' (2:2)");
+
+        // "a" then "b" reads back as "ab"
+        let map = Words::parse("+ a\n- b\n. ab").unwrap();
+        let ast = AST::from_tokens(&lexer("bf").tokenize(b"+-", None)).unwrap();
+        let err = translate(&ast, &map).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Ambiguous);
+    }
+}
@@ -0,0 +1,938 @@
+//! WebAssembly code generation.
+//!
+//! The module exports its linear memory, which holds the tape from address 0
+//! followed by any embedded input, and a `_start` function that runs the
+//! program. The host supplies I/O as imports from `env`:
+//!
+//! ```text
+//! read_byte  () -> i32              next input byte, or -1 at end of input
+//! write_byte (i32) -> ()
+//! debug      (i32, i32, i32, i32, i32) -> ()
+//!            tape position, address and length of the file name, line and
+//!            column of a '#'
+//! ```
+//!
+//! `debug` is only imported by programs containing `#`. The names of included
+//! files are stored after the input, and the script itself has an empty name.
+//! Moving left of the tape traps with an out of bounds memory access, but like
+//! the native backends, moving past its end is only caught at the end of
+//! memory.
+use crate::ir::{Node, NodeKind};
+use crate::token::InputPosition;
+use std::fmt::{self, Write};
+use std::rc::Rc;
+
+const PAGE_SIZE: u32 = 0x1_0000;
+
+/// The single local of `_start`, holding the address of the active cell.
+const LOCAL_PTR: u32 = 0;
+/// The only global, counting the embedded input consumed so far.
+const GLOBAL_INPUT_POS: u32 = 0;
+
+const TYPE_READ: u8 = 0;
+const TYPE_WRITE: u8 = 1;
+const TYPE_START: u8 = 2;
+const TYPE_DEBUG: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Import {
+    ReadByte,
+    WriteByte,
+    Debug,
+}
+
+/// The subset of wasm instructions the backend emits. Memory offsets are
+/// unsigned, so cells left of the active one are addressed explicitly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instr {
+    LocalGet(u32),
+    LocalSet(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    I32Const(i32),
+    I32Add,
+    I32Mul,
+    I32Eqz,
+    I32LtU,
+    /// Load a byte at an offset from the address on the stack.
+    Load8U(u32),
+    /// Store a byte at an offset from the address on the stack.
+    Store8(u32),
+    Call(Import),
+    Block,
+    Loop,
+    /// An `if` block producing an `i32`.
+    If,
+    /// An `if` block with no result.
+    IfEmpty,
+    Else,
+    End,
+    BrIf(u32),
+}
+
+/// A lowered program and the data it needs.
+#[derive(Debug, PartialEq)]
+pub struct Module {
+    /// Body of `_start`, without its final `end`.
+    pub code: Vec<Instr>,
+    pub tape_len: u32,
+    pub input: Option<Vec<u8>>,
+    /// Whether `debug` is imported.
+    pub debug: bool,
+    /// Files named by calls to `debug`, in the order they are stored.
+    pub files: Vec<Rc<str>>,
+}
+
+impl Module {
+    pub fn lower(ir: &[Node], tape_len: u32, input: Option<Vec<u8>>) -> Module {
+        let mut module = Module {
+            code: vec![],
+            tape_len,
+            input,
+            debug: false,
+            files: vec![],
+        };
+        module.lower_all(ir);
+        module
+    }
+
+    fn lower_all(&mut self, ir: &[Node]) {
+        for node in ir {
+            match &node.kind {
+                NodeKind::Add(n) => self.add_cell(0, |m| m.code.push(Instr::I32Const(*n as i32))),
+                NodeKind::Move(n) => self.code.extend_from_slice(&[
+                    Instr::LocalGet(LOCAL_PTR),
+                    Instr::I32Const(*n as i32),
+                    Instr::I32Add,
+                    Instr::LocalSet(LOCAL_PTR),
+                ]),
+                NodeKind::Clear => self.clear_cell(),
+                NodeKind::Transfer(targets) => {
+                    // the loop this came from never moves off a zero cell
+                    self.code.extend_from_slice(&[Instr::LocalGet(LOCAL_PTR), Instr::Load8U(0), Instr::IfEmpty]);
+                    for &(offset, factor) in targets {
+                        self.add_cell(offset as i32, |m| {
+                            m.code.extend_from_slice(&[
+                                Instr::LocalGet(LOCAL_PTR),
+                                Instr::Load8U(0),
+                                Instr::I32Const(factor as i32),
+                                Instr::I32Mul,
+                            ])
+                        });
+                    }
+                    self.clear_cell();
+                    self.code.push(Instr::End);
+                }
+                NodeKind::Read => {
+                    self.code.push(Instr::LocalGet(LOCAL_PTR));
+                    match &self.input {
+                        Some(input) => {
+                            let len = input.len() as i32;
+                            self.code.extend_from_slice(&[
+                                Instr::GlobalGet(GLOBAL_INPUT_POS),
+                                Instr::I32Const(len),
+                                Instr::I32LtU,
+                                Instr::If,
+                                Instr::GlobalGet(GLOBAL_INPUT_POS),
+                                Instr::Load8U(self.tape_len),
+                                Instr::GlobalGet(GLOBAL_INPUT_POS),
+                                Instr::I32Const(1),
+                                Instr::I32Add,
+                                Instr::GlobalSet(GLOBAL_INPUT_POS),
+                                Instr::Else,
+                                Instr::I32Const(255),
+                                Instr::End,
+                            ]);
+                        }
+                        // storing the low byte turns -1 into 255
+                        None => self.code.push(Instr::Call(Import::ReadByte)),
+                    }
+                    self.code.push(Instr::Store8(0));
+                }
+                NodeKind::Write => self.code.extend_from_slice(&[
+                    Instr::LocalGet(LOCAL_PTR),
+                    Instr::Load8U(0),
+                    Instr::Call(Import::WriteByte),
+                ]),
+                NodeKind::Debug => {
+                    self.debug = true;
+                    let (addr, len) = self.file_name(&node.pos);
+                    self.code.extend_from_slice(&[
+                        Instr::LocalGet(LOCAL_PTR),
+                        Instr::I32Const(addr as i32),
+                        Instr::I32Const(len as i32),
+                        Instr::I32Const(node.pos.line as i32),
+                        Instr::I32Const(node.pos.pos as i32),
+                        Instr::Call(Import::Debug),
+                    ]);
+                }
+                NodeKind::Loop(body) => {
+                    self.code.extend_from_slice(&[
+                        Instr::Block,
+                        Instr::LocalGet(LOCAL_PTR),
+                        Instr::Load8U(0),
+                        Instr::I32Eqz,
+                        Instr::BrIf(0),
+                        Instr::Loop,
+                    ]);
+                    self.lower_all(body);
+                    self.code.extend_from_slice(&[
+                        Instr::LocalGet(LOCAL_PTR),
+                        Instr::Load8U(0),
+                        Instr::BrIf(0),
+                        Instr::End,
+                        Instr::End,
+                    ]);
+                }
+            }
+        }
+    }
+
+    /// Add the value pushed by `value` to the cell at `offset`.
+    fn add_cell(&mut self, offset: i32, value: impl FnOnce(&mut Module)) {
+        let mem_offset = self.cell_addr(offset);
+        self.cell_addr(offset);
+        self.code.push(Instr::Load8U(mem_offset));
+        value(self);
+        self.code.push(Instr::I32Add);
+        self.code.push(Instr::Store8(mem_offset));
+    }
+
+    fn clear_cell(&mut self) {
+        self.code.extend_from_slice(&[
+            Instr::LocalGet(LOCAL_PTR),
+            Instr::I32Const(0),
+            Instr::Store8(0),
+        ]);
+    }
+
+    /// Push the address of the cell at `offset` and return the memory offset
+    /// to access it with.
+    fn cell_addr(&mut self, offset: i32) -> u32 {
+        self.code.push(Instr::LocalGet(LOCAL_PTR));
+        if offset < 0 {
+            self.code.push(Instr::I32Const(offset));
+            self.code.push(Instr::I32Add);
+            0
+        } else {
+            offset as u32
+        }
+    }
+
+    /// Address and length of the name of the file `pos` is in.
+    fn file_name(&mut self, pos: &InputPosition) -> (u32, u32) {
+        let file = match &pos.file {
+            Some(file) => file,
+            None => return (0, 0),
+        };
+        let mut addr = self.tape_len + self.input_len();
+        for name in &self.files {
+            if name == file {
+                return (addr, name.len() as u32);
+            }
+            addr += name.len() as u32;
+        }
+        self.files.push(file.clone());
+        (addr, file.len() as u32)
+    }
+
+    fn imports(&self) -> Vec<Import> {
+        let mut imports = vec![Import::ReadByte, Import::WriteByte];
+        if self.debug {
+            imports.push(Import::Debug);
+        }
+        imports
+    }
+
+    fn input_len(&self) -> u32 {
+        self.input.as_ref().map_or(0, |i| i.len() as u32)
+    }
+
+    /// Everything stored after the tape: the input, then the file names.
+    fn data(&self) -> Vec<u8> {
+        let mut data = self.input.clone().unwrap_or_default();
+        for name in &self.files {
+            data.extend_from_slice(name.as_bytes());
+        }
+        data
+    }
+
+    fn pages(&self) -> u32 {
+        (self.tape_len + self.data().len() as u32).div_ceil(PAGE_SIZE).max(1)
+    }
+
+    /// Render the module in the WebAssembly text format.
+    pub fn to_wat(&self) -> String {
+        let mut out = String::new();
+        self.write_wat(&mut out).unwrap();
+        out
+    }
+
+    fn write_wat(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "(module")?;
+        for import in self.imports() {
+            let sig = match import {
+                Import::ReadByte => "(result i32)",
+                Import::WriteByte => "(param i32)",
+                Import::Debug => "(param i32 i32 i32 i32 i32)",
+            };
+            let name = import_name(import);
+            writeln!(out, "  (import \"env\" \"{}\" (func ${} {}))", name, name, sig)?;
+        }
+        writeln!(out, "  (memory (export \"memory\") {})", self.pages())?;
+
+        if self.input.is_some() {
+            writeln!(out, "  (global $input_pos (mut i32) (i32.const 0))")?;
+        }
+        let data = self.data();
+        if !data.is_empty() {
+            write!(out, "  (data (i32.const {}) \"", self.tape_len)?;
+            for &b in &data {
+                if b.is_ascii_graphic() && b != b'"' && b != b'\\' || b == b' ' {
+                    out.push(b as char);
+                } else {
+                    write!(out, "\\{:02x}", b)?;
+                }
+            }
+            writeln!(out, "\")")?;
+        }
+
+        writeln!(out, "  (func (export \"_start\") (local $p i32)")?;
+        let mut depth = 2;
+        for instr in &self.code {
+            if let Instr::Else | Instr::End = instr {
+                depth -= 1;
+            }
+            write!(out, "{}", "  ".repeat(depth))?;
+            match *instr {
+                Instr::LocalGet(_) => writeln!(out, "local.get $p")?,
+                Instr::LocalSet(_) => writeln!(out, "local.set $p")?,
+                Instr::GlobalGet(_) => writeln!(out, "global.get $input_pos")?,
+                Instr::GlobalSet(_) => writeln!(out, "global.set $input_pos")?,
+                Instr::I32Const(n) => writeln!(out, "i32.const {}", n)?,
+                Instr::I32Add => writeln!(out, "i32.add")?,
+                Instr::I32Mul => writeln!(out, "i32.mul")?,
+                Instr::I32Eqz => writeln!(out, "i32.eqz")?,
+                Instr::I32LtU => writeln!(out, "i32.lt_u")?,
+                Instr::Load8U(0) => writeln!(out, "i32.load8_u")?,
+                Instr::Load8U(off) => writeln!(out, "i32.load8_u offset={}", off)?,
+                Instr::Store8(0) => writeln!(out, "i32.store8")?,
+                Instr::Store8(off) => writeln!(out, "i32.store8 offset={}", off)?,
+                Instr::Call(import) => writeln!(out, "call ${}", import_name(import))?,
+                Instr::Block => writeln!(out, "block")?,
+                Instr::Loop => writeln!(out, "loop")?,
+                Instr::If => writeln!(out, "if (result i32)")?,
+                Instr::IfEmpty => writeln!(out, "if")?,
+                Instr::Else => writeln!(out, "else")?,
+                Instr::End => writeln!(out, "end")?,
+                Instr::BrIf(n) => writeln!(out, "br_if {}", n)?,
+            }
+            if let Instr::Block | Instr::Loop | Instr::If | Instr::IfEmpty | Instr::Else = instr {
+                depth += 1;
+            }
+        }
+        writeln!(out, "  )")?;
+        writeln!(out, ")")
+    }
+
+    /// Encode the module in the binary format.
+    pub fn encode(&self) -> Vec<u8> {
+        let imports = self.imports();
+        let mut out = b"\0asm".to_vec();
+        out.extend_from_slice(&1u32.to_le_bytes());
+
+        // types: read_byte, write_byte, _start, debug
+        section(&mut out, 1, |s| {
+            s.extend_from_slice(&[4]);
+            s.extend_from_slice(&[0x60, 0, 1, 0x7f]);
+            s.extend_from_slice(&[0x60, 1, 0x7f, 0]);
+            s.extend_from_slice(&[0x60, 0, 0]);
+            s.extend_from_slice(&[0x60, 5, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f, 0]);
+        });
+
+        section(&mut out, 2, |s| {
+            uleb(s, imports.len() as u32);
+            for &import in &imports {
+                name(s, "env");
+                name(s, import_name(import));
+                let ty = match import {
+                    Import::ReadByte => TYPE_READ,
+                    Import::WriteByte => TYPE_WRITE,
+                    Import::Debug => TYPE_DEBUG,
+                };
+                s.extend_from_slice(&[0, ty]);
+            }
+        });
+
+        section(&mut out, 3, |s| s.extend_from_slice(&[1, TYPE_START]));
+
+        section(&mut out, 5, |s| {
+            s.extend_from_slice(&[1, 0]);
+            uleb(s, self.pages());
+        });
+
+        if self.input.is_some() {
+            // one mutable i32 initialized to 0
+            section(&mut out, 6, |s| s.extend_from_slice(&[1, 0x7f, 1, 0x41, 0, 0x0b]));
+        }
+
+        section(&mut out, 7, |s| {
+            s.push(2);
+            name(s, "memory");
+            s.extend_from_slice(&[2, 0]);
+            name(s, "_start");
+            s.push(0);
+            uleb(s, imports.len() as u32);
+        });
+
+        section(&mut out, 10, |s| {
+            let mut body = vec![1, 1, 0x7f];
+            for &instr in &self.code {
+                self.encode_instr(&mut body, instr);
+            }
+            body.push(0x0b);
+
+            s.push(1);
+            uleb(s, body.len() as u32);
+            s.extend_from_slice(&body);
+        });
+
+        let data = self.data();
+        if !data.is_empty() {
+            section(&mut out, 11, |s| {
+                s.extend_from_slice(&[1, 0, 0x41]);
+                sleb(s, self.tape_len as i32);
+                s.push(0x0b);
+                uleb(s, data.len() as u32);
+                s.extend_from_slice(&data);
+            });
+        }
+
+        out
+    }
+
+    fn encode_instr(&self, out: &mut Vec<u8>, instr: Instr) {
+        match instr {
+            Instr::LocalGet(i) => {
+                out.push(0x20);
+                uleb(out, i);
+            }
+            Instr::LocalSet(i) => {
+                out.push(0x21);
+                uleb(out, i);
+            }
+            Instr::GlobalGet(i) => {
+                out.push(0x23);
+                uleb(out, i);
+            }
+            Instr::GlobalSet(i) => {
+                out.push(0x24);
+                uleb(out, i);
+            }
+            Instr::I32Const(n) => {
+                out.push(0x41);
+                sleb(out, n);
+            }
+            Instr::I32Add => out.push(0x6a),
+            Instr::I32Mul => out.push(0x6c),
+            Instr::I32Eqz => out.push(0x45),
+            Instr::I32LtU => out.push(0x49),
+            // alignment 2^0, then the offset
+            Instr::Load8U(off) => {
+                out.extend_from_slice(&[0x2d, 0]);
+                uleb(out, off);
+            }
+            Instr::Store8(off) => {
+                out.extend_from_slice(&[0x3a, 0]);
+                uleb(out, off);
+            }
+            Instr::Call(import) => {
+                out.push(0x10);
+                let index = self.imports().iter().position(|&i| i == import).unwrap();
+                uleb(out, index as u32);
+            }
+            Instr::Block => out.extend_from_slice(&[0x02, 0x40]),
+            Instr::Loop => out.extend_from_slice(&[0x03, 0x40]),
+            Instr::If => out.extend_from_slice(&[0x04, 0x7f]),
+            Instr::IfEmpty => out.extend_from_slice(&[0x04, 0x40]),
+            Instr::Else => out.push(0x05),
+            Instr::End => out.push(0x0b),
+            Instr::BrIf(n) => {
+                out.push(0x0d);
+                uleb(out, n);
+            }
+        }
+    }
+}
+
+fn import_name(import: Import) -> &'static str {
+    match import {
+        Import::ReadByte => "read_byte",
+        Import::WriteByte => "write_byte",
+        Import::Debug => "debug",
+    }
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: impl FnOnce(&mut Vec<u8>)) {
+    let mut data = vec![];
+    contents(&mut data);
+    out.push(id);
+    uleb(out, data.len() as u32);
+    out.extend_from_slice(&data);
+}
+
+fn name(out: &mut Vec<u8>, s: &str) {
+    uleb(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn uleb(out: &mut Vec<u8>, mut n: u32) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut n: i32) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Import, Instr, Module};
+    use crate::ir;
+
+    fn lower(raw: &str, tape_len: u32, input: Option<&[u8]>) -> Module {
+        Module::lower(&ir::parse(raw), tape_len, input.map(|i| i.to_vec()))
+    }
+
+    /// Just enough of a wasm decoder and interpreter to run what `encode`
+    /// produces.
+    struct Decoded {
+        imports: Vec<Import>,
+        pages: u32,
+        exports: Vec<String>,
+        has_global: bool,
+        data: Option<(u32, Vec<u8>)>,
+        code: Vec<Instr>,
+    }
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl<'a> Reader<'a> {
+        fn byte(&mut self) -> u8 {
+            let (b, rest) = self.0.split_first().unwrap();
+            self.0 = rest;
+            *b
+        }
+
+        fn bytes(&mut self, n: usize) -> &'a [u8] {
+            let (b, rest) = self.0.split_at(n);
+            self.0 = rest;
+            b
+        }
+
+        fn uleb(&mut self) -> u32 {
+            let (mut n, mut shift) = (0, 0);
+            loop {
+                let b = self.byte();
+                n |= ((b & 0x7f) as u32) << shift;
+                shift += 7;
+                if b & 0x80 == 0 {
+                    return n;
+                }
+            }
+        }
+
+        fn sleb(&mut self) -> i32 {
+            let (mut n, mut shift) = (0i64, 0);
+            loop {
+                let b = self.byte();
+                n |= ((b & 0x7f) as i64) << shift;
+                shift += 7;
+                if b & 0x80 == 0 {
+                    if b & 0x40 != 0 {
+                        n |= -1 << shift;
+                    }
+                    return n as i32;
+                }
+            }
+        }
+
+        fn name(&mut self) -> String {
+            let len = self.uleb() as usize;
+            String::from_utf8(self.bytes(len).to_vec()).unwrap()
+        }
+    }
+
+    fn decode(module: &[u8]) -> Decoded {
+        let mut r = Reader(module);
+        assert_eq!(r.bytes(8), b"\0asm\x01\0\0\0");
+        let mut decoded = Decoded {
+            imports: vec![],
+            pages: 0,
+            exports: vec![],
+            has_global: false,
+            data: None,
+            code: vec![],
+        };
+
+        let mut last_id = 0;
+        while !r.0.is_empty() {
+            let id = r.byte();
+            assert!(id > last_id, "section {} out of order", id);
+            last_id = id;
+            let len = r.uleb() as usize;
+            let mut s = Reader(r.bytes(len));
+
+            match id {
+                1 => {
+                    for _ in 0..s.uleb() {
+                        assert_eq!(s.byte(), 0x60);
+                        let params = s.uleb() as usize;
+                        assert!(s.bytes(params).iter().all(|&t| t == 0x7f));
+                        let results = s.uleb() as usize;
+                        assert!(s.bytes(results).iter().all(|&t| t == 0x7f));
+                    }
+                }
+                2 => {
+                    for _ in 0..s.uleb() {
+                        assert_eq!(s.name(), "env");
+                        decoded.imports.push(match &s.name()[..] {
+                            "read_byte" => Import::ReadByte,
+                            "write_byte" => Import::WriteByte,
+                            "debug" => Import::Debug,
+                            other => panic!("unexpected import {}", other),
+                        });
+                        assert_eq!(s.byte(), 0);
+                        s.uleb();
+                    }
+                }
+                3 => assert_eq!(s.bytes(2), &[1, 2]),
+                5 => {
+                    assert_eq!(s.bytes(2), &[1, 0]);
+                    decoded.pages = s.uleb();
+                }
+                6 => {
+                    assert_eq!(s.bytes(6), &[1, 0x7f, 1, 0x41, 0, 0x0b]);
+                    decoded.has_global = true;
+                }
+                7 => {
+                    for _ in 0..s.uleb() {
+                        decoded.exports.push(s.name());
+                        s.byte();
+                        s.uleb();
+                    }
+                }
+                10 => {
+                    assert_eq!(s.uleb(), 1);
+                    let len = s.uleb() as usize;
+                    let mut body = Reader(s.bytes(len));
+                    assert_eq!(body.bytes(3), &[1, 1, 0x7f]);
+                    decoded.code = decode_code(&mut body, &decoded.imports);
+                    assert!(body.0.is_empty());
+                }
+                11 => {
+                    assert_eq!(s.bytes(3), &[1, 0, 0x41]);
+                    let offset = s.sleb() as u32;
+                    assert_eq!(s.byte(), 0x0b);
+                    let len = s.uleb() as usize;
+                    decoded.data = Some((offset, s.bytes(len).to_vec()));
+                }
+                _ => panic!("unexpected section {}", id),
+            }
+            assert!(s.0.is_empty(), "trailing bytes in section {}", id);
+        }
+        decoded
+    }
+
+    fn decode_code(r: &mut Reader, imports: &[Import]) -> Vec<Instr> {
+        let mut code = vec![];
+        let mut depth = 0;
+        loop {
+            code.push(match r.byte() {
+                0x20 => Instr::LocalGet(r.uleb()),
+                0x21 => Instr::LocalSet(r.uleb()),
+                0x23 => Instr::GlobalGet(r.uleb()),
+                0x24 => Instr::GlobalSet(r.uleb()),
+                0x41 => Instr::I32Const(r.sleb()),
+                0x6a => Instr::I32Add,
+                0x6c => Instr::I32Mul,
+                0x45 => Instr::I32Eqz,
+                0x49 => Instr::I32LtU,
+                0x2d => {
+                    assert_eq!(r.byte(), 0);
+                    Instr::Load8U(r.uleb())
+                }
+                0x3a => {
+                    assert_eq!(r.byte(), 0);
+                    Instr::Store8(r.uleb())
+                }
+                0x10 => Instr::Call(imports[r.uleb() as usize]),
+                0x02 => {
+                    assert_eq!(r.byte(), 0x40);
+                    depth += 1;
+                    Instr::Block
+                }
+                0x03 => {
+                    assert_eq!(r.byte(), 0x40);
+                    depth += 1;
+                    Instr::Loop
+                }
+                0x04 => {
+                    depth += 1;
+                    match r.byte() {
+                        0x7f => Instr::If,
+                        0x40 => Instr::IfEmpty,
+                        t => panic!("unexpected block type {:#x}", t),
+                    }
+                }
+                0x05 => Instr::Else,
+                0x0b if depth == 0 => return code,
+                0x0b => {
+                    depth -= 1;
+                    Instr::End
+                }
+                0x0d => Instr::BrIf(r.uleb()),
+                op => panic!("unexpected opcode {:#x}", op),
+            });
+        }
+    }
+
+    struct Run {
+        output: Vec<u8>,
+        /// Tape position, file name, line and column of each `debug` call.
+        debug: Vec<(i32, String, i32, i32)>,
+        trapped: bool,
+    }
+
+    fn run(module: &Decoded, input: &[u8]) -> Run {
+        let code = &module.code;
+        // index of the matching `else` and `end` of each block
+        let mut ends = vec![(0, 0); code.len()];
+        let mut open = vec![];
+        for (i, instr) in code.iter().enumerate() {
+            match instr {
+                Instr::Block | Instr::Loop | Instr::If | Instr::IfEmpty => open.push(i),
+                Instr::Else => ends[*open.last().unwrap()].0 = i,
+                Instr::End => ends[open.pop().unwrap()].1 = i,
+                _ => {}
+            }
+        }
+
+        let mut mem = vec![0u8; (module.pages * super::PAGE_SIZE) as usize];
+        if let Some((offset, data)) = &module.data {
+            mem[*offset as usize..][..data.len()].copy_from_slice(data);
+        }
+        let mut input = input.iter();
+        let mut res = Run {
+            output: vec![],
+            debug: vec![],
+            trapped: false,
+        };
+        let (mut local, mut global) = (0i32, 0i32);
+        let mut stack: Vec<i32> = vec![];
+        // (is loop, start, end) of each enclosing block
+        let mut labels: Vec<(bool, usize, usize)> = vec![];
+        let mut pc = 0;
+
+        while pc < code.len() {
+            match code[pc] {
+                Instr::LocalGet(_) => stack.push(local),
+                Instr::LocalSet(_) => local = stack.pop().unwrap(),
+                Instr::GlobalGet(_) => stack.push(global),
+                Instr::GlobalSet(_) => global = stack.pop().unwrap(),
+                Instr::I32Const(n) => stack.push(n),
+                Instr::I32Add => {
+                    let b = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    stack.push(a.wrapping_add(b));
+                }
+                Instr::I32Mul => {
+                    let b = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    stack.push(a.wrapping_mul(b));
+                }
+                Instr::I32Eqz => {
+                    let a = stack.pop().unwrap();
+                    stack.push((a == 0) as i32);
+                }
+                Instr::I32LtU => {
+                    let b = stack.pop().unwrap() as u32;
+                    let a = stack.pop().unwrap() as u32;
+                    stack.push((a < b) as i32);
+                }
+                Instr::Load8U(off) => {
+                    let addr = stack.pop().unwrap() as u32 as u64 + off as u64;
+                    match mem.get(addr as usize) {
+                        Some(b) => stack.push(*b as i32),
+                        None => {
+                            res.trapped = true;
+                            return res;
+                        }
+                    }
+                }
+                Instr::Store8(off) => {
+                    let val = stack.pop().unwrap();
+                    let addr = stack.pop().unwrap() as u32 as u64 + off as u64;
+                    match mem.get_mut(addr as usize) {
+                        Some(b) => *b = val as u8,
+                        None => {
+                            res.trapped = true;
+                            return res;
+                        }
+                    }
+                }
+                Instr::Call(Import::ReadByte) => stack.push(input.next().map_or(-1, |b| *b as i32)),
+                Instr::Call(Import::WriteByte) => res.output.push(stack.pop().unwrap() as u8),
+                Instr::Call(Import::Debug) => {
+                    let col = stack.pop().unwrap();
+                    let line = stack.pop().unwrap();
+                    let len = stack.pop().unwrap() as usize;
+                    let addr = stack.pop().unwrap() as usize;
+                    let pos = stack.pop().unwrap();
+                    let file = String::from_utf8(mem[addr..addr + len].to_vec()).unwrap();
+                    res.debug.push((pos, file, line, col));
+                }
+                Instr::Block | Instr::Loop => {
+                    labels.push((code[pc] == Instr::Loop, pc, ends[pc].1));
+                }
+                Instr::If | Instr::IfEmpty => {
+                    labels.push((false, pc, ends[pc].1));
+                    if stack.pop().unwrap() == 0 {
+                        // without an `else`, skip to the `end`
+                        pc = if ends[pc].0 == 0 { ends[pc].1 - 1 } else { ends[pc].0 };
+                    }
+                }
+                Instr::Else => pc = labels.last().unwrap().2 - 1,
+                Instr::End => {
+                    labels.pop();
+                }
+                Instr::BrIf(n) => {
+                    if stack.pop().unwrap() != 0 {
+                        let depth = labels.len() - 1 - n as usize;
+                        let (is_loop, start, end) = labels[depth];
+                        if is_loop {
+                            labels.truncate(depth + 1);
+                            pc = start;
+                        } else {
+                            labels.truncate(depth);
+                            pc = end;
+                        }
+                    }
+                }
+            }
+            pc += 1;
+        }
+        assert!(labels.is_empty() && stack.is_empty());
+        res
+    }
+
+    fn run_bf(raw: &str, tape_len: u32, embedded: Option<&[u8]>, input: &[u8]) -> Run {
+        let module = lower(raw, tape_len, embedded);
+        let decoded = decode(&module.encode());
+        assert_eq!(decoded.code, module.code);
+        run(&decoded, input)
+    }
+
+    #[test]
+    fn structure() {
+        let decoded = decode(&lower("+[.,]", 70_000, Some(b"hi")).encode());
+        assert_eq!(decoded.imports, vec![Import::ReadByte, Import::WriteByte]);
+        assert_eq!(decoded.exports, vec!["memory", "_start"]);
+        assert_eq!(decoded.pages, 2);
+        assert!(decoded.has_global);
+        assert_eq!(decoded.data, Some((70_000, b"hi".to_vec())));
+
+        let decoded = decode(&lower("#", 10, None).encode());
+        assert_eq!(decoded.imports, vec![Import::ReadByte, Import::WriteByte, Import::Debug]);
+        assert_eq!(decoded.pages, 1);
+        assert!(!decoded.has_global);
+        assert_eq!(decoded.data, None);
+    }
+
+    #[test]
+    fn leb() {
+        let mut out = vec![];
+        super::uleb(&mut out, 624_485);
+        super::sleb(&mut out, -123_456);
+        super::sleb(&mut out, 64);
+        super::sleb(&mut out, 255);
+        assert_eq!(out, vec![0xe5, 0x8e, 0x26, 0xc0, 0xbb, 0x78, 0xc0, 0x00, 0xff, 0x01]);
+    }
+
+    #[test]
+    fn hello() {
+        let res = run_bf(include_str!("../bf/hello.bf"), 30_000, None, b"");
+        assert_eq!(res.output, b"Hello Sateko!\n");
+        assert!(!res.trapped);
+    }
+
+    #[test]
+    fn rot13() {
+        let res = run_bf(include_str!("../bf/rot13.bf"), 30_000, None, b"Hello");
+        assert_eq!(res.output, b"Uryyb");
+    }
+
+    #[test]
+    fn cells() {
+        // transfers to the left, wrapping and end of input
+        let res = run_bf(">+++[-<++>]<.>-.,.", 10, None, b"");
+        assert_eq!(res.output, vec![6, 255, 255]);
+
+        let res = run_bf(",.,.,.,.", 10, Some(b"abc"), b"xyz");
+        assert_eq!(res.output, b"abc\xff");
+
+        let res = run_bf("<+", 10, None, b"");
+        assert!(res.trapped);
+
+        // transfers which would leave the tape don't run from a zero cell
+        let res = run_bf("[-<+>]+.", 10, None, b"");
+        assert_eq!(res.output, [1]);
+        assert!(!res.trapped);
+    }
+
+    #[test]
+    fn debug() {
+        let res = run_bf(">++\n #", 10, None, b"");
+        assert_eq!(res.debug, vec![(1, String::new(), 2, 2)]);
+
+        // file names are stored once each, after the input
+        let mut ir = ir::parse("#>#>#,");
+        ir[0].pos.file = Some("a.bf".into());
+        ir[2].pos.file = Some("lib.bf".into());
+        ir[4].pos.file = Some("a.bf".into());
+        let module = Module::lower(&ir, 10, Some(b"in".to_vec()));
+        let decoded = decode(&module.encode());
+        assert_eq!(decoded.data, Some((10, b"ina.bflib.bf".to_vec())));
+        let res = run(&decoded, b"");
+        let files: Vec<&str> = res.debug.iter().map(|d| &d.1[..]).collect();
+        assert_eq!(files, ["a.bf", "lib.bf", "a.bf"]);
+    }
+
+    #[test]
+    fn wat() {
+        let wat = lower("<+[-]#,", 30_000, Some(b"a\"\n")).to_wat();
+        assert!(wat.starts_with("(module\n  (import \"env\" \"read_byte\" (func $read_byte (result i32)))\n"));
+        assert!(wat.contains("  (import \"env\" \"debug\" (func $debug (param i32 i32 i32 i32 i32)))\n"));
+        assert!(wat.contains("  (memory (export \"memory\") 1)\n"));
+        assert!(wat.contains("  (data (i32.const 30000) \"a\\22\\0a\")\n"));
+        assert!(wat.contains("    local.get $p\n    i32.const -1\n    i32.add\n    local.set $p\n"));
+        assert!(wat.contains("      i32.load8_u offset=30000\n"));
+        assert!(wat.contains("    call $debug\n"));
+        assert!(wat.ends_with("    i32.store8\n  )\n)\n"));
+    }
+}
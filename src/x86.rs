@@ -0,0 +1,595 @@
+//! x86-64 Linux code generation without LLVM.
+//!
+//! Programs are lowered from the IR to a short list of `Inst`s, each of which
+//! expands to a fixed instruction sequence, either as assembly text or as
+//! machine code. `%rbx` holds the address of the active cell, and I/O goes
+//! straight to the `read`/`write` syscalls.
+use crate::ir::{self, Node, NodeKind};
+use crate::partial::Prefix;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{self, Write};
+
+const SYS_READ: u32 = 0;
+const SYS_WRITE: u32 = 1;
+const SYS_EXIT: u32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Inst {
+    /// Point `%rbx` at the first cell of the tape.
+    Start,
+    /// Add to the cell at an offset from the active cell.
+    AddCell(i32, u8),
+    /// Move the active cell.
+    AddPtr(i32),
+    /// Store a constant in the active cell.
+    SetCell(u8),
+    /// Load the active cell into `%eax`.
+    LoadCell,
+    /// Multiply `%eax` by a constant.
+    MulEax(u8),
+    /// Add `%al` to the cell at an offset from the active cell.
+    AddAlToCell(i32),
+    /// Read a byte from stdin into the active cell, or 255 at end of file.
+    Read,
+    /// Read the next byte of the embedded input into the active cell, or 255
+    /// once it is exhausted.
+    ReadInput,
+    /// Write the active cell to stdout.
+    Write,
+    /// Write the output computed at compile time to stdout.
+    WriteOutput,
+    /// Jump to the label if the active cell is zero.
+    JumpIfZero(usize),
+    /// Jump to the label if the active cell is non-zero.
+    JumpIfNonZero(usize),
+    Label(usize),
+    /// Exit with status 0.
+    Exit,
+}
+
+/// A lowered program and the data it needs.
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub insts: Vec<Inst>,
+    pub tape_len: u32,
+    pub input: Option<Vec<u8>>,
+    /// Output written by `WriteOutput`.
+    pub output: Vec<u8>,
+}
+
+/// Virtual addresses used when encoding machine code.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Layout {
+    pub code: u64,
+    pub tape: u64,
+    /// Start of the embedded input.
+    pub input: u64,
+    /// 8-byte counter of embedded input consumed so far.
+    pub input_pos: u64,
+    /// Start of the output computed at compile time.
+    pub output: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CodegenError {
+    /// `#` dumps have no native implementation.
+    DebugUnsupported,
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodegenError::DebugUnsupported => {
+                write!(f, "'#' debug commands are not supported by the x86-64 backend")
+            }
+        }
+    }
+}
+
+impl Error for CodegenError {}
+
+impl Program {
+    pub fn lower(ir: &[Node], tape_len: u32, input: Option<Vec<u8>>) -> Result<Program, CodegenError> {
+        let mut prog = Program {
+            insts: vec![Inst::Start],
+            tape_len,
+            input,
+            output: vec![],
+        };
+        let mut labels = 0;
+        prog.lower_all(ir, &mut labels)?;
+        prog.insts.push(Inst::Exit);
+        Ok(prog)
+    }
+
+    /// Lower the rest of a program which was run at compile time up to
+    /// `prefix`, starting with its output and tape.
+    pub fn lower_prefix(prefix: &Prefix, tape_len: u32, input: Option<Vec<u8>>) -> Result<Program, CodegenError> {
+        let mut prog = Program {
+            insts: vec![Inst::Start],
+            tape_len,
+            input,
+            output: prefix.output.clone(),
+        };
+        if !prog.output.is_empty() {
+            prog.insts.push(Inst::WriteOutput);
+        }
+        let ir = ir::from_ast(&prefix.rest);
+        // nothing is left to look at the tape
+        if !ir.is_empty() {
+            for (i, &cell) in prefix.tape.iter().enumerate() {
+                if cell != 0 {
+                    prog.insts.push(Inst::AddCell(i as i32, cell));
+                }
+            }
+            if prefix.pos != 0 {
+                prog.insts.push(Inst::AddPtr(prefix.pos as i32));
+            }
+        }
+        let mut labels = 0;
+        prog.lower_all(&ir, &mut labels)?;
+        prog.insts.push(Inst::Exit);
+        Ok(prog)
+    }
+
+    fn lower_all(&mut self, ir: &[Node], labels: &mut usize) -> Result<(), CodegenError> {
+        for node in ir {
+            match &node.kind {
+                NodeKind::Add(n) => self.insts.push(Inst::AddCell(0, *n)),
+                NodeKind::Move(n) => self.insts.push(Inst::AddPtr(*n as i32)),
+                NodeKind::Clear => self.insts.push(Inst::SetCell(0)),
+                NodeKind::Transfer(targets) => {
+                    // the loop this came from never runs on a zero cell, and
+                    // its targets may be off the tape
+                    let skip = *labels;
+                    *labels += 1;
+                    self.insts.push(Inst::JumpIfZero(skip));
+                    self.insts.push(Inst::LoadCell);
+                    let mut factor = 1;
+                    for &(offset, f) in targets {
+                        if f != factor {
+                            // reload rather than divide out the last factor
+                            if factor != 1 {
+                                self.insts.push(Inst::LoadCell);
+                            }
+                            if f != 1 {
+                                self.insts.push(Inst::MulEax(f));
+                            }
+                            factor = f;
+                        }
+                        self.insts.push(Inst::AddAlToCell(offset as i32));
+                    }
+                    self.insts.push(Inst::SetCell(0));
+                    self.insts.push(Inst::Label(skip));
+                }
+                NodeKind::Read if self.input.is_some() => self.insts.push(Inst::ReadInput),
+                NodeKind::Read => self.insts.push(Inst::Read),
+                NodeKind::Write => self.insts.push(Inst::Write),
+                NodeKind::Debug => return Err(CodegenError::DebugUnsupported),
+                NodeKind::Loop(body) => {
+                    let start = *labels;
+                    let end = start + 1;
+                    *labels += 2;
+                    self.insts.push(Inst::JumpIfZero(end));
+                    self.insts.push(Inst::Label(start));
+                    self.lower_all(body, labels)?;
+                    self.insts.push(Inst::JumpIfNonZero(start));
+                    self.insts.push(Inst::Label(end));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the program as GNU assembler source, ready for
+    /// `as out.s -o out.o && ld out.o`.
+    pub fn to_asm(&self) -> String {
+        let mut out = String::new();
+        self.write_asm(&mut out).unwrap();
+        out
+    }
+
+    fn write_asm(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "\t.text")?;
+        writeln!(out, "\t.globl _start")?;
+        writeln!(out, "_start:")?;
+
+        for inst in &self.insts {
+            match *inst {
+                Inst::Start => writeln!(out, "\tleaq tape(%rip), %rbx")?,
+                Inst::AddCell(off, n) => writeln!(out, "\taddb ${}, {}(%rbx)", n, off)?,
+                Inst::AddPtr(n) => writeln!(out, "\taddq ${}, %rbx", n)?,
+                Inst::SetCell(n) => writeln!(out, "\tmovb ${}, (%rbx)", n)?,
+                Inst::LoadCell => writeln!(out, "\tmovzbl (%rbx), %eax")?,
+                Inst::MulEax(n) => writeln!(out, "\timull ${}, %eax, %eax", n)?,
+                Inst::AddAlToCell(off) => writeln!(out, "\taddb %al, {}(%rbx)", off)?,
+                Inst::Read => {
+                    writeln!(out, "\tmovl ${}, %eax", SYS_READ)?;
+                    writeln!(out, "\tmovl $0, %edi")?;
+                    writeln!(out, "\tmovq %rbx, %rsi")?;
+                    writeln!(out, "\tmovl $1, %edx")?;
+                    writeln!(out, "\tsyscall")?;
+                    writeln!(out, "\ttestq %rax, %rax")?;
+                    writeln!(out, "\tjg 1f")?;
+                    writeln!(out, "\tmovb $255, (%rbx)")?;
+                    writeln!(out, "1:")?;
+                }
+                Inst::ReadInput => {
+                    writeln!(out, "\tmovq input_pos(%rip), %rax")?;
+                    writeln!(out, "\tmovb $255, (%rbx)")?;
+                    writeln!(out, "\tcmpq ${}, %rax", self.input_len())?;
+                    writeln!(out, "\tjae 1f")?;
+                    writeln!(out, "\tleaq input(%rip), %rcx")?;
+                    writeln!(out, "\tmovb (%rcx,%rax), %cl")?;
+                    writeln!(out, "\tmovb %cl, (%rbx)")?;
+                    writeln!(out, "\tincq input_pos(%rip)")?;
+                    writeln!(out, "1:")?;
+                }
+                Inst::Write => {
+                    writeln!(out, "\tmovl ${}, %eax", SYS_WRITE)?;
+                    writeln!(out, "\tmovl $1, %edi")?;
+                    writeln!(out, "\tmovq %rbx, %rsi")?;
+                    writeln!(out, "\tmovl $1, %edx")?;
+                    writeln!(out, "\tsyscall")?;
+                }
+                Inst::WriteOutput => {
+                    writeln!(out, "\tmovl ${}, %eax", SYS_WRITE)?;
+                    writeln!(out, "\tmovl $1, %edi")?;
+                    writeln!(out, "\tleaq output(%rip), %rsi")?;
+                    writeln!(out, "\tmovl ${}, %edx", self.output.len())?;
+                    writeln!(out, "\tsyscall")?;
+                }
+                Inst::JumpIfZero(label) => {
+                    writeln!(out, "\tcmpb $0, (%rbx)")?;
+                    writeln!(out, "\tje .L{}", label)?;
+                }
+                Inst::JumpIfNonZero(label) => {
+                    writeln!(out, "\tcmpb $0, (%rbx)")?;
+                    writeln!(out, "\tjne .L{}", label)?;
+                }
+                Inst::Label(label) => writeln!(out, ".L{}:", label)?,
+                Inst::Exit => {
+                    writeln!(out, "\tmovl ${}, %eax", SYS_EXIT)?;
+                    writeln!(out, "\tmovl $0, %edi")?;
+                    writeln!(out, "\tsyscall")?;
+                }
+            }
+        }
+
+        if let Some(input) = &self.input {
+            writeln!(out)?;
+            writeln!(out, "\t.data")?;
+            writeln!(out, "input_pos:")?;
+            writeln!(out, "\t.quad 0")?;
+            writeln!(out, "input:")?;
+            write_bytes(out, input)?;
+        }
+
+        if !self.output.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "\t.section .rodata")?;
+            writeln!(out, "output:")?;
+            write_bytes(out, &self.output)?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "\t.bss")?;
+        writeln!(out, "\t.lcomm tape, {}", self.tape_len)
+    }
+
+    /// Encode the program as machine code to be loaded at `layout.code`.
+    /// Every instruction has a fixed size, so the length of the result does
+    /// not depend on the layout.
+    pub fn encode(&self, layout: &Layout) -> Vec<u8> {
+        let mut code = Code {
+            bytes: vec![],
+            base: layout.code,
+        };
+        let mut labels = vec![];
+        let mut fixups = vec![];
+
+        for inst in &self.insts {
+            match *inst {
+                Inst::Start => {
+                    // lea rbx, [rip + tape]
+                    code.emit(&[0x48, 0x8d, 0x1d]);
+                    code.rip_rel(layout.tape);
+                }
+                Inst::AddCell(off, n) => {
+                    // add byte [rbx + off], n
+                    code.emit(&[0x80]);
+                    code.rbx_mem(0, off);
+                    code.emit(&[n]);
+                }
+                Inst::AddPtr(n) => {
+                    // add rbx, n
+                    code.emit(&[0x48, 0x81, 0xc3]);
+                    code.emit(&n.to_le_bytes());
+                }
+                Inst::SetCell(n) => {
+                    // mov byte [rbx], n
+                    code.emit(&[0xc6, 0x03, n]);
+                }
+                Inst::LoadCell => {
+                    // movzx eax, byte [rbx]
+                    code.emit(&[0x0f, 0xb6, 0x03]);
+                }
+                Inst::MulEax(n) => {
+                    // imul eax, eax, n
+                    code.emit(&[0x69, 0xc0]);
+                    code.emit(&(n as u32).to_le_bytes());
+                }
+                Inst::AddAlToCell(off) => {
+                    // add byte [rbx + off], al
+                    code.emit(&[0x00]);
+                    code.rbx_mem(0, off);
+                }
+                Inst::Read => {
+                    code.syscall_setup(SYS_READ, 0);
+                    code.emit(&[0x0f, 0x05]); // syscall
+                    code.emit(&[0x48, 0x85, 0xc0]); // test rax, rax
+                    code.emit(&[0x7f, 0x03]); // jg past the store
+                    code.emit(&[0xc6, 0x03, 0xff]); // mov byte [rbx], 255
+                }
+                Inst::ReadInput => {
+                    // mov rax, [rip + input_pos]
+                    code.emit(&[0x48, 0x8b, 0x05]);
+                    code.rip_rel(layout.input_pos);
+                    code.emit(&[0xc6, 0x03, 0xff]); // mov byte [rbx], 255
+                    code.emit(&[0x48, 0x3d]); // cmp rax, len
+                    code.emit(&(self.input_len() as u32).to_le_bytes());
+                    code.emit(&[0x73, 19]); // jae past the increment
+                    // lea rcx, [rip + input]
+                    code.emit(&[0x48, 0x8d, 0x0d]);
+                    code.rip_rel(layout.input);
+                    code.emit(&[0x8a, 0x0c, 0x01]); // mov cl, [rcx + rax]
+                    code.emit(&[0x88, 0x0b]); // mov [rbx], cl
+                    // inc qword [rip + input_pos]
+                    code.emit(&[0x48, 0xff, 0x05]);
+                    code.rip_rel(layout.input_pos);
+                }
+                Inst::Write => {
+                    code.syscall_setup(SYS_WRITE, 1);
+                    code.emit(&[0x0f, 0x05]); // syscall
+                }
+                Inst::WriteOutput => {
+                    code.emit(&[0xb8]); // mov eax, SYS_WRITE
+                    code.emit(&SYS_WRITE.to_le_bytes());
+                    code.emit(&[0xbf]); // mov edi, 1
+                    code.emit(&1u32.to_le_bytes());
+                    // lea rsi, [rip + output]
+                    code.emit(&[0x48, 0x8d, 0x35]);
+                    code.rip_rel(layout.output);
+                    code.emit(&[0xba]); // mov edx, len
+                    code.emit(&(self.output.len() as u32).to_le_bytes());
+                    code.emit(&[0x0f, 0x05]); // syscall
+                }
+                Inst::JumpIfZero(label) | Inst::JumpIfNonZero(label) => {
+                    // cmp byte [rbx], 0
+                    code.emit(&[0x80, 0x3b, 0x00]);
+                    // je/jne rel32
+                    let cc = if let Inst::JumpIfZero(_) = inst { 0x84 } else { 0x85 };
+                    code.emit(&[0x0f, cc]);
+                    fixups.push((code.bytes.len(), label));
+                    code.emit(&[0; 4]);
+                }
+                Inst::Label(label) => {
+                    if labels.len() <= label {
+                        labels.resize(label + 1, 0);
+                    }
+                    labels[label] = code.bytes.len();
+                }
+                Inst::Exit => {
+                    code.emit(&[0xb8]); // mov eax, SYS_EXIT
+                    code.emit(&SYS_EXIT.to_le_bytes());
+                    code.emit(&[0x31, 0xff]); // xor edi, edi
+                    code.emit(&[0x0f, 0x05]); // syscall
+                }
+            }
+        }
+
+        for (at, label) in fixups {
+            let rel = labels[label] as i64 - (at as i64 + 4);
+            code.bytes[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+
+        code.bytes
+    }
+
+    fn input_len(&self) -> usize {
+        self.input.as_ref().map_or(0, |i| i.len())
+    }
+}
+
+fn write_bytes(out: &mut String, bytes: &[u8]) -> fmt::Result {
+    for chunk in bytes.chunks(16) {
+        let bytes: Vec<String> = chunk.iter().map(|b| b.to_string()).collect();
+        writeln!(out, "\t.byte {}", bytes.join(", "))?;
+    }
+    Ok(())
+}
+
+struct Code {
+    bytes: Vec<u8>,
+    base: u64,
+}
+
+impl Code {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Emit a 32-bit displacement to `target` from the end of the
+    /// instruction, which must end with the displacement.
+    fn rip_rel(&mut self, target: u64) {
+        let next = self.base + self.bytes.len() as u64 + 4;
+        self.emit(&((target as i64 - next as i64) as i32).to_le_bytes());
+    }
+
+    /// Emit a ModRM byte (and displacement) addressing `[rbx + off]`.
+    fn rbx_mem(&mut self, reg: u8, off: i32) {
+        if off == 0 {
+            self.emit(&[reg << 3 | 0x03]);
+        } else if let Ok(off) = i8::try_from(off) {
+            self.emit(&[0x40 | reg << 3 | 0x03, off as u8]);
+        } else {
+            self.emit(&[0x80 | reg << 3 | 0x03]);
+            self.emit(&off.to_le_bytes());
+        }
+    }
+
+    /// Load the syscall number and a file descriptor, and point `rsi` at the
+    /// active cell for a one byte transfer.
+    fn syscall_setup(&mut self, num: u32, fd: u32) {
+        self.emit(&[0xb8]); // mov eax, num
+        self.emit(&num.to_le_bytes());
+        self.emit(&[0xbf]); // mov edi, fd
+        self.emit(&fd.to_le_bytes());
+        self.emit(&[0x48, 0x89, 0xde]); // mov rsi, rbx
+        self.emit(&[0xba]); // mov edx, 1
+        self.emit(&1u32.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CodegenError, Inst, Layout, Program};
+    use crate::ast::AST;
+    use crate::ir;
+    use crate::partial;
+    use crate::token::{tokenize, tokenize_with, Extensions};
+
+    fn lower(raw: &str) -> Program {
+        let ast = AST::from_tokens(&tokenize(raw)).unwrap();
+        Program::lower(&ir::from_ast(&ast), 16, None).unwrap()
+    }
+
+    #[test]
+    fn loops() {
+        let prog = lower("+[>-<[-]]");
+        assert_eq!(
+            prog.insts,
+            vec![
+                Inst::Start,
+                Inst::AddCell(0, 1),
+                Inst::JumpIfZero(1),
+                Inst::Label(0),
+                Inst::AddPtr(1),
+                Inst::AddCell(0, 255),
+                Inst::AddPtr(-1),
+                Inst::SetCell(0),
+                Inst::JumpIfNonZero(0),
+                Inst::Label(1),
+                Inst::Exit,
+            ]
+        );
+    }
+
+    #[test]
+    fn transfer() {
+        let prog = lower("[->+>+>++>+++<<<<]");
+        assert_eq!(
+            prog.insts[1..prog.insts.len() - 1],
+            [
+                Inst::JumpIfZero(0),
+                Inst::LoadCell,
+                Inst::AddAlToCell(1),
+                Inst::AddAlToCell(2),
+                Inst::MulEax(2),
+                Inst::AddAlToCell(3),
+                Inst::LoadCell,
+                Inst::MulEax(3),
+                Inst::AddAlToCell(4),
+                Inst::SetCell(0),
+                Inst::Label(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn asm() {
+        let asm = lower("+[.>]").to_asm();
+        assert!(asm.starts_with("\t.text\n\t.globl _start\n_start:\n\tleaq tape(%rip), %rbx\n"));
+        assert!(asm.contains("\tcmpb $0, (%rbx)\n\tje .L1\n.L0:\n"));
+        assert!(asm.contains("\tjne .L0\n.L1:\n"));
+        assert!(asm.ends_with("\t.bss\n\t.lcomm tape, 16\n"));
+    }
+
+    #[test]
+    fn embedded_input() {
+        let ast = AST::from_tokens(&tokenize(",.")).unwrap();
+        let prog = Program::lower(&ir::from_ast(&ast), 16, Some(b"hi".to_vec())).unwrap();
+        assert_eq!(prog.insts[1], Inst::ReadInput);
+        assert!(prog.to_asm().contains("input:\n\t.byte 104, 105\n"));
+    }
+
+    #[test]
+    fn prefix() {
+        let ast = AST::from_tokens(&tokenize("++.>+++[<+.>-,]")).unwrap();
+        let prefix = partial::evaluate(&ast, 16, partial::STEP_BUDGET);
+        let prog = Program::lower_prefix(&prefix, 16, None).unwrap();
+        assert_eq!(
+            prog.insts[..5],
+            [Inst::Start, Inst::WriteOutput, Inst::AddCell(0, 3), Inst::AddCell(1, 2), Inst::AddPtr(1)]
+        );
+        assert_eq!(prog.insts[5], Inst::Read);
+        assert!(prog.to_asm().contains("\tmovl $2, %edx\n"));
+        assert!(prog.to_asm().contains("output:\n\t.byte 2, 3\n"));
+
+        // the tape isn't set up when nothing is left to use it
+        let ast = AST::from_tokens(&tokenize("+++.")).unwrap();
+        let prefix = partial::evaluate(&ast, 16, partial::STEP_BUDGET);
+        let prog = Program::lower_prefix(&prefix, 16, None).unwrap();
+        assert_eq!(prog.insts, [Inst::Start, Inst::WriteOutput, Inst::Exit]);
+        assert_eq!(prog.output, [3]);
+    }
+
+    #[test]
+    fn debug_unsupported() {
+        let ext = Extensions {
+            debug_hash: true,
+            ..Extensions::default()
+        };
+        let ast = AST::from_tokens(&tokenize_with("#", &ext)).unwrap();
+        let res = Program::lower(&ir::from_ast(&ast), 16, None);
+        assert_eq!(res, Err(CodegenError::DebugUnsupported));
+    }
+
+    #[test]
+    fn encode() {
+        let layout = Layout {
+            code: 0x1000,
+            tape: 0x3000,
+            ..Layout::default()
+        };
+        let code = lower("+[>-<]").encode(&layout);
+        assert_eq!(
+            code,
+            vec![
+                0x48, 0x8d, 0x1d, 0xf9, 0x1f, 0x00, 0x00, // lea rbx, [rip + 0x1ff9]
+                0x80, 0x03, 0x01, // add byte [rbx], 1
+                0x80, 0x3b, 0x00, 0x0f, 0x84, 0x1a, 0x00, 0x00, 0x00, // cmp; je +26
+                0x48, 0x81, 0xc3, 0x01, 0x00, 0x00, 0x00, // add rbx, 1
+                0x80, 0x03, 0xff, // add byte [rbx], 255
+                0x48, 0x81, 0xc3, 0xff, 0xff, 0xff, 0xff, // add rbx, -1
+                0x80, 0x3b, 0x00, 0x0f, 0x85, 0xe6, 0xff, 0xff, 0xff, // cmp; jne -26
+                0xb8, 0x3c, 0x00, 0x00, 0x00, 0x31, 0xff, 0x0f, 0x05, // exit(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_offsets() {
+        let prog = Program {
+            insts: vec![Inst::AddAlToCell(-2), Inst::AddCell(300, 7)],
+            tape_len: 1,
+            input: None,
+            output: vec![],
+        };
+        assert_eq!(
+            prog.encode(&Layout::default()),
+            vec![0x00, 0x43, 0xfe, 0x80, 0x83, 0x2c, 0x01, 0x00, 0x00, 0x07]
+        );
+    }
+}